@@ -0,0 +1,100 @@
+//! Compares ctries whose C-nodes branch on 4, 5 and 6 bits of the hash.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use crossbeam::epoch;
+use ctrie::{Ctrie, FxHasher};
+use std::hash::BuildHasherDefault;
+
+const SIZES: [u32; 2] = [1_000, 100_000];
+
+fn filled<const W: usize>(n: u32) -> Ctrie<u32, u32, BuildHasherDefault<FxHasher>, W> {
+    let ctrie = Ctrie::with_width(BuildHasherDefault::default());
+    let guard = &epoch::pin();
+    for i in 0..n {
+        ctrie.insert(i, i, guard);
+    }
+    ctrie
+}
+
+fn insert<const W: usize>(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    for n in SIZES {
+        group.bench_with_input(BenchmarkId::new(format!("w{}", W), n), &n, |b, &n| {
+            b.iter(|| filled::<W>(n))
+        });
+    }
+    group.finish();
+}
+
+fn lookup<const W: usize>(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+    for n in SIZES {
+        let ctrie = filled::<W>(n);
+        group.bench_with_input(BenchmarkId::new(format!("w{}", W), n), &n, |b, &n| {
+            let guard = &epoch::pin();
+            b.iter(|| {
+                for i in 0..n {
+                    black_box(ctrie.lookup(&i, guard));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn remove<const W: usize>(c: &mut Criterion) {
+    let mut group = c.benchmark_group("remove");
+    for n in SIZES {
+        group.bench_with_input(BenchmarkId::new(format!("w{}", W), n), &n, |b, &n| {
+            b.iter_batched(
+                || filled::<W>(n),
+                |ctrie| {
+                    let guard = &epoch::pin();
+                    for i in 0..n {
+                        ctrie.remove(&i, guard);
+                    }
+                    ctrie
+                },
+                criterion::BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn snapshot_writes<const W: usize>(c: &mut Criterion) {
+    // every write after a snapshot copies the C-nodes on its path, which is where narrower nodes
+    // should pay off
+    let mut group = c.benchmark_group("snapshot_writes");
+    for n in SIZES {
+        let ctrie = filled::<W>(n);
+        group.bench_with_input(BenchmarkId::new(format!("w{}", W), n), &n, |b, &n| {
+            let guard = &epoch::pin();
+            b.iter(|| {
+                let snapshot = ctrie.snapshot(guard);
+                for i in (0..n).step_by(100) {
+                    snapshot.insert(i, 0, guard);
+                }
+                snapshot
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    insert<4>,
+    insert<5>,
+    insert<6>,
+    lookup<4>,
+    lookup<5>,
+    lookup<6>,
+    remove<4>,
+    remove<5>,
+    remove<6>,
+    snapshot_writes<4>,
+    snapshot_writes<5>,
+    snapshot_writes<6>,
+);
+criterion_main!(benches);
@@ -0,0 +1,91 @@
+//! Pieces shared by the fuzz targets.
+
+use std::hash::{BuildHasherDefault, Hasher};
+
+/// A hasher that only produces eight distinct hashes, which differ in bits 0, 31 and 63.
+///
+/// Keys with different hashes share long prefixes, so tries get deep, and keys with equal hashes
+/// end up in collision lists.
+#[derive(Default)]
+pub struct TinyHasher(u64);
+
+impl Hasher for TinyHasher {
+    fn finish(&self) -> u64 {
+        let h = self.0.wrapping_mul(0x517c_c1b7_2722_0a95) >> 61;
+        (h & 1) | ((h >> 1 & 1) << 31) | ((h >> 2) << 63)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0.rotate_left(5) ^ u64::from(byte)).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+    }
+}
+
+pub type TinyState = BuildHasherDefault<TinyHasher>;
+
+/// A secondary hasher for `TinyHasher`, which tells apart some of the keys that collide under it,
+/// but only produces sixteen distinct hashes, so collision lists past the secondary hash remain.
+#[derive(Default)]
+pub struct SplitHasher(u64);
+
+impl Hasher for SplitHasher {
+    fn finish(&self) -> u64 {
+        let h = self.0.wrapping_mul(0xff51_afd7_ed55_8ccd) >> 60;
+        (h & 3) | ((h >> 2) << 40)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0.rotate_left(7) ^ u64::from(byte)).wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        }
+    }
+}
+
+pub type SplitState = BuildHasherDefault<SplitHasher>;
+
+/// Returns the collision list limit of a ctrie that falls back to `SplitState`, if the config
+/// byte asks for one, or `None` for a ctrie that only hashes with `TinyState`.
+pub fn fallback_limit(config: u8) -> Option<usize> {
+    if config % 2 == 0 {
+        None
+    } else {
+        Some(1 + (config as usize >> 1) % 4)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+    Insert(u8, u8),
+    Lookup(u8),
+    Remove(u8),
+    /// Keeps the entries for which `retained` returns `true` with the given seed.
+    Retain(u8),
+    /// Inserts the entries returned by `batch`.
+    InsertBatch(u8, u8),
+}
+
+/// Decodes operations from the input, three bytes at a time.
+pub fn decode_ops(data: &[u8]) -> Vec<Op> {
+    data.chunks_exact(3)
+        .map(|chunk| match chunk[0] % 5 {
+            0 => Op::Insert(chunk[1], chunk[2]),
+            1 => Op::Lookup(chunk[1]),
+            2 => Op::Remove(chunk[1]),
+            3 => Op::Retain(chunk[1]),
+            _ => Op::InsertBatch(chunk[1], chunk[2]),
+        })
+        .collect()
+}
+
+/// The predicate of `Op::Retain`, which keeps about two thirds of the entries.
+pub fn retained(seed: u8, key: u8, value: u8) -> bool {
+    (key.wrapping_add(value) ^ seed) % 3 != 0
+}
+
+/// The entries of `Op::InsertBatch`, up to 31 of them, where some keys can appear more than once.
+pub fn batch(start: u8, len: u8) -> Vec<(u8, u8)> {
+    (0..len % 32)
+        .map(|i| (start ^ i.wrapping_mul(i), i))
+        .collect()
+}
@@ -0,0 +1,258 @@
+#![no_main]
+
+//! Runs several logical threads against one ctrie with a deterministic schedule.
+//!
+//! Every logical thread runs on its own OS thread, but only the thread holding the turn may run.
+//! The turn is handed over at every GCAS yield point and between operations, to a thread chosen
+//! by the fuzz input.
+
+mod common;
+
+use common::{batch, decode_ops, fallback_limit, retained, Op, SplitState, TinyState};
+use crossbeam::epoch;
+use ctrie::{fuzzing, Ctrie};
+use libfuzzer_sys::fuzz_target;
+use std::{
+    collections::{HashMap, HashSet},
+    hash::BuildHasher,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+struct State {
+    current: usize,
+    finished: Vec<bool>,
+    schedule: Vec<u8>,
+    step: usize,
+}
+
+impl State {
+    /// Hands the turn to the next unfinished thread according to the schedule.
+    fn pick_next(&mut self) {
+        let unfinished = (0..self.finished.len())
+            .filter(|&t| !self.finished[t])
+            .collect::<Vec<_>>();
+        if unfinished.is_empty() {
+            return;
+        }
+        let choice = if self.schedule.is_empty() {
+            0
+        } else {
+            self.schedule[self.step % self.schedule.len()] as usize
+        };
+        self.step += 1;
+        self.current = unfinished[choice % unfinished.len()];
+    }
+}
+
+struct Scheduler {
+    state: Mutex<State>,
+    turn: Condvar,
+}
+
+impl Scheduler {
+    fn new(threads: usize, schedule: Vec<u8>) -> Self {
+        Self {
+            state: Mutex::new(State {
+                current: 0,
+                finished: vec![false; threads],
+                schedule,
+                step: 0,
+            }),
+            turn: Condvar::new(),
+        }
+    }
+
+    fn wait_turn(&self, me: usize) {
+        let mut state = self.state.lock().unwrap();
+        while state.current != me {
+            state = self.turn.wait(state).unwrap();
+        }
+    }
+
+    fn switch(&self, me: usize) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.pick_next();
+            self.turn.notify_all();
+        }
+        self.wait_turn(me);
+    }
+
+    fn finish(&self, me: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.finished[me] = true;
+        state.pick_next();
+        self.turn.notify_all();
+    }
+}
+
+fn run<S2>(ctrie: Ctrie<u8, u8, TinyState, 6, S2>, threads: usize, ops: Vec<Op>, schedule: &[u8])
+where
+    S2: BuildHasher + Clone + Send + Sync + 'static,
+{
+    let per_thread = (0..threads)
+        .map(|t| {
+            ops.iter()
+                .skip(t)
+                .step_by(threads)
+                .copied()
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    // every value written to each key
+    let mut written = HashMap::<u8, HashSet<u8>>::new();
+    for op in &ops {
+        match *op {
+            Op::Insert(key, value) => {
+                written.entry(key).or_default().insert(value);
+            }
+            Op::InsertBatch(start, len) => {
+                for (key, value) in batch(start, len) {
+                    written.entry(key).or_default().insert(value);
+                }
+            }
+            Op::Lookup(_) | Op::Remove(_) | Op::Retain(_) => {}
+        }
+    }
+
+    // the keys that may be removed by any thread, and the possible final states of each key: the
+    // outcomes of the last operations of each thread that may have changed the key
+    let mut removed = HashSet::new();
+    let mut outcomes = HashMap::<u8, HashSet<Option<u8>>>::new();
+    for thread_ops in &per_thread {
+        let mut last = HashMap::<u8, HashSet<Option<u8>>>::new();
+        for op in thread_ops {
+            match *op {
+                Op::Insert(key, value) => {
+                    last.insert(key, HashSet::from([Some(value)]));
+                }
+                Op::InsertBatch(start, len) => {
+                    for (key, value) in batch(start, len) {
+                        last.insert(key, HashSet::from([Some(value)]));
+                    }
+                }
+                Op::Remove(key) => {
+                    removed.insert(key);
+                    last.insert(key, HashSet::from([None]));
+                }
+                Op::Retain(seed) => {
+                    // a key may be removed if any value written to it fails the predicate, and
+                    // may keep its value otherwise
+                    for (&key, values) in &written {
+                        if values.iter().any(|&value| !retained(seed, key, value)) {
+                            removed.insert(key);
+                            last.entry(key).or_default().insert(None);
+                        }
+                    }
+                }
+                Op::Lookup(_) => {}
+            }
+        }
+        for (key, outcome) in last {
+            outcomes.entry(key).or_default().extend(outcome);
+        }
+    }
+
+    let ctrie = Arc::new(ctrie);
+    let scheduler = Arc::new(Scheduler::new(threads, schedule.to_vec()));
+    let written = Arc::new(written);
+    let removed = Arc::new(removed);
+
+    let handles = per_thread
+        .into_iter()
+        .enumerate()
+        .map(|(me, thread_ops)| {
+            let ctrie = ctrie.clone();
+            let scheduler = scheduler.clone();
+            let written = written.clone();
+            let removed = removed.clone();
+            thread::spawn(move || {
+                scheduler.wait_turn(me);
+                let hook_scheduler = scheduler.clone();
+                fuzzing::set_yield_hook(Some(Box::new(move || hook_scheduler.switch(me))));
+
+                let guard = &epoch::pin();
+                let mut own_keys = HashSet::new();
+                for op in thread_ops {
+                    match op {
+                        Op::Insert(key, value) => {
+                            ctrie.insert(key, value, guard);
+                            own_keys.insert(key);
+                        }
+                        Op::Lookup(key) => match ctrie.lookup(&key, guard) {
+                            Some(value) => assert!(written[&key].contains(value)),
+                            None => assert!(!own_keys.contains(&key) || removed.contains(&key)),
+                        },
+                        Op::Remove(key) => {
+                            match ctrie.remove(&key, guard) {
+                                Some(value) => assert!(written[&key].contains(value)),
+                                None => {
+                                    assert!(!own_keys.contains(&key) || removed.contains(&key))
+                                }
+                            }
+                            own_keys.remove(&key);
+                        }
+                        Op::Retain(seed) => {
+                            ctrie.retain(|&key, &value| retained(seed, key, value), guard);
+                        }
+                        Op::InsertBatch(start, len) => {
+                            let entries = batch(start, len);
+                            own_keys.extend(entries.iter().map(|&(key, _)| key));
+                            ctrie.insert_batch(entries, guard);
+                        }
+                    }
+                    scheduler.switch(me);
+                }
+
+                fuzzing::set_yield_hook(None);
+                scheduler.finish(me);
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let guard = &epoch::pin();
+    let mut present = 0;
+    for key in 0..=u8::MAX {
+        let value = ctrie.lookup(&key, guard).copied();
+        match outcomes.get(&key) {
+            Some(possible) => assert!(possible.contains(&value)),
+            None => assert_eq!(value, None),
+        }
+        present += value.is_some() as usize;
+    }
+    assert_eq!(ctrie.validate(guard), Ok(present));
+}
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 3 {
+        return;
+    }
+    let threads = 2 + data[0] as usize % 3;
+    let ops_per_thread = 1 + data[1] as usize % 16;
+    let config = data[2];
+    let data = &data[3..];
+    let ops_len = (threads * ops_per_thread * 3).min(data.len() / 3 * 3);
+    let (ops, schedule) = data.split_at(ops_len);
+
+    let ops = decode_ops(ops);
+    match fallback_limit(config) {
+        None => run(
+            Ctrie::with_hasher(TinyState::default()),
+            threads,
+            ops,
+            schedule,
+        ),
+        Some(limit) => run(
+            Ctrie::with_fallback_hasher(TinyState::default(), SplitState::default(), limit),
+            threads,
+            ops,
+            schedule,
+        ),
+    }
+});
@@ -0,0 +1,52 @@
+#![no_main]
+
+mod common;
+
+use common::{batch, decode_ops, fallback_limit, retained, Op, SplitState, TinyState};
+use crossbeam::epoch;
+use ctrie::Ctrie;
+use libfuzzer_sys::fuzz_target;
+use std::{collections::HashMap, hash::BuildHasher};
+
+fn run<S2: BuildHasher + Clone>(ctrie: Ctrie<u8, u8, TinyState, 6, S2>, ops: &[u8]) {
+    let mut model = HashMap::new();
+    let guard = &epoch::pin();
+
+    for op in decode_ops(ops) {
+        match op {
+            Op::Insert(key, value) => {
+                ctrie.insert(key, value, guard);
+                model.insert(key, value);
+            }
+            Op::Lookup(key) => assert_eq!(ctrie.lookup(&key, guard), model.get(&key)),
+            Op::Remove(key) => assert_eq!(ctrie.remove(&key, guard), model.remove(&key).as_ref()),
+            Op::Retain(seed) => {
+                ctrie.retain(|&key, &value| retained(seed, key, value), guard);
+                model.retain(|&key, &mut value| retained(seed, key, value));
+            }
+            Op::InsertBatch(start, len) => {
+                let entries = batch(start, len);
+                ctrie.insert_batch(entries.iter().copied(), guard);
+                model.extend(entries);
+            }
+        }
+    }
+
+    assert_eq!(ctrie.validate(guard), Ok(model.len()));
+    for (key, value) in &model {
+        assert_eq!(ctrie.lookup(key, guard), Some(value));
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&config, ops)) = data.split_first() else {
+        return;
+    };
+    match fallback_limit(config) {
+        None => run(Ctrie::with_hasher(TinyState::default()), ops),
+        Some(limit) => run(
+            Ctrie::with_fallback_hasher(TinyState::default(), SplitState::default(), limit),
+            ops,
+        ),
+    }
+});
@@ -0,0 +1,774 @@
+use crate::{
+    gcas::{gcas, gcas_read},
+    hash_index,
+    hashers::Hashers,
+    node::{Branch, CtrieNode, IndirectionNode, ListNode, MainNode, MainNodeKind, SingletonNode},
+    path_order, Ctrie, DefaultHashBuilder, Generation, Key, Value,
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    hash::{BuildHasher, Hash},
+    ops::Range,
+};
+use crossbeam::epoch::{Atomic, Guard, Owned};
+
+/// Sorts hashed entries by the path they take through the trie, keeping only the last value of
+/// each key.
+///
+/// Returns the hashes and the entries as separate vectors, in the same order.
+pub(crate) fn sorted_entries<const W: usize, K: Key, V>(
+    mut sorted: Vec<(u128, K, V)>,
+) -> (Vec<u128>, Vec<(K, V)>) {
+    // the sort is stable, so later values of a key stay after earlier ones
+    sorted.sort_by_key(|&(hash, _, _)| path_order::<W>(hash));
+
+    let mut hashes = Vec::with_capacity(sorted.len());
+    let mut pairs: Vec<(K, V)> = Vec::with_capacity(sorted.len());
+    // the start of the run of entries with the same hash as the last one
+    let mut run = 0;
+    for (hash, key, value) in sorted {
+        if hashes.last() != Some(&hash) {
+            run = pairs.len();
+        }
+        match pairs[run..].iter_mut().find(|(k, _)| *k == key) {
+            Some(pair) => pair.1 = value,
+            None => {
+                hashes.push(hash);
+                pairs.push((key, value));
+            }
+        }
+    }
+    (hashes, pairs)
+}
+
+/// Sorts entries by the path order of their primary hashes, then extends the hashes of the entries
+/// that share their primary hash with another entry, and sorts each run of them by their whole
+/// hashes.
+///
+/// The other entries keep their primary hashes, which branch the same way as their whole hashes
+/// above `split`, until they reach a C-node that needs the rest.
+pub(crate) fn extend_collisions<const W: usize, K: Hash, V, S: BuildHasher, S2: BuildHasher>(
+    hashers: &Hashers<S, S2>,
+    entries: &mut [(u128, K, V)],
+) {
+    // the sort is stable, so later values of a key stay after earlier ones
+    entries.sort_by_key(|&(hash, _, _)| path_order::<W>(hash));
+    if hashers.bits() == 64 {
+        return;
+    }
+    for run in entries.chunk_by_mut(|(a, _, _), (b, _, _)| *a as u64 == *b as u64) {
+        if run.len() > 1 {
+            for (hash, key, _) in run.iter_mut() {
+                *hash = hashers.complete(key, *hash);
+            }
+            // the sort is stable, so later values of a key stay after earlier ones
+            run.sort_by_key(|&(hash, _, _)| path_order::<W>(hash));
+        }
+    }
+}
+
+/// Splits hashes sorted by path order into the runs that take the same branch of a C-node at
+/// `level`, and returns the index of the branch and the range of each run.
+pub(crate) fn groups<const W: usize>(
+    hashes: &[u128],
+    level: usize,
+) -> impl Iterator<Item = (u64, Range<usize>)> + '_ {
+    let mut start = 0;
+    core::iter::from_fn(move || {
+        let &hash = hashes.get(start)?;
+        let index = hash_index::<W>(hash, level);
+        let len = hashes[start..]
+            .iter()
+            .take_while(|&&hash| hash_index::<W>(hash, level) == index)
+            .count();
+        let range = start..start + len;
+        start += len;
+        Some((index, range))
+    })
+}
+
+/// Builds a C-node at `level` holding the given entries, which must be sorted by path order and
+/// have unique keys, exactly like inserting them one by one would.
+pub(crate) fn build_cnode<const W: usize, K, V, S, S2, I>(
+    hashes: &[u128],
+    entries: &mut I,
+    level: usize,
+    hashers: &Hashers<S, S2>,
+    generation: &Generation,
+) -> CtrieNode<K, V>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+    I: Iterator<Item = (K, V)>,
+{
+    let mut bitmap = 0;
+    let mut array = Vec::with_capacity(groups::<W>(hashes, level).count());
+    for (index, range) in groups::<W>(hashes, level) {
+        bitmap |= 1 << index;
+        array.push(build_branch::<W, _, _, _, _, _>(
+            &hashes[range],
+            entries,
+            level + W,
+            hashers,
+            generation,
+        ));
+    }
+    CtrieNode::new(bitmap, array, generation.clone())
+}
+
+/// Builds the branch holding the given entries, whose main node, if any, is at `level`.
+pub(crate) fn build_branch<const W: usize, K, V, S, S2, I>(
+    hashes: &[u128],
+    entries: &mut I,
+    level: usize,
+    hashers: &Hashers<S, S2>,
+    generation: &Generation,
+) -> Branch<K, V>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+    I: Iterator<Item = (K, V)>,
+{
+    if hashes.len() == 1 {
+        let (key, value) = entries.next().unwrap();
+        return Branch::Singleton(SingletonNode::new(key, value, hashes[0]));
+    }
+    let main = if level < hashers.list_level(hashes.len()) {
+        MainNode::from_ctrie_node(build_cnode::<W, _, _, _, _, _>(
+            hashes, entries, level, hashers, generation,
+        ))
+    } else {
+        // the keys have colliding hashes, or few enough colliding primary hashes to share a list,
+        // which keeps them in path order
+        let snodes = hashes
+            .iter()
+            .zip(entries)
+            .map(|(&hash, (key, value))| SingletonNode::new(key, value, hash))
+            .collect();
+        MainNode::from_list_node(ListNode::from_entries(snodes))
+    };
+    Branch::Indirection(Arc::new(IndirectionNode::new(
+        Atomic::new(main),
+        generation.clone(),
+    )))
+}
+
+/// Builds a ctrie from a large number of entries at once.
+///
+/// Entries are hashed as they are added and sorted by the path they take through the trie when the
+/// ctrie is built, so every node is allocated once, with exactly the branches it ends up with. The
+/// result has the same structure as inserting the entries one by one. If a key is added more than
+/// once, its last value wins.
+pub struct CtrieBuilder<K, V, S = DefaultHashBuilder, const W: usize = 6, S2 = S> {
+    entries: Vec<(u128, K, V)>,
+    hashers: Hashers<S, S2>,
+}
+
+impl<K, V> CtrieBuilder<K, V>
+where
+    K: Key,
+    V: Value,
+{
+    pub fn new() -> Self {
+        Self::with_hasher(DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V> Default for CtrieBuilder<K, V>
+where
+    K: Key,
+    V: Value,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, S> CtrieBuilder<K, V, S>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+{
+    pub fn with_hasher(hash_builder: S) -> Self {
+        Self::with_width(hash_builder)
+    }
+}
+
+impl<K, V, S, const W: usize> CtrieBuilder<K, V, S, W>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+{
+    /// Creates a builder for a ctrie with the given hasher and the width `W` of its type, like
+    /// `Ctrie::with_width`.
+    pub fn with_width(hash_builder: S) -> Self {
+        Self::with_hashers_of(Hashers::new(hash_builder))
+    }
+}
+
+impl<K, V, S, const W: usize, S2> CtrieBuilder<K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    pub(crate) fn with_hashers_of(hashers: Hashers<S, S2>) -> Self {
+        Self {
+            entries: Vec::new(),
+            hashers,
+        }
+    }
+
+    /// Reserves space for at least `additional` more entries.
+    pub fn reserve(&mut self, additional: usize) {
+        self.entries.reserve(additional);
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        let hash = self.hashers.primary_hash(&key);
+        self.entries.push((u128::from(hash), key, value));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn build(mut self) -> Ctrie<K, V, S, W, S2> {
+        extend_collisions::<W, _, _, _, _>(&self.hashers, &mut self.entries);
+        let (hashes, pairs) = sorted_entries::<W, _, _>(self.entries);
+        let generation = Generation::new();
+        let cnode = build_cnode::<W, _, _, _, _, _>(
+            &hashes,
+            &mut pairs.into_iter(),
+            0,
+            &self.hashers,
+            &generation,
+        );
+        Ctrie::from_root(
+            IndirectionNode::new(Atomic::new(MainNode::from_ctrie_node(cnode)), generation),
+            false,
+            self.hashers,
+        )
+    }
+}
+
+impl<K, V, S, const W: usize, S2> Extend<(K, V)> for CtrieBuilder<K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<K, V> Ctrie<K, V>
+where
+    K: Key,
+    V: Value,
+{
+    /// Builds a ctrie from the entries of an iterator with a `CtrieBuilder`, which is much faster
+    /// than inserting them one by one.
+    pub fn from_iter_bulk<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut builder = CtrieBuilder::new();
+        builder.extend(iter);
+        builder.build()
+    }
+}
+
+impl<K, V, S, const W: usize, S2> Ctrie<K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    /// Inserts every key-value pair from an iterator, like calling `insert` for each of them.
+    ///
+    /// The pairs are sorted by the path they take through the trie, so every C-node that gains
+    /// or changes branches is copied and swapped in once for the whole batch. Pairs whose swap
+    /// fails because of a concurrent write are inserted one at a time afterwards. If a key
+    /// appears more than once, its last value wins. Each pair is inserted atomically, but the
+    /// batch as a whole isn't.
+    pub fn insert_batch<I>(&self, entries: I, guard: &Guard)
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut entries = entries
+            .into_iter()
+            .map(|(key, value)| (u128::from(self.hashers.primary_hash(&key)), key, value))
+            .collect::<Vec<_>>();
+        extend_collisions::<W, _, _, _, _>(&self.hashers, &mut entries);
+        let (hashes, pairs) = sorted_entries::<W, _, _>(entries);
+        {
+            let publisher = self.events.publisher();
+            if !publisher.active() {
+                let mut leftovers = Vec::new();
+                let root = self.read_root(guard);
+                self.ibatch(
+                    root,
+                    &hashes,
+                    &pairs,
+                    0,
+                    0,
+                    None,
+                    root.generation(),
+                    &mut leftovers,
+                    guard,
+                );
+                for range in leftovers {
+                    for (&hash, (key, value)) in hashes[range.clone()].iter().zip(&pairs[range]) {
+                        self.insert_entry(hash as u64, key.clone(), value.clone(), guard);
+                    }
+                }
+                return;
+            }
+        }
+        // subscribers need every change separately, with the value it replaced
+        for (key, value) in pairs {
+            self.insert(key, value, guard);
+        }
+    }
+
+    /// Inserts a run of sorted entries into the subtree of an i-node, adding the ranges of the
+    /// entries that couldn't be inserted because of concurrent writes to `leftovers`.
+    ///
+    /// Only the hashes of entries that share their primary hash with another entry are extended,
+    /// like `extend_collisions` does.
+    #[allow(clippy::too_many_arguments)]
+    fn ibatch(
+        &self,
+        inode: &IndirectionNode<K, V>,
+        hashes: &[u128],
+        pairs: &[(K, V)],
+        offset: usize,
+        level: usize,
+        parent: Option<&IndirectionNode<K, V>>,
+        start_generation: &Generation,
+        leftovers: &mut Vec<Range<usize>>,
+        guard: &Guard,
+    ) {
+        let main_ptr = gcas_read(inode, self, guard);
+        let main = unsafe { main_ptr.deref() };
+
+        match main.kind() {
+            MainNodeKind::Ctrie(cnode) => {
+                // an entry that reaches the first c-node that branches on the secondary hash by
+                // itself doesn't have its whole hash yet
+                let extended;
+                let mut hashes = hashes;
+                if let ([hash], [(key, _)]) = (hashes, pairs) {
+                    if self.hashers.extends_at(level) {
+                        extended = [self.hashers.extend(key, *hash as u64)];
+                        hashes = &extended;
+                    }
+                }
+
+                let stale = cnode.generation() != inode.generation()
+                    || (0..cnode.branches()).any(|position| match cnode.branch(position) {
+                        Branch::Indirection(child) => child.generation() != start_generation,
+                        Branch::Singleton(_) => false,
+                    });
+                if stale {
+                    // renew the c-node first, like `insert` does
+                    let renewed = cnode.renewed(start_generation.clone(), self, guard);
+                    let new_main_ptr =
+                        Owned::new(MainNode::from_ctrie_node(renewed)).into_shared(guard);
+                    if gcas(inode, main_ptr, new_main_ptr, self, guard) {
+                        self.ibatch(
+                            inode,
+                            hashes,
+                            pairs,
+                            offset,
+                            level,
+                            parent,
+                            start_generation,
+                            leftovers,
+                            guard,
+                        );
+                    } else {
+                        leftovers.push(offset..offset + pairs.len());
+                    }
+                    return;
+                }
+
+                // new branches, in the order of their indices, and the ranges they hold
+                let mut updates = Vec::new();
+                let mut descents = Vec::new();
+                for (index, range) in groups::<W>(hashes, level) {
+                    let flag = 1 << index;
+                    if cnode.bitmap() & flag == 0 {
+                        let mut entries = pairs[range.clone()].iter().cloned();
+                        let branch = build_branch::<W, _, _, _, _, _>(
+                            &hashes[range.clone()],
+                            &mut entries,
+                            level + W,
+                            &self.hashers,
+                            inode.generation(),
+                        );
+                        updates.push((flag, branch, range));
+                        continue;
+                    }
+                    let position = (cnode.bitmap() & (flag - 1)).count_ones() as usize;
+                    match cnode.branch(position) {
+                        Branch::Indirection(child) => descents.push((child, range)),
+                        Branch::Singleton(snode) => {
+                            let branch = self.merged_branch(
+                                snode,
+                                &hashes[range.clone()],
+                                &pairs[range.clone()],
+                                level + W,
+                                inode.generation(),
+                            );
+                            updates.push((flag, branch, range));
+                        }
+                    }
+                }
+
+                if !updates.is_empty() {
+                    let bitmap = updates
+                        .iter()
+                        .fold(cnode.bitmap(), |bitmap, (flag, _, _)| bitmap | flag);
+                    let mut array = Vec::with_capacity(bitmap.count_ones() as usize);
+                    let mut position = 0;
+                    let mut new_branches = updates.iter().peekable();
+                    for index in 0..1 << W {
+                        let flag = 1 << index;
+                        if bitmap & flag == 0 {
+                            continue;
+                        }
+                        let old = cnode.bitmap() & flag != 0;
+                        match new_branches.next_if(|(new_flag, _, _)| *new_flag == flag) {
+                            Some((_, branch, _)) => array.push(branch.clone()),
+                            None => array.push(cnode.branch(position).clone()),
+                        }
+                        if old {
+                            position += 1;
+                        }
+                    }
+                    let new_cnode = CtrieNode::new(bitmap, array, inode.generation().clone());
+                    let new_main_ptr =
+                        Owned::new(MainNode::from_ctrie_node(new_cnode)).into_shared(guard);
+                    if !gcas(inode, main_ptr, new_main_ptr, self, guard) {
+                        for (_, _, range) in updates {
+                            leftovers.push(offset + range.start..offset + range.end);
+                        }
+                    }
+                }
+
+                // i-nodes are shared between copies of the c-node, so they can be written to
+                // whether or not the swap above succeeded
+                for (child, range) in descents {
+                    self.ibatch(
+                        child,
+                        &hashes[range.clone()],
+                        &pairs[range.clone()],
+                        offset + range.start,
+                        level + W,
+                        Some(inode),
+                        start_generation,
+                        leftovers,
+                        guard,
+                    );
+                }
+            }
+
+            MainNodeKind::List(lnode) => {
+                let list =
+                    hashes
+                        .iter()
+                        .zip(pairs)
+                        .fold(lnode.clone(), |list, (&hash, (key, value))| {
+                            let snode = self.list_entry(key.clone(), value.clone(), hash);
+                            self.list_inserted(&list, snode, guard)
+                        });
+                let new_main = self.collision_node(list, level, inode.generation(), guard);
+                let new_main_ptr = Owned::new(new_main).into_shared(guard);
+                if !gcas(inode, main_ptr, new_main_ptr, self, guard) {
+                    leftovers.push(offset..offset + pairs.len());
+                }
+            }
+
+            MainNodeKind::Tomb(_) => {
+                // the i-node is about to be removed from its parent, so help clean it up and
+                // leave the entries for later
+                if let Some(parent) = parent {
+                    self.clean(parent, level - W, guard);
+                }
+                leftovers.push(offset..offset + pairs.len());
+            }
+
+            MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
+        }
+    }
+
+    /// Builds the branch that replaces a singleton when a run of sorted entries is inserted next
+    /// to it.
+    ///
+    /// The hash of the singleton is only extended if one of the entries shares its primary hash.
+    fn merged_branch(
+        &self,
+        snode: &SingletonNode<K, V>,
+        hashes: &[u128],
+        pairs: &[(K, V)],
+        level: usize,
+        generation: &Generation,
+    ) -> Branch<K, V> {
+        if pairs.iter().any(|(key, _)| key == snode.key()) {
+            return build_branch::<W, _, _, _, _, _>(
+                hashes,
+                &mut pairs.iter().cloned(),
+                level,
+                &self.hashers,
+                generation,
+            );
+        }
+        let mut merged = Vec::with_capacity(hashes.len() + 1);
+        merged.extend(
+            hashes
+                .iter()
+                .zip(pairs)
+                .map(|(&hash, (key, value))| (hash, key.clone(), value.clone())),
+        );
+        merged.push((
+            snode.known_hash(),
+            snode.key().clone(),
+            snode.value().clone(),
+        ));
+        extend_collisions::<W, _, _, _, _>(&self.hashers, &mut merged);
+        let (merged_hashes, merged_pairs): (Vec<_>, Vec<_>) = merged
+            .into_iter()
+            .map(|(hash, key, value)| (hash, (key, value)))
+            .unzip();
+        build_branch::<W, _, _, _, _, _>(
+            &merged_hashes,
+            &mut merged_pairs.into_iter(),
+            level,
+            &self.hashers,
+            generation,
+        )
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{
+        hashers::tests::{lists, Tiny},
+        tests::{CountingHasher, TinyHasher},
+    };
+    use core::hash::BuildHasherDefault;
+    use crossbeam::epoch;
+    use std::{collections::HashMap, format, string::String};
+
+    /// Describes the shape of the subtree of a main node, ignoring the order of list entries.
+    fn shape<K: Key, V: Value, S: BuildHasher>(
+        ctrie: &Ctrie<K, V, S>,
+        inode: &IndirectionNode<K, V>,
+        guard: &Guard,
+    ) -> String {
+        let main = unsafe { gcas_read(inode, ctrie, guard).deref() };
+        match main.kind() {
+            MainNodeKind::Ctrie(cnode) => {
+                let branches = (0..cnode.branches())
+                    .map(|position| match cnode.branch(position) {
+                        Branch::Singleton(_) => String::from("s"),
+                        Branch::Indirection(child) => shape(ctrie, child, guard),
+                    })
+                    .collect::<Vec<_>>();
+                format!("{:x}[{}]", cnode.bitmap(), branches.join(","))
+            }
+            MainNodeKind::List(lnode) => format!("l{}", lnode.length(guard)),
+            MainNodeKind::Tomb(_) => String::from("t"),
+            MainNodeKind::Failed => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn from_iter_bulk() {
+        let guard = &epoch::pin();
+        let bulk = Ctrie::from_iter_bulk((0..5000).rev().map(|i| (i, i)));
+        let incremental = Ctrie::with_hasher(bulk.hasher().clone());
+        for i in 0..5000 {
+            incremental.insert(i, i, guard);
+        }
+        assert_eq!(
+            shape(&bulk, bulk.read_root(guard), guard),
+            shape(&incremental, incremental.read_root(guard), guard)
+        );
+        assert_eq!(bulk.diff(&incremental, guard).count(), 0);
+        assert_eq!(bulk.validate(guard), Ok(5000));
+
+        // the built ctrie works like any other
+        bulk.insert(5000, 5000, guard);
+        assert_eq!(bulk.remove(&0, guard), Some(&0));
+        assert_eq!(bulk.validate(guard), Ok(5000));
+        assert_eq!(
+            Ctrie::<u32, u32>::from_iter_bulk(None).validate(guard),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn builder_collisions() {
+        let guard = &epoch::pin();
+        let incremental = Ctrie::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        let mut builder = CtrieBuilder::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        for i in 0..100u32 {
+            incremental.insert(i, i, guard);
+            builder.insert(i, 0);
+        }
+        // the last value of a key wins
+        builder.extend((0..100u32).map(|i| (i, i)));
+        assert_eq!(builder.len(), 200);
+
+        let bulk = builder.build();
+        assert_eq!(
+            shape(&bulk, bulk.read_root(guard), guard),
+            shape(&incremental, incremental.read_root(guard), guard)
+        );
+        assert_eq!(bulk.diff(&incremental, guard).count(), 0);
+        assert_eq!(bulk.validate(guard), Ok(100));
+    }
+
+    #[test]
+    fn insert_batch() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        // later values of a key replace earlier ones
+        ctrie.insert_batch((0..1000).chain(0..100).map(|i| (i, i)), guard);
+        ctrie.insert_batch((0..100).map(|i| (i, i + 1)).chain(Some((5, 0))), guard);
+
+        for i in 0..1000 {
+            let expected = match i {
+                5 => 0,
+                i if i < 100 => i + 1,
+                i => i,
+            };
+            assert_eq!(ctrie.lookup(&i, guard), Some(&expected));
+        }
+        assert_eq!(ctrie.validate(guard), Ok(1000));
+    }
+
+    #[test]
+    fn insert_batch_existing() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        let mut model = HashMap::new();
+        for i in (0..2000).step_by(7) {
+            ctrie.insert(i, i, guard);
+            model.insert(i, i);
+        }
+        let snapshot = ctrie.snapshot(guard);
+
+        // the batch lands next to existing singletons, inside existing subtrees, and on top of
+        // existing keys
+        let batch = (0..2000).step_by(3).map(|i| (i, i * 2)).collect::<Vec<_>>();
+        ctrie.insert_batch(batch.clone(), guard);
+        model.extend(batch);
+
+        for (key, value) in &model {
+            assert_eq!(ctrie.lookup(key, guard), Some(value));
+        }
+        assert_eq!(ctrie.validate(guard), Ok(model.len()));
+        // the snapshot doesn't see the batch
+        assert_eq!(snapshot.lookup(&3, guard), None);
+        assert_eq!(snapshot.lookup(&21, guard), Some(&21));
+    }
+
+    #[test]
+    fn insert_batch_collisions() {
+        let ctrie = Ctrie::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        let guard = &epoch::pin();
+        ctrie.insert_batch((0..50u32).map(|i| (i, i)), guard);
+        ctrie.insert(50, 50, guard);
+        ctrie.insert_batch((25..100u32).map(|i| (i, i + 1)), guard);
+
+        for i in 0..100u32 {
+            let expected = if i < 25 { i } else { i + 1 };
+            assert_eq!(ctrie.lookup(&i, guard), Some(&expected));
+        }
+        assert_eq!(ctrie.validate(guard), Ok(100));
+    }
+
+    #[test]
+    fn insert_batch_secondary_hashes() {
+        let guard = &epoch::pin();
+        // keys whose primary hashes collide are spread by their secondary hashes
+        let incremental =
+            Ctrie::with_fallback_hasher(Tiny::default(), DefaultHashBuilder::default(), 2);
+        let batched =
+            Ctrie::with_fallback_hasher(Tiny::default(), DefaultHashBuilder::default(), 2);
+        for i in (0..300u32).step_by(2) {
+            incremental.insert(i, i, guard);
+            batched.insert(i, i, guard);
+        }
+        for i in 0..300u32 {
+            incremental.insert(i, i + 1, guard);
+        }
+        batched.insert_batch((0..300u32).map(|i| (i, i + 1)), guard);
+        assert!(lists(&batched, batched.read_root(guard), guard)
+            .iter()
+            .all(|&len| len <= 2));
+        assert_eq!(batched.diff(&incremental, guard).count(), 0);
+        assert_eq!(batched.validate(guard), Ok(300));
+
+        // and no other keys are hashed by the secondary hasher
+        let secondary = CountingHasher::default();
+        let mut builder = CtrieBuilder::<_, _, _, 6>::with_hashers_of(
+            Hashers::with_secondary::<6>(CountingHasher::default(), secondary.clone(), 0),
+        );
+        builder.extend((0..1000u32).map(|i| (i, i)));
+        let ctrie = builder.build();
+        ctrie.insert_batch((500..2000u32).map(|i| (i, i + 1)), guard);
+        assert_eq!(secondary.count(), 0);
+        assert_eq!(ctrie.validate(guard), Ok(2000));
+    }
+
+    #[test]
+    fn concurrent_insert_batch() {
+        let ctrie = Ctrie::new();
+        std::thread::scope(|scope| {
+            for t in 0..4u32 {
+                let ctrie = &ctrie;
+                scope.spawn(move || {
+                    let guard = &epoch::pin();
+                    for round in 0..20u32 {
+                        let batch = (0..100).map(|i| ((i * 4 + t) * 20 + round, t));
+                        ctrie.insert_batch(batch, guard);
+                        // snapshots renew the nodes batches run into
+                        if round % 5 == 0 {
+                            ctrie.snapshot(guard);
+                        }
+                    }
+                });
+            }
+        });
+
+        let guard = &epoch::pin();
+        for key in 0..8000u32 {
+            assert_eq!(ctrie.lookup(&key, guard), Some(&(key / 20 % 4)));
+        }
+        assert_eq!(ctrie.validate(guard), Ok(8000));
+    }
+}
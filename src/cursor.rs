@@ -0,0 +1,348 @@
+use crate::{
+    gcas::gcas_read,
+    hash_index,
+    node::{Branch, MainNode, MainNodeKind, SingletonNode},
+    path_order, Ctrie, Key, Value,
+};
+use alloc::vec::Vec;
+use core::{cmp::Ordering, hash::BuildHasher, ops::ControlFlow};
+use crossbeam::epoch::Guard;
+
+/// A position in a scan over the entries of a ctrie in hash order.
+///
+/// A cursor is the hash of the next entry to return, and the offset of that entry among the
+/// entries of its collision list with the same hash, if it is in one. It doesn't borrow the ctrie,
+/// so it can be kept, sent elsewhere, and rebuilt from its parts between pages of a scan.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct HashCursor {
+    hash: u128,
+    offset: usize,
+}
+
+impl HashCursor {
+    /// Returns the cursor at the first entry, the same as `HashCursor::default()`.
+    pub fn start() -> Self {
+        Self::default()
+    }
+
+    pub fn new(hash: u128, offset: usize) -> Self {
+        Self { hash, offset }
+    }
+
+    pub fn hash(&self) -> u128 {
+        self.hash
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+/// The state of a single page of a scan.
+struct Scan<'a, 'g, K, V, S, const W: usize, S2> {
+    snapshot: &'a Ctrie<K, V, S, W, S2>,
+    cursor: HashCursor,
+    limit: usize,
+    entries: Vec<(&'g K, &'g V)>,
+    guard: &'g Guard,
+}
+
+impl<'a, 'g, K, V, S, const W: usize, S2> Scan<'a, 'g, K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    /// Visits the entries of the subtree of a main node at `level`, skipping those before the
+    /// cursor if the subtree is on the path to it.
+    fn main(
+        &mut self,
+        main: &'g MainNode<K, V>,
+        level: usize,
+        bounded: bool,
+    ) -> ControlFlow<HashCursor> {
+        match main.kind() {
+            MainNodeKind::Ctrie(cnode) => {
+                let first = if bounded {
+                    hash_index::<W>(self.cursor.hash, level)
+                } else {
+                    0
+                };
+                let mut bitmap = cnode.bitmap();
+                let mut position = 0;
+                while bitmap != 0 {
+                    let index = u64::from(bitmap.trailing_zeros());
+                    bitmap &= bitmap - 1;
+                    let branch = cnode.branch(position);
+                    position += 1;
+                    if index >= first {
+                        self.branch(branch, level + W, bounded && index == first)?;
+                    }
+                }
+                ControlFlow::Continue(())
+            }
+            MainNodeKind::List(lnode) => {
+                // lists are sorted by hash, so the entries before the cursor's have a lower hash,
+                // or the same hash and a lower offset among the entries with it
+                let mut last = None;
+                let mut offset = 0;
+                for snode in lnode.entries(self.guard) {
+                    let order = path_order::<W>(self.snapshot.snode_hash(snode));
+                    offset = if last == Some(order) { offset + 1 } else { 0 };
+                    last = Some(order);
+                    if bounded {
+                        let skipped = match order.cmp(&path_order::<W>(self.cursor.hash)) {
+                            Ordering::Less => true,
+                            Ordering::Equal => offset < self.cursor.offset,
+                            Ordering::Greater => false,
+                        };
+                        if skipped {
+                            continue;
+                        }
+                    }
+                    self.emit(snode, offset)?;
+                }
+                ControlFlow::Continue(())
+            }
+            MainNodeKind::Tomb(tnode) => self.singleton(tnode.snode(), bounded),
+            MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
+        }
+    }
+
+    fn branch(
+        &mut self,
+        branch: &'g Branch<K, V>,
+        level: usize,
+        bounded: bool,
+    ) -> ControlFlow<HashCursor> {
+        match branch {
+            Branch::Singleton(snode) => self.singleton(snode, bounded),
+            Branch::Indirection(inode) => {
+                let main_ptr = gcas_read(inode, self.snapshot, self.guard);
+                self.main(unsafe { main_ptr.deref() }, level, bounded)
+            }
+        }
+    }
+
+    fn singleton(
+        &mut self,
+        snode: &'g SingletonNode<K, V>,
+        bounded: bool,
+    ) -> ControlFlow<HashCursor> {
+        if bounded {
+            // the chunks of the hash used so far match the cursor's, but the rest may not, and
+            // the secondary hash only matters if the primary hashes are the same
+            let hash = if snode.hash() == self.cursor.hash as u64 {
+                self.snapshot.snode_hash(snode)
+            } else {
+                u128::from(snode.hash())
+            };
+            let skipped = match path_order::<W>(hash).cmp(&path_order::<W>(self.cursor.hash)) {
+                Ordering::Less => true,
+                Ordering::Equal => self.cursor.offset > 0,
+                Ordering::Greater => false,
+            };
+            if skipped {
+                return ControlFlow::Continue(());
+            }
+        }
+        self.emit(snode, 0)
+    }
+
+    /// Adds an entry to the page, or stops at it if the page is full.
+    fn emit(&mut self, snode: &'g SingletonNode<K, V>, offset: usize) -> ControlFlow<HashCursor> {
+        if self.entries.len() == self.limit {
+            let hash = self.snapshot.snode_hash(snode);
+            return ControlFlow::Break(HashCursor::new(hash, offset));
+        }
+        self.entries.push((snode.key(), snode.value()));
+        ControlFlow::Continue(())
+    }
+}
+
+impl<K, V, S, const W: usize, S2> Ctrie<K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher + Clone,
+    S2: BuildHasher + Clone,
+{
+    /// Returns up to `limit` entries in hash order, starting at `cursor`, and the cursor to pass
+    /// to get the next page, or `None` if there are no more entries.
+    ///
+    /// Each page is read from its own read-only snapshot, so a scan sees entries added or removed
+    /// between pages only if they are after the cursor. An entry present for the whole scan is
+    /// returned exactly once, unless keys with the same hash are removed between pages, which can
+    /// shift its offset among them. Scanning a read-only snapshot pages through the same contents
+    /// every time.
+    pub fn scan_from<'g>(
+        &self,
+        cursor: HashCursor,
+        limit: usize,
+        guard: &'g Guard,
+    ) -> (Vec<(&'g K, &'g V)>, Option<HashCursor>)
+    where
+        K: 'g,
+        V: 'g,
+    {
+        let snapshot = self.read_only_snapshot(guard);
+        let mut scan = Scan {
+            snapshot: &snapshot,
+            cursor,
+            limit,
+            entries: Vec::new(),
+            guard,
+        };
+        let main_ptr = gcas_read(snapshot.read_root(guard), &snapshot, guard);
+        let next = match scan.main(unsafe { main_ptr.deref() }, 0, true) {
+            ControlFlow::Break(next) => Some(next),
+            ControlFlow::Continue(()) => None,
+        };
+        (scan.entries, next)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{
+        hashers::tests::{lists, Tiny},
+        tests::TinyHasher,
+        DefaultHashBuilder,
+    };
+    use core::hash::BuildHasherDefault;
+    use crossbeam::epoch;
+
+    fn scan_all<K: Key, V: Value, S: BuildHasher + Clone, S2: BuildHasher + Clone>(
+        ctrie: &Ctrie<K, V, S, 6, S2>,
+        limit: usize,
+    ) -> Vec<(K, V)> {
+        let mut entries = Vec::new();
+        let mut cursor = Some(HashCursor::start());
+        while let Some(start) = cursor {
+            // a new guard for every page
+            let guard = &epoch::pin();
+            let (page, next) = ctrie.scan_from(start, limit, guard);
+            assert!(page.len() <= limit);
+            entries.extend(page.into_iter().map(|(k, v)| (k.clone(), v.clone())));
+            cursor = next;
+        }
+        entries
+    }
+
+    #[test]
+    fn scan_from() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..1000u32 {
+            ctrie.insert(i, i, guard);
+        }
+        let all = ctrie.iter(guard).map(|(&k, &v)| (k, v)).collect::<Vec<_>>();
+        for limit in [1, 7, 64, 1000, 5000] {
+            assert_eq!(scan_all(&ctrie, limit), all);
+        }
+
+        let (page, next) = ctrie.scan_from(HashCursor::start(), 10, guard);
+        let next = next.unwrap();
+        assert_eq!(next.hash(), ctrie.hash(&all[10].0));
+        assert_eq!(page.len(), 10);
+        assert_eq!(
+            ctrie.scan_from(next, 1, guard).0,
+            vec![(&all[10].0, &all[10].1)]
+        );
+        assert_eq!(ctrie.scan_from(HashCursor::start(), 0, guard).0, vec![]);
+        assert_eq!(
+            Ctrie::<u32, u32>::new().scan_from(next, 10, guard),
+            (vec![], None)
+        );
+    }
+
+    #[test]
+    fn scan_from_collisions() {
+        let ctrie = Ctrie::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        let guard = &epoch::pin();
+        for i in 0..100u32 {
+            ctrie.insert(i, i, guard);
+        }
+        let all = ctrie.iter(guard).map(|(&k, &v)| (k, v)).collect::<Vec<_>>();
+        for limit in [1, 3, 30, 100] {
+            assert_eq!(scan_all(&ctrie, limit), all);
+        }
+    }
+
+    #[test]
+    fn scan_spread_lists() {
+        let ctrie = Ctrie::with_fallback_hasher(Tiny::default(), DefaultHashBuilder::default(), 8);
+        let guard = &epoch::pin();
+        for i in 0..24u32 {
+            ctrie.insert(i, i, guard);
+        }
+        let all = ctrie.iter(guard).map(|(&k, _)| k).collect::<Vec<_>>();
+        assert!(lists(&ctrie, ctrie.read_root(guard), guard)
+            .iter()
+            .any(|&len| len > 3));
+        assert_eq!(scan_all(&ctrie, 5).len(), 24);
+
+        for limit in [1, 2, 3, 5] {
+            let ctrie =
+                Ctrie::with_fallback_hasher(Tiny::default(), DefaultHashBuilder::default(), 8);
+            for &key in &all {
+                ctrie.insert(key, key, guard);
+            }
+            // the lists the cursors point into are spread over c-nodes between pages
+            let mut keys = Vec::new();
+            let mut cursor = Some(HashCursor::start());
+            let mut round = 0;
+            while let Some(start) = cursor {
+                let (page, next) = ctrie.scan_from(start, limit, guard);
+                keys.extend(page.into_iter().map(|(&k, _)| k));
+                cursor = next;
+                if round == 1 {
+                    for i in 24..60 {
+                        ctrie.insert(i, i, guard);
+                    }
+                    assert!(lists(&ctrie, ctrie.read_root(guard), guard)
+                        .iter()
+                        .all(|&len| len <= 8));
+                }
+                round += 1;
+            }
+            for key in &all {
+                assert_eq!(keys.iter().filter(|&k| k == key).count(), 1, "{}", key);
+            }
+            let mut unique = keys.clone();
+            unique.sort_unstable();
+            unique.dedup();
+            assert_eq!(unique.len(), keys.len());
+        }
+    }
+
+    #[test]
+    fn scan_snapshot() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..500u32 {
+            ctrie.insert(i, i, guard);
+        }
+        let snapshot = ctrie.read_only_snapshot(guard);
+        let expected = snapshot
+            .iter(guard)
+            .map(|(&k, &v)| (k, v))
+            .collect::<Vec<_>>();
+
+        // writes between pages don't affect a scan of a snapshot
+        let mut entries = Vec::new();
+        let mut cursor = Some(HashCursor::start());
+        let mut round = 0;
+        while let Some(start) = cursor {
+            let (page, next) = snapshot.scan_from(start, 50, guard);
+            entries.extend(page.into_iter().map(|(&k, &v)| (k, v)));
+            cursor = next;
+            ctrie.remove(&round, guard);
+            ctrie.insert(1000 + round, round, guard);
+            round += 1;
+        }
+        assert_eq!(entries, expected);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::{
+    gcas::gcas_read,
+    node::{Branch, CtrieNode, MainNode, MainNodeKind, SingletonNode},
+    Ctrie, Key, Value,
+};
+use alloc::{collections::VecDeque, sync::Arc, vec, vec::Vec};
+use core::{hash::BuildHasher, ptr};
+use crossbeam::epoch::Guard;
+
+/// A difference between two ctries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change<K, V> {
+    /// The key is only in the new ctrie.
+    Added { key: K, value: V },
+    /// The key is only in the old ctrie.
+    Removed { key: K, value: V },
+    /// The key is in both ctries, with different values.
+    Updated { key: K, old: V, new: V },
+}
+
+impl<K, V> Change<K, V> {
+    /// Returns the key that changed.
+    pub fn key(&self) -> &K {
+        match self {
+            Change::Added { key, .. }
+            | Change::Removed { key, .. }
+            | Change::Updated { key, .. } => key,
+        }
+    }
+}
+
+// a main node of the old ctrie and the main node at the same position in the new ctrie
+type MainPair<'g, K, V> = (&'g MainNode<K, V>, &'g MainNode<K, V>);
+
+/// An iterator over the differences between two ctries.
+///
+/// Compares read-only snapshots of the two ctries, and skips every subtree the snapshots share,
+/// so diffing two snapshots of the same ctrie takes time proportional to the changes between them
+/// rather than to their size.
+pub struct Diff<'g, K, V, S, const W: usize = 6, S2 = S> {
+    old: Ctrie<K, V, S, W, S2>,
+    new: Ctrie<K, V, S, W, S2>,
+    // pairs of main nodes still to be compared
+    stack: Vec<MainPair<'g, K, V>>,
+    // changes found but not yet returned
+    changes: VecDeque<Change<K, V>>,
+    guard: &'g Guard,
+}
+
+impl<'g, K, V, S, const W: usize, S2> Diff<'g, K, V, S, W, S2>
+where
+    K: Key,
+    V: Value + PartialEq,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    pub(crate) fn new(
+        old: Ctrie<K, V, S, W, S2>,
+        new: Ctrie<K, V, S, W, S2>,
+        guard: &'g Guard,
+    ) -> Self {
+        debug_assert!(old.read_only() && new.read_only());
+        let old_main = gcas_read(old.read_root(guard), &old, guard);
+        let new_main = gcas_read(new.read_root(guard), &new, guard);
+        Self {
+            old,
+            new,
+            stack: unsafe { vec![(old_main.deref(), new_main.deref())] },
+            changes: VecDeque::new(),
+            guard,
+        }
+    }
+
+    /// Compares two main nodes, queueing the changes between them and the pairs of children that
+    /// differ.
+    fn compare(&mut self, old: &'g MainNode<K, V>, new: &'g MainNode<K, V>) {
+        // nodes reachable from a read-only snapshot never change, so shared nodes have no changes
+        if ptr::eq(old, new) {
+            return;
+        }
+        match (old.kind(), new.kind()) {
+            (MainNodeKind::Ctrie(old), MainNodeKind::Ctrie(new)) => self.compare_cnodes(old, new),
+            _ => {
+                let old = self.entries(old, true);
+                let new = self.entries(new, false);
+                self.match_entries(old, new);
+            }
+        }
+    }
+
+    fn compare_cnodes(&mut self, old: &'g CtrieNode<K, V>, new: &'g CtrieNode<K, V>) {
+        for index in 0..1 << W {
+            let flag = 1u64 << index;
+            let old_branch = (old.bitmap() & flag != 0)
+                .then(|| old.branch((old.bitmap() & (flag - 1)).count_ones() as usize));
+            let new_branch = (new.bitmap() & flag != 0)
+                .then(|| new.branch((new.bitmap() & (flag - 1)).count_ones() as usize));
+            match (old_branch, new_branch) {
+                (None, None) => {}
+                (Some(Branch::Singleton(old)), Some(Branch::Singleton(new))) => {
+                    self.match_entries(vec![old], vec![new])
+                }
+                (Some(Branch::Indirection(old)), Some(Branch::Indirection(new))) => {
+                    if !Arc::ptr_eq(old, new) {
+                        let old_main = gcas_read(old, &self.old, self.guard);
+                        let new_main = gcas_read(new, &self.new, self.guard);
+                        self.stack
+                            .push(unsafe { (old_main.deref(), new_main.deref()) });
+                    }
+                }
+                (old, new) => {
+                    let old = old.map_or(vec![], |branch| self.branch_entries(branch, true));
+                    let new = new.map_or(vec![], |branch| self.branch_entries(branch, false));
+                    self.match_entries(old, new);
+                }
+            }
+        }
+    }
+
+    /// Queues the changes between two lists of entries with the same hash prefix.
+    fn match_entries(
+        &mut self,
+        old: Vec<&'g SingletonNode<K, V>>,
+        mut new: Vec<&'g SingletonNode<K, V>>,
+    ) {
+        for old in old {
+            match new.iter().position(|new| new.key() == old.key()) {
+                Some(position) => {
+                    let new = new.swap_remove(position);
+                    if new.value() != old.value() {
+                        self.changes.push_back(Change::Updated {
+                            key: old.key().clone(),
+                            old: old.value().clone(),
+                            new: new.value().clone(),
+                        });
+                    }
+                }
+                None => self.changes.push_back(Change::Removed {
+                    key: old.key().clone(),
+                    value: old.value().clone(),
+                }),
+            }
+        }
+        for new in new {
+            self.changes.push_back(Change::Added {
+                key: new.key().clone(),
+                value: new.value().clone(),
+            });
+        }
+    }
+
+    fn branch_entries(&self, branch: &'g Branch<K, V>, old: bool) -> Vec<&'g SingletonNode<K, V>> {
+        match branch {
+            Branch::Singleton(snode) => vec![snode],
+            Branch::Indirection(inode) => {
+                let ctrie = if old { &self.old } else { &self.new };
+                let main_ptr = gcas_read(inode, ctrie, self.guard);
+                self.entries(unsafe { main_ptr.deref() }, old)
+            }
+        }
+    }
+
+    /// Returns every entry in the subtree of a main node.
+    fn entries(&self, main: &'g MainNode<K, V>, old: bool) -> Vec<&'g SingletonNode<K, V>> {
+        match main.kind() {
+            MainNodeKind::Ctrie(cnode) => (0..cnode.branches())
+                .flat_map(|position| self.branch_entries(cnode.branch(position), old))
+                .collect(),
+            MainNodeKind::List(lnode) => lnode.entries(self.guard).collect(),
+            MainNodeKind::Tomb(tnode) => vec![tnode.snode()],
+            MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
+        }
+    }
+}
+
+impl<'g, K, V, S, const W: usize, S2> Iterator for Diff<'g, K, V, S, W, S2>
+where
+    K: Key,
+    V: Value + PartialEq,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    type Item = Change<K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(change) = self.changes.pop_front() {
+                return Some(change);
+            }
+            let (old, new) = self.stack.pop()?;
+            self.compare(old, new);
+        }
+    }
+}
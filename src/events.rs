@@ -0,0 +1,160 @@
+//! Publishing changes to subscribers in the order they were committed.
+//!
+//! Without subscribers, writers don't synchronize with each other at all. Once anyone subscribes,
+//! writers take a lock around committing a change and publishing it, so subscribers see the
+//! changes in the order they took effect.
+
+use crate::Change;
+#[cfg(not(feature = "std"))]
+use core::marker::PhantomData;
+#[cfg(feature = "std")]
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Mutex, MutexGuard, RwLock, RwLockReadGuard,
+    },
+    vec::Vec,
+};
+
+/// A change to a ctrie, published to its subscribers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event<K, V> {
+    /// A key was added, removed or updated.
+    Change(Change<K, V>),
+    /// Every entry was removed.
+    Cleared,
+}
+
+impl<K, V> Event<K, V> {
+    /// Returns the key that changed, or `None` if every key changed.
+    pub fn key(&self) -> Option<&K> {
+        match self {
+            Event::Change(change) => Some(change.key()),
+            Event::Cleared => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+struct Subscriber<K, V> {
+    // only changes to this key are sent, if set
+    key: Option<K>,
+    sender: Sender<Event<K, V>>,
+}
+
+#[cfg(feature = "std")]
+pub(crate) struct Events<K, V> {
+    // whether there are any subscribers, in which case writers must publish under the lock
+    active: AtomicBool,
+    // held shared by every writer that saw no subscribers until it finishes, and exclusively by a
+    // new subscriber to wait for them
+    unlocked_writers: RwLock<()>,
+    subscribers: Mutex<Vec<Subscriber<K, V>>>,
+}
+
+#[cfg(feature = "std")]
+impl<K, V> Events<K, V>
+where
+    K: Clone + Eq,
+    V: Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            active: AtomicBool::new(false),
+            unlocked_writers: RwLock::new(()),
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn subscribe(&self, key: Option<K>) -> Receiver<Event<K, V>> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        self.active.store(true, Ordering::SeqCst);
+        // a writer that saw no subscribers may be about to commit a change, which must not be
+        // committed after the subscription without being published to it
+        drop(self.unlocked_writers.write().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        subscribers.push(Subscriber { key, sender });
+        receiver
+    }
+
+    /// Starts a write, which must commit any changes before the returned publisher is dropped.
+    pub fn publisher(&self) -> Publisher<'_, K, V> {
+        // pairs with `subscribe`: either the subscriber waits for this writer, or this writer sees
+        // the subscriber
+        let unlocked = self.unlocked_writers.read().unwrap();
+        if !self.active.load(Ordering::SeqCst) {
+            return Publisher {
+                events: self,
+                _unlocked: Some(unlocked),
+                subscribers: None,
+            };
+        }
+        drop(unlocked);
+        Publisher {
+            events: self,
+            _unlocked: None,
+            subscribers: Some(self.subscribers.lock().unwrap()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub(crate) struct Publisher<'a, K, V> {
+    events: &'a Events<K, V>,
+    _unlocked: Option<RwLockReadGuard<'a, ()>>,
+    subscribers: Option<MutexGuard<'a, Vec<Subscriber<K, V>>>>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, K, V> Publisher<'a, K, V>
+where
+    K: Clone + Eq,
+    V: Clone,
+{
+    /// Returns whether changes committed by this write have to be published.
+    pub fn active(&self) -> bool {
+        self.subscribers.is_some()
+    }
+
+    pub fn publish(&mut self, event: Event<K, V>) {
+        if let Some(subscribers) = &mut self.subscribers {
+            subscribers.retain(|subscriber| match (&subscriber.key, event.key()) {
+                (Some(key), Some(changed)) if key != changed => true,
+                // a subscriber whose receiver was dropped is no longer interested
+                _ => subscriber.sender.send(event.clone()).is_ok(),
+            });
+            if subscribers.is_empty() {
+                self.events.active.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+// without std there's no way to subscribe, so there's never anything to publish
+
+#[cfg(not(feature = "std"))]
+pub(crate) struct Events<K, V>(PhantomData<(K, V)>);
+
+#[cfg(not(feature = "std"))]
+impl<K, V> Events<K, V> {
+    pub fn new() -> Self {
+        Self(PhantomData)
+    }
+
+    pub fn publisher(&self) -> Publisher<K, V> {
+        Publisher(PhantomData)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) struct Publisher<K, V>(PhantomData<(K, V)>);
+
+#[cfg(not(feature = "std"))]
+impl<K, V> Publisher<K, V> {
+    pub fn active(&self) -> bool {
+        false
+    }
+
+    pub fn publish(&mut self, _event: Event<K, V>) {}
+}
@@ -0,0 +1,322 @@
+use crate::{
+    gcas::gcas_read,
+    hashers::Hashers,
+    node::{Branch, CtrieNode, IndirectionNode, ListNode, MainNode, MainNodeKind, SingletonNode},
+    Ctrie, Generation, Key, Value,
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::hash::BuildHasher;
+use crossbeam::epoch::{Atomic, Guard};
+
+/// Copies the trie of a read-only snapshot, mapping or dropping every entry.
+///
+/// Keys stay where they are, so nothing is rehashed. Subtrees that lose all but one entry are
+/// contracted like removals would.
+struct FilterMap<'a, K, V, S, const W: usize, S2, F> {
+    snapshot: &'a Ctrie<K, V, S, W, S2>,
+    f: F,
+    generation: Generation,
+}
+
+impl<'a, K, V, S, F, U, const W: usize, S2> FilterMap<'a, K, V, S, W, S2, F>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+    F: Fn(&K, &V) -> Option<U>,
+    U: Value,
+{
+    fn root<'g>(&self, guard: &'g Guard) -> &'g CtrieNode<K, V> {
+        let main_ptr = gcas_read(self.snapshot.read_root(guard), self.snapshot, guard);
+        match unsafe { main_ptr.deref() }.kind() {
+            MainNodeKind::Ctrie(cnode) => cnode,
+            _ => unreachable!("the root main node is always a c-node"),
+        }
+    }
+
+    /// Returns the ctrie with the given root branches, the results of the root C-node's branches.
+    fn ctrie<I>(
+        &self,
+        root: &CtrieNode<K, V>,
+        branches: I,
+        hashers: Hashers<S, S2>,
+    ) -> Ctrie<K, U, S, W, S2>
+    where
+        I: IntoIterator<Item = Option<Branch<K, U>>>,
+    {
+        let cnode = self.cnode(root, branches);
+        Ctrie::from_root(
+            IndirectionNode::new(
+                Atomic::new(MainNode::from_ctrie_node(cnode)),
+                self.generation.clone(),
+            ),
+            false,
+            hashers,
+        )
+    }
+
+    /// Builds the C-node replacing `cnode`, given what each of its branches turned into.
+    fn cnode<I>(&self, cnode: &CtrieNode<K, V>, branches: I) -> CtrieNode<K, U>
+    where
+        I: IntoIterator<Item = Option<Branch<K, U>>>,
+    {
+        let mut old_bitmap = cnode.bitmap();
+        let mut bitmap = 0;
+        let mut array = Vec::with_capacity(cnode.branches());
+        for branch in branches {
+            let flag = old_bitmap & old_bitmap.wrapping_neg();
+            old_bitmap &= old_bitmap - 1;
+            if let Some(branch) = branch {
+                bitmap |= flag;
+                array.push(branch);
+            }
+        }
+        CtrieNode::new(bitmap, array, self.generation.clone())
+    }
+
+    /// Returns what a branch turns into.
+    fn branch(&self, branch: &Branch<K, V>, guard: &Guard) -> Option<Branch<K, U>> {
+        let inode = match branch {
+            Branch::Singleton(snode) => return self.singleton(snode).map(Branch::Singleton),
+            Branch::Indirection(inode) => inode,
+        };
+        let main_ptr = gcas_read(inode, self.snapshot, guard);
+        let main = match unsafe { main_ptr.deref() }.kind() {
+            MainNodeKind::Ctrie(cnode) => {
+                let branches = (0..cnode.branches())
+                    .map(|position| self.branch(cnode.branch(position), guard));
+                let cnode = self.cnode(cnode, branches);
+                match cnode.branches() {
+                    0 => return None,
+                    // contract a lone singleton into its parent, like removals do
+                    1 if matches!(cnode.branch(0), Branch::Singleton(_)) => {
+                        return Some(cnode.branch(0).clone())
+                    }
+                    _ => MainNode::from_ctrie_node(cnode),
+                }
+            }
+            MainNodeKind::List(lnode) => {
+                let mut entries = lnode
+                    .entries(guard)
+                    .filter_map(|snode| self.singleton(snode))
+                    .collect::<Vec<_>>();
+                match entries.len() {
+                    0 => return None,
+                    1 => return entries.pop().map(Branch::Singleton),
+                    // the entries keep their order, which a secondary hasher sorts
+                    _ => MainNode::from_list_node(ListNode::from_entries(entries)),
+                }
+            }
+            // resurrect the entombed singleton, since the contraction it's waiting for is
+            // already done here
+            MainNodeKind::Tomb(tnode) => {
+                return self.singleton(tnode.snode()).map(Branch::Singleton)
+            }
+            MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
+        };
+        Some(Branch::Indirection(Arc::new(IndirectionNode::new(
+            Atomic::new(main),
+            self.generation.clone(),
+        ))))
+    }
+
+    fn singleton(&self, snode: &SingletonNode<K, V>) -> Option<SingletonNode<K, U>> {
+        let value = (self.f)(snode.key(), snode.value())?;
+        Some(SingletonNode::new(
+            snode.key().clone(),
+            value,
+            snode.known_hash(),
+        ))
+    }
+}
+
+impl<K, V, S, const W: usize, S2> Ctrie<K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher + Clone,
+    S2: BuildHasher + Clone,
+{
+    /// Returns a new ctrie with the keys of a read-only snapshot of this one, and the values
+    /// returned by `f` given each key and its value.
+    ///
+    /// The new ctrie has the same shape as the snapshot, so keys aren't rehashed.
+    pub fn map_values<U, F>(&self, f: F, guard: &Guard) -> Ctrie<K, U, S, W, S2>
+    where
+        U: Value,
+        F: Fn(&K, &V) -> U,
+    {
+        self.filter_map(|key, value| Some(f(key, value)), guard)
+    }
+
+    /// Returns a new ctrie with the entries of a read-only snapshot of this one for which `pred`
+    /// returns `true`.
+    ///
+    /// Like `map_values`, keys aren't rehashed.
+    pub fn filter<F>(&self, pred: F, guard: &Guard) -> Self
+    where
+        F: Fn(&K, &V) -> bool,
+    {
+        self.filter_map(|key, value| pred(key, value).then(|| value.clone()), guard)
+    }
+
+    fn filter_map<U, F>(&self, f: F, guard: &Guard) -> Ctrie<K, U, S, W, S2>
+    where
+        U: Value,
+        F: Fn(&K, &V) -> Option<U>,
+    {
+        let snapshot = self.read_only_snapshot(guard);
+        let filter_map = FilterMap {
+            snapshot: &snapshot,
+            f,
+            generation: Generation::new(),
+        };
+        let root = filter_map.root(guard);
+        let branches =
+            (0..root.branches()).map(|position| filter_map.branch(root.branch(position), guard));
+        filter_map.ctrie(root, branches, self.hashers.clone())
+    }
+}
+
+#[cfg(feature = "rayon")]
+mod par {
+    use super::*;
+    use crossbeam::epoch;
+    use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+    impl<K, V, S, const W: usize, S2> Ctrie<K, V, S, W, S2>
+    where
+        K: Key + Send + Sync,
+        V: Value + Send + Sync,
+        S: BuildHasher + Clone + Send + Sync,
+        S2: BuildHasher + Clone + Send + Sync,
+    {
+        /// Like `map_values`, but maps the branches of the root C-node in parallel.
+        pub fn par_map_values<U, F>(&self, f: F, guard: &Guard) -> Ctrie<K, U, S, W, S2>
+        where
+            U: Value + Send + Sync,
+            F: Fn(&K, &V) -> U + Sync,
+        {
+            self.par_filter_map(|key, value| Some(f(key, value)), guard)
+        }
+
+        /// Like `filter`, but filters the branches of the root C-node in parallel.
+        pub fn par_filter<F>(&self, pred: F, guard: &Guard) -> Self
+        where
+            F: Fn(&K, &V) -> bool + Sync,
+        {
+            self.par_filter_map(|key, value| pred(key, value).then(|| value.clone()), guard)
+        }
+
+        fn par_filter_map<U, F>(&self, f: F, guard: &Guard) -> Ctrie<K, U, S, W, S2>
+        where
+            U: Value + Send + Sync,
+            F: Fn(&K, &V) -> Option<U> + Sync,
+        {
+            let snapshot = self.read_only_snapshot(guard);
+            let filter_map = FilterMap {
+                snapshot: &snapshot,
+                f,
+                generation: Generation::new(),
+            };
+            let root = filter_map.root(guard);
+            let branches = (0..root.branches())
+                .into_par_iter()
+                .map(|position| {
+                    // guards can't be shared between threads
+                    let guard = &epoch::pin();
+                    filter_map.branch(root.branch(position), guard)
+                })
+                .collect::<Vec<_>>();
+            filter_map.ctrie(root, branches, self.hashers.clone())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::tests::TinyHasher;
+    use core::hash::BuildHasherDefault;
+    use crossbeam::epoch;
+    use std::{format, string::String};
+
+    #[test]
+    fn map_values() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..1000u32 {
+            ctrie.insert(i, format!("{}", i), guard);
+        }
+
+        let lengths: Ctrie<u32, usize> = ctrie.map_values(|_, value: &String| value.len(), guard);
+        for i in 0..1000u32 {
+            assert_eq!(lengths.lookup(&i, guard), Some(&format!("{}", i).len()));
+        }
+        assert_eq!(lengths.validate(guard), Ok(1000));
+
+        // the projection is independent of the ctrie
+        lengths.insert(1000, 0, guard);
+        assert_eq!(ctrie.lookup(&1000, guard), None);
+    }
+
+    #[test]
+    fn filter() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..1000u32 {
+            ctrie.insert(i, i, guard);
+        }
+        for i in (0..1000).step_by(7) {
+            ctrie.remove(&i, guard);
+        }
+
+        let filtered = ctrie.filter(|&key, _| key % 3 == 0, guard);
+        for i in 0..1000 {
+            let expected = (i % 3 == 0 && i % 7 != 0).then_some(i);
+            assert_eq!(filtered.lookup(&i, guard), expected.as_ref());
+        }
+        assert_eq!(filtered.validate(guard), Ok(286));
+        assert_eq!(ctrie.filter(|_, _| false, guard).validate(guard), Ok(0));
+        assert_eq!(ctrie.validate(guard), Ok(857));
+    }
+
+    #[test]
+    fn filter_collisions() {
+        let ctrie = Ctrie::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        let guard = &epoch::pin();
+        for i in 0..100u32 {
+            ctrie.insert(i, i, guard);
+        }
+        for keep in 0..5 {
+            let filtered = ctrie.filter(|&key, _| key < keep, guard);
+            assert_eq!(filtered.validate(guard), Ok(keep as usize));
+        }
+        let doubled = ctrie.map_values(|_, value| value * 2, guard);
+        assert!(doubled.iter(guard).all(|(k, v)| *v == k * 2));
+        assert_eq!(doubled.validate(guard), Ok(100));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_filter_map() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..5000u32 {
+            ctrie.insert(i, i, guard);
+        }
+
+        let filtered = ctrie.par_filter(|&key, _| key % 2 == 0, guard);
+        assert_eq!(
+            filtered
+                .diff(&ctrie.filter(|&key, _| key % 2 == 0, guard), guard)
+                .count(),
+            0
+        );
+        assert_eq!(filtered.validate(guard), Ok(2500));
+        let mapped = ctrie.par_map_values(|&key, _| u64::from(key) * 3, guard);
+        assert!(mapped.iter(guard).all(|(&k, &v)| v == u64::from(k) * 3));
+        assert_eq!(mapped.validate(guard), Ok(5000));
+    }
+}
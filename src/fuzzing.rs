@@ -0,0 +1,25 @@
+//! Hooks for the fuzz targets in `fuzz/`.
+//!
+//! The interleaving target runs several logical threads on real OS threads, but only lets one of
+//! them make progress at a time. Each thread installs a yield hook which is called right before
+//! every CAS in the GCAS protocol, giving the scheduler a chance to switch to another thread.
+
+use std::cell::RefCell;
+
+thread_local! {
+    static YIELD_HOOK: RefCell<Option<Box<dyn Fn()>>> = RefCell::new(None);
+}
+
+/// Installs (or with `None`, removes) the yield hook for the current thread.
+pub fn set_yield_hook(hook: Option<Box<dyn Fn()>>) {
+    YIELD_HOOK.with(|cell| *cell.borrow_mut() = hook);
+}
+
+/// Calls the current thread's yield hook, if any.
+pub(crate) fn yield_point() {
+    YIELD_HOOK.with(|cell| {
+        if let Some(hook) = &*cell.borrow() {
+            hook();
+        }
+    });
+}
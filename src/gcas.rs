@@ -1,27 +1,32 @@
 use crate::{
     node::{IndirectionNode, MainNode, MainNodeKind},
+    rdcss::rdcss_read_root,
     Ctrie, Key, Value, CAS_ORD, LOAD_ORD, STORE_ORD,
 };
+use core::hash::BuildHasher;
 use crossbeam::epoch::{Atomic, Guard, Owned, Shared};
-use std::hash::BuildHasher;
 
-pub fn gcas<'g, K, V, S>(
+pub fn gcas<K, V, S, const W: usize, S2>(
     inode: &IndirectionNode<K, V>,
     old_ptr: Shared<MainNode<K, V>>,
     new_ptr: Shared<MainNode<K, V>>,
-    ctrie: &Ctrie<K, V, S>,
-    guard: &'g Guard,
+    ctrie: &Ctrie<K, V, S, W, S2>,
+    guard: &Guard,
 ) -> bool
 where
     K: Key,
     V: Value,
     S: BuildHasher,
+    S2: BuildHasher,
 {
     let new = unsafe { new_ptr.deref() };
 
     // store the previous value in case we need to reset
     new.prev().store(old_ptr, STORE_ORD);
 
+    #[cfg(feature = "fuzzing")]
+    crate::fuzzing::yield_point();
+
     if inode
         .main()
         .compare_and_set(old_ptr, new_ptr, CAS_ORD, guard)
@@ -34,15 +39,16 @@ where
     }
 }
 
-pub fn gcas_read<'g, K, V, S>(
+pub fn gcas_read<'g, K, V, S, const W: usize, S2>(
     inode: &IndirectionNode<K, V>,
-    ctrie: &Ctrie<K, V, S>,
+    ctrie: &Ctrie<K, V, S, W, S2>,
     guard: &'g Guard,
 ) -> Shared<'g, MainNode<K, V>>
 where
     K: Key,
     V: Value,
     S: BuildHasher,
+    S2: BuildHasher,
 {
     // load main
     let main_ptr = inode.main().load(LOAD_ORD, guard);
@@ -59,25 +65,25 @@ where
     }
 }
 
-pub fn gcas_commit<'g, K, V, S>(
+pub fn gcas_commit<'g, K, V, S, const W: usize, S2>(
     inode: &IndirectionNode<K, V>,
     main_ptr: Shared<'g, MainNode<K, V>>,
-    ctrie: &Ctrie<K, V, S>,
+    ctrie: &Ctrie<K, V, S, W, S2>,
     guard: &'g Guard,
 ) -> Shared<'g, MainNode<K, V>>
 where
     K: Key,
     V: Value,
     S: BuildHasher,
+    S2: BuildHasher,
 {
     // main pointer of inode is never null
     let main = unsafe { main_ptr.deref() };
 
     let prev_ptr = main.prev().load(LOAD_ORD, guard);
 
-    // TODO: abortable read
-    let root_ptr = ctrie.root().load(LOAD_ORD, guard);
-    let root = unsafe { root_ptr.deref() };
+    let root_ptr = rdcss_read_root(ctrie, true, guard);
+    let root = unsafe { root_ptr.deref() }.inode();
 
     if prev_ptr.is_null() {
         main_ptr
@@ -87,6 +93,9 @@ where
 
         match prev.kind() {
             MainNodeKind::Failed => {
+                #[cfg(feature = "fuzzing")]
+                crate::fuzzing::yield_point();
+
                 let failed_prev_ptr = prev.prev().load(LOAD_ORD, guard);
                 if inode
                     .main()
@@ -100,6 +109,9 @@ where
                 }
             }
             _ => {
+                #[cfg(feature = "fuzzing")]
+                crate::fuzzing::yield_point();
+
                 if root.generation() == inode.generation() && !ctrie.read_only() {
                     if main
                         .prev()
@@ -113,10 +125,11 @@ where
                 } else {
                     let failed = MainNode::failed(Atomic::new(prev.clone()));
                     let failed_ptr = Owned::new(failed).into_shared(guard);
-                    // TODO: ?
-                    main.prev()
-                        .compare_and_set(prev_ptr, failed_ptr, CAS_ORD, guard)
-                        .is_ok();
+                    // if this fails, another thread has already committed or failed the
+                    // proposal, which is then picked up by the retry below
+                    let _ = main
+                        .prev()
+                        .compare_and_set(prev_ptr, failed_ptr, CAS_ORD, guard);
 
                     let new_main_ptr = inode.main().load(LOAD_ORD, guard);
                     gcas_commit(inode, new_main_ptr, ctrie, guard)
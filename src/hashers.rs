@@ -0,0 +1,242 @@
+use core::hash::{BuildHasher, Hash};
+
+/// The hashers of a ctrie.
+///
+/// Keys are hashed to 128 bits, the lowest 64 of which come from the primary hasher. If there is
+/// a secondary hasher, its hash makes up the bits from `split`, the first level at or after 64, so
+/// the trie can keep branching on keys whose primary hashes collide. Otherwise the highest bits
+/// are always zero, and collision lists start at `split`.
+///
+/// With a secondary hasher, lists at `split` or deeper may hold up to `limit` entries, and longer
+/// ones are spread over a C-node instead. Only keys in those subtrees are ever hashed by the
+/// secondary hasher during lookups, inserts and removals.
+#[derive(Clone)]
+pub(crate) struct Hashers<S, S2 = S> {
+    primary: S,
+    secondary: Option<S2>,
+    split: usize,
+    limit: usize,
+}
+
+impl<S: BuildHasher, S2: BuildHasher> Hashers<S, S2> {
+    pub fn new(primary: S) -> Self {
+        Self {
+            primary,
+            secondary: None,
+            split: 64,
+            limit: usize::MAX,
+        }
+    }
+
+    /// Returns the hashers of a trie of width `W` with a secondary hasher and collision lists of
+    /// at most `limit` entries before level 128.
+    pub fn with_secondary<const W: usize>(primary: S, secondary: S2, limit: usize) -> Self {
+        Self {
+            primary,
+            secondary: Some(secondary),
+            split: 64usize.div_ceil(W) * W,
+            limit,
+        }
+    }
+
+    pub fn primary(&self) -> &S {
+        &self.primary
+    }
+
+    /// Returns the primary hash of a key, which is all that nodes store until they are placed where
+    /// the secondary hash is used.
+    pub fn primary_hash<K: Hash>(&self, key: &K) -> u64 {
+        self.primary.hash_one(key)
+    }
+
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub fn hash<K: Hash>(&self, key: &K) -> u128 {
+        self.extend(key, self.primary_hash(key))
+    }
+
+    /// Returns the whole hash of a key given its primary hash, which only calls a hasher if there
+    /// is a secondary one.
+    pub fn extend<K: Hash>(&self, key: &K, hash: u64) -> u128 {
+        match &self.secondary {
+            Some(secondary) => u128::from(hash) | u128::from(secondary.hash_one(key)) << self.split,
+            None => u128::from(hash),
+        }
+    }
+
+    /// Like `extend`, but given as much of the whole hash as is known, so the secondary hasher is
+    /// only called if the bits past `split` are all zero.
+    pub fn complete<K: Hash>(&self, key: &K, hash: u128) -> u128 {
+        if hash >> 64 == 0 {
+            self.extend(key, hash as u64)
+        } else {
+            hash
+        }
+    }
+
+    /// Returns the hash of a key given as much of it as is known, as far as a C-node at `level`
+    /// and its ancestors use it, which only needs the secondary hasher past `split`.
+    pub fn hash_at<K: Hash>(&self, key: &K, hash: u128, level: usize) -> u128 {
+        if level < self.split {
+            hash
+        } else {
+            self.complete(key, hash)
+        }
+    }
+
+    /// Returns whether C-nodes at `level` are the first to branch on the secondary hash, so that
+    /// primary hashes carried down to them have to be extended there.
+    pub fn extends_at(&self, level: usize) -> bool {
+        self.secondary.is_some() && level == self.split
+    }
+
+    /// Returns whether two sets of hashers hash keys the same way, judging by the hashes they give
+    /// a fixed probe value, since hashers can't be compared directly.
+    pub fn same_as(&self, other: &Self) -> bool {
+        const PROBE: u64 = 0x9e37_79b9_7f4a_7c15;
+        let secondaries = match (&self.secondary, &other.secondary) {
+            (Some(a), Some(b)) => a.hash_one(PROBE) == b.hash_one(PROBE),
+            (None, None) => true,
+            _ => false,
+        };
+        secondaries
+            && self.split == other.split
+            && self.limit == other.limit
+            && self.primary.hash_one(PROBE) == other.primary.hash_one(PROBE)
+    }
+
+    /// Returns the number of bits a hash has, which is the level below which C-nodes can be.
+    pub fn bits(&self) -> usize {
+        if self.secondary.is_some() {
+            128
+        } else {
+            64
+        }
+    }
+
+    /// Returns the first level at which a collision list of `len` entries can be.
+    pub fn list_level(&self, len: usize) -> usize {
+        if len <= self.limit {
+            self.split
+        } else {
+            self.bits()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+pub(crate) mod tests {
+    use crate::{
+        gcas::gcas_read,
+        node::{Branch, IndirectionNode, MainNodeKind},
+        tests::TinyHasher,
+        Ctrie, DefaultHashBuilder, Key, Value,
+    };
+    use core::hash::{BuildHasher, BuildHasherDefault};
+    use crossbeam::epoch::{self, Guard};
+    use std::{collections::hash_map::RandomState, vec, vec::Vec};
+
+    /// Builds `TinyHasher`s, whose hashes collide all the time.
+    pub(crate) type Tiny = BuildHasherDefault<TinyHasher>;
+
+    /// Returns the lengths of the collision lists in the subtree of an i-node.
+    pub(crate) fn lists<K: Key, V: Value, S: BuildHasher, S2: BuildHasher>(
+        ctrie: &Ctrie<K, V, S, 6, S2>,
+        inode: &IndirectionNode<K, V>,
+        guard: &Guard,
+    ) -> Vec<usize> {
+        match unsafe { gcas_read(inode, ctrie, guard).deref() }.kind() {
+            MainNodeKind::Ctrie(cnode) => (0..cnode.branches())
+                .flat_map(|position| match cnode.branch(position) {
+                    Branch::Indirection(child) => lists(ctrie, child, guard),
+                    Branch::Singleton(_) => vec![],
+                })
+                .collect(),
+            MainNodeKind::List(lnode) => vec![lnode.length(guard)],
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn secondary_hasher() {
+        let guard = &epoch::pin();
+        let narrow = Ctrie::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        let wide = Ctrie::with_hashers(Tiny::default(), RandomState::new());
+        for i in 0..1000u32 {
+            narrow.insert(i, i, guard);
+            wide.insert(i, i, guard);
+        }
+        assert!(!lists(&narrow, narrow.read_root(guard), guard).is_empty());
+        // the primary hashes collide just as much, but the secondary ones tell the keys apart
+        assert!(lists(&wide, wide.read_root(guard), guard).is_empty());
+        assert_eq!(wide.validate(guard), Ok(1000));
+
+        let snapshot = wide.snapshot(guard);
+        for i in (0..1000).step_by(2) {
+            assert_eq!(wide.remove(&i, guard), Some(&i));
+        }
+        for i in 0..1000 {
+            assert_eq!(wide.lookup(&i, guard), (i % 2 == 1).then_some(&i));
+            assert_eq!(snapshot.lookup(&i, guard), Some(&i));
+        }
+        assert_eq!(wide.validate(guard), Ok(500));
+        assert_eq!(snapshot.diff(&wide, guard).count(), 500);
+    }
+
+    #[test]
+    fn fallback_hasher() {
+        let guard = &epoch::pin();
+        let ctrie = Ctrie::with_fallback_hasher(Tiny::default(), DefaultHashBuilder::default(), 4);
+        // few enough keys with colliding primary hashes stay in lists
+        for i in 0..8u32 {
+            ctrie.insert(i, i, guard);
+        }
+        let short = lists(&ctrie, ctrie.read_root(guard), guard);
+        assert!(!short.is_empty() && short.iter().all(|&len| len <= 4));
+        let snapshot = ctrie.snapshot(guard);
+
+        for i in 8..500u32 {
+            ctrie.insert(i, i, guard);
+        }
+        ctrie.insert_batch((500..1000).map(|i| (i, i)), guard);
+        assert!(lists(&ctrie, ctrie.read_root(guard), guard)
+            .iter()
+            .all(|&len| len <= 4));
+        assert_eq!(ctrie.validate(guard), Ok(1000));
+
+        for i in (0..1000).step_by(3) {
+            assert_eq!(ctrie.remove(&i, guard), Some(&i));
+        }
+        for i in 0..1000 {
+            assert_eq!(ctrie.lookup(&i, guard), (i % 3 != 0).then_some(&i));
+        }
+        assert_eq!(ctrie.validate(guard), Ok(666));
+        assert_eq!(snapshot.validate(guard), Ok(8));
+        assert_eq!(snapshot.diff(&ctrie, guard).count(), 664);
+    }
+
+    #[test]
+    fn keyed_fallback_hasher() {
+        let guard = &epoch::pin();
+        let fx = DefaultHashBuilder::default();
+        // `FxHasher` mixes in each field of a pair by rotating its state and xoring the field, so
+        // a second field chosen from the hash of the first makes every pair hash the same
+        let keys = (0..1000u64)
+            .map(|i| (i, fx.hash_one(i).rotate_left(5)))
+            .collect::<Vec<_>>();
+        assert!(keys
+            .iter()
+            .all(|key| fx.hash_one(key) == fx.hash_one(keys[0])));
+
+        let ctrie = Ctrie::with_fallback_hasher(fx, RandomState::new(), 8);
+        for &key in &keys {
+            ctrie.insert(key, key.0, guard);
+        }
+        assert!(lists(&ctrie, ctrie.read_root(guard), guard)
+            .iter()
+            .all(|&len| len <= 8));
+        for key in &keys {
+            assert_eq!(ctrie.lookup(key, guard), Some(&key.0));
+        }
+        assert_eq!(ctrie.validate(guard), Ok(1000));
+    }
+}
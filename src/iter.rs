@@ -0,0 +1,104 @@
+use crate::{
+    gcas::gcas_read,
+    node::{Branch, CtrieNode, ListEntries, MainNode, MainNodeKind, SingletonNode},
+    Ctrie, Key, Value,
+};
+use alloc::{vec, vec::Vec};
+use core::hash::BuildHasher;
+use crossbeam::epoch::Guard;
+
+/// An iterator over the entries of a ctrie.
+///
+/// Iterates over a read-only snapshot, so it is not affected by concurrent modifications.
+pub struct Iter<'g, K, V, S, const W: usize = 6, S2 = S> {
+    snapshot: Ctrie<K, V, S, W, S2>,
+    // c-nodes on the path to the current entry, with the position of the next branch to visit
+    stack: Vec<(&'g CtrieNode<K, V>, usize)>,
+    // the remaining entries of the l-node being visited, if any
+    list: Option<ListEntries<'g, K, V>>,
+    // the entombed singleton node to visit next, if any
+    tombed: Option<&'g SingletonNode<K, V>>,
+    guard: &'g Guard,
+}
+
+impl<'g, K, V, S, const W: usize, S2> Iter<'g, K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    pub(crate) fn new(snapshot: Ctrie<K, V, S, W, S2>, guard: &'g Guard) -> Self {
+        debug_assert!(snapshot.read_only());
+        let root = snapshot.read_root(guard);
+        let main_ptr = gcas_read(root, &snapshot, guard);
+        let mut iter = Self {
+            snapshot,
+            stack: vec![],
+            list: None,
+            tombed: None,
+            guard,
+        };
+        iter.visit(unsafe { main_ptr.deref() });
+        iter
+    }
+
+    fn visit(&mut self, main: &'g MainNode<K, V>) {
+        match main.kind() {
+            MainNodeKind::Ctrie(cnode) => self.stack.push((cnode, 0)),
+            MainNodeKind::List(lnode) => self.list = Some(lnode.entries(self.guard)),
+            MainNodeKind::Tomb(tnode) => self.tombed = Some(tnode.snode()),
+            MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
+        }
+    }
+
+    /// Returns the singleton nodes of the snapshot rather than their entries, so their cached
+    /// hashes can be used.
+    pub(crate) fn singletons(mut self) -> impl Iterator<Item = &'g SingletonNode<K, V>> {
+        core::iter::from_fn(move || self.next_snode())
+    }
+
+    fn next_snode(&mut self) -> Option<&'g SingletonNode<K, V>> {
+        loop {
+            if let Some(snode) = self.tombed.take() {
+                return Some(snode);
+            }
+            if let Some(list) = &mut self.list {
+                match list.next() {
+                    Some(snode) => return Some(snode),
+                    None => self.list = None,
+                }
+            }
+
+            let (cnode, position) = self.stack.last_mut()?;
+            let cnode: &'g CtrieNode<K, V> = cnode;
+            if *position == cnode.branches() {
+                self.stack.pop();
+                continue;
+            }
+            let branch = cnode.branch(*position);
+            *position += 1;
+            match branch {
+                Branch::Singleton(snode) => return Some(snode),
+                Branch::Indirection(inode) => {
+                    let main_ptr = gcas_read(inode, &self.snapshot, self.guard);
+                    self.visit(unsafe { main_ptr.deref() });
+                }
+            }
+        }
+    }
+}
+
+impl<'g, K, V, S, const W: usize, S2> Iterator for Iter<'g, K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    type Item = (&'g K, &'g V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_snode().map(|snode| (snode.key(), snode.value()))
+    }
+}
@@ -1,29 +1,77 @@
-use crossbeam::epoch::{Atomic, Guard, Owned};
-use fxhash::FxHasher;
-use std::{
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(any(test, feature = "fuzzing"))]
+use alloc::{borrow::ToOwned, format, string::String};
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::{
+    cmp,
     fmt::{self, Debug},
-    hash::{BuildHasher, BuildHasherDefault, Hash, Hasher},
-    sync::{atomic::Ordering, Arc},
+    hash::{BuildHasher, Hash},
+    ptr,
+    sync::atomic::Ordering,
 };
+use crossbeam::epoch::{Atomic, Guard, Owned};
 
+mod bulk;
+#[cfg(feature = "std")]
+mod checksum;
+mod codec;
+mod cursor;
+mod diff;
+mod events;
+mod filter_map;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
 mod gcas;
+mod hashers;
+mod iter;
 mod node;
+#[cfg(feature = "rayon")]
+mod par_iter;
+#[cfg(feature = "std")]
+pub mod persist;
+mod rdcss;
+mod retain;
+#[cfg(feature = "serde")]
+pub mod serde_impl;
+mod set_ops;
+mod shard;
+mod transaction;
 
-use self::{gcas::*, node::*};
+#[cfg(feature = "rayon")]
+pub use self::par_iter::ParIter;
+pub use self::{
+    bulk::CtrieBuilder,
+    codec::Codec,
+    cursor::HashCursor,
+    diff::{Change, Diff},
+    events::Event,
+    iter::Iter,
+    transaction::Transaction,
+};
+use self::{
+    bulk::{build_cnode, extend_collisions, sorted_entries},
+    events::Events,
+    gcas::*,
+    hashers::Hashers,
+    node::*,
+    rdcss::*,
+};
+pub use rustc_hash::FxHasher;
 
 /// The ordering to use when loading atomic pointers.
-const LOAD_ORD: Ordering = Ordering::Relaxed;
+///
+/// Nodes are initialized before they are published, so loads must acquire to see their contents
+/// from other threads.
+const LOAD_ORD: Ordering = Ordering::Acquire;
 
 /// The ordering to use when storing atomic pointers.
-const STORE_ORD: Ordering = Ordering::Relaxed;
+const STORE_ORD: Ordering = Ordering::Release;
 
 /// The ordering to use when compare-and-swapping atomic pointers.
-const CAS_ORD: (Ordering, Ordering) = (Ordering::Relaxed, Ordering::Relaxed);
-
-const W: usize = 6;
-
-/// Used to extract the last W = 6 bits of a hash.
-const LAST_W_BITS: u64 = 0b_111111;
+const CAS_ORD: (Ordering, Ordering) = (Ordering::AcqRel, Ordering::Acquire);
 
 /// A trait to represent a key in a ctrie.
 pub trait Key: Clone + Eq + Hash {}
@@ -52,7 +100,7 @@ impl Generation {
 impl Debug for Generation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // debug representation is based on the pointer, not the value pointed to
-        writeln!(f, "{:?}", Arc::into_raw(self.inner.clone()))
+        writeln!(f, "{:?}", Arc::as_ptr(&self.inner))
     }
 }
 
@@ -66,12 +114,17 @@ impl PartialEq for Generation {
     }
 }
 
-fn flag_and_position(hash: u64, level: usize, bitmap: u64) -> (u64, usize) {
-    // extract W = 6 bits from the hash, skipping the first level bits
-    let index = (hash >> level) & LAST_W_BITS;
+/// Returns the `W` bits of a hash that index a C-node at `level`.
+fn hash_index<const W: usize>(hash: u128, level: usize) -> u64 {
+    ((hash >> level) & ((1 << W) - 1)) as u64
+}
+
+fn flag_and_position<const W: usize>(hash: u128, level: usize, bitmap: u64) -> (u64, usize) {
+    // extract W bits from the hash, skipping the first level bits
+    let index = hash_index::<W>(hash, level);
 
     // flag is the position in the bitmap corresponding to the index
-    // index is guaranteed to be less than 2^W = 64, so this cannot overflow
+    // index is guaranteed to be less than 2^W <= 64, so this cannot overflow
     let flag = 1u64 << index;
 
     // to calculate the array position, count the number of 1's in the bitmap that precede index
@@ -80,6 +133,22 @@ fn flag_and_position(hash: u64, level: usize, bitmap: u64) -> (u64, usize) {
     (flag, position)
 }
 
+/// Returns a key that orders hashes by the path they take through the trie, so that sorting by it
+/// puts keys in the same subtree next to each other.
+///
+/// The `W`-bit chunks of the hash are used from the lowest to the highest as the trie descends,
+/// so this reverses the order of the chunks, keeping the bits within each chunk in order.
+fn path_order<const W: usize>(hash: u128) -> u128 {
+    let mut order = 0;
+    let mut level = 0;
+    while level < 128 {
+        let bits = cmp::min(W, 128 - level);
+        order = (order << bits) | ((hash >> level) & ((1 << bits) - 1));
+        level += W;
+    }
+    order
+}
+
 pub fn entomb<K, V>(snode: SingletonNode<K, V>) -> MainNode<K, V>
 where
     K: Key,
@@ -88,7 +157,7 @@ where
     MainNode::from_tomb_node(TombNode::new(snode))
 }
 
-pub fn resurrect<K, V>(inode: IndirectionNode<K, V>, main: &MainNode<K, V>) -> Branch<K, V>
+pub fn resurrect<K, V>(inode: Arc<IndirectionNode<K, V>>, main: &MainNode<K, V>) -> Branch<K, V>
 where
     K: Key,
     V: Value,
@@ -99,42 +168,92 @@ where
     }
 }
 
-fn to_compressed<'g, K, V>(
-    cnode: &CtrieNode<K, V>,
-    level: u64,
-    generation: Generation,
-    guard: &'g Guard,
-) -> MainNode<K, V>
+/// The hasher of ctries that aren't given one.
+///
+/// This is `FxHasher`, which is fast but easy to find colliding keys for. Ctries whose keys come
+/// from untrusted input should be created with `RandomStateCtrie::with_random_state` instead, or
+/// with `Ctrie::with_fallback_hasher(DefaultHashBuilder::default(), RandomState::new(), limit)`,
+/// which keeps `FxHasher` for keys that don't collide.
+///
+/// There is deliberately no cargo feature that swaps this for `RandomState`. Features are unified
+/// across a build, so enabling one in any crate would change how `Ctrie::new` hashes keys in every
+/// other crate too, breaking code that relies on two ctries created that way hashing the same.
+pub type DefaultHashBuilder = core::hash::BuildHasherDefault<FxHasher>;
+
+/// A ctrie that hashes keys with the randomly seeded `RandomState` of the standard library, which
+/// resists hash flooding.
+#[cfg(feature = "std")]
+pub type RandomStateCtrie<K, V> = Ctrie<K, V, std::collections::hash_map::RandomState>;
+
+/// A concurrent hash trie.
+///
+/// Every C-node branches on the next `W` bits of the hash, so it has up to `2^W` branches. `W` can
+/// be 4, 5 or 6. Narrower nodes make the trie deeper, but make the C-node copied by every write
+/// smaller, which saves memory and allocation when writes are frequent.
+///
+/// `S2` is the type of the secondary hasher of a ctrie created with `with_hashers` or
+/// `with_fallback_hasher`, which can differ from the type `S` of the primary one.
+pub struct Ctrie<K, V, S = DefaultHashBuilder, const W: usize = 6, S2 = S> {
+    root: Atomic<RootNode<K, V>>,
+    read_only: bool,
+    hashers: Hashers<S, S2>,
+    events: Events<K, V>,
+}
+
+// A ctrie hands out references to its keys and values to any thread holding a guard, and nodes
+// are shared between threads and between snapshots. Sending a ctrie to another thread therefore
+// shares its contents with the sending thread too, so both impls need `Sync` keys and values.
+unsafe impl<K, V, S, const W: usize, S2> Send for Ctrie<K, V, S, W, S2>
+where
+    K: Send + Sync,
+    V: Send + Sync,
+    S: Send + Sync,
+    S2: Send + Sync,
+{
+}
+
+unsafe impl<K, V, S, const W: usize, S2> Sync for Ctrie<K, V, S, W, S2>
+where
+    K: Send + Sync,
+    V: Send + Sync,
+    S: Send + Sync,
+    S2: Send + Sync,
+{
+}
+
+impl<K, V> Ctrie<K, V>
 where
     K: Key,
     V: Value,
 {
-    let mut new_array = Vec::with_capacity(cnode.branches());
-    for i in 0..cnode.branches() {
-        match cnode.branch(i) {
-            Branch::Singleton(snode) => {
-                new_array.push(Branch::Singleton(snode.clone()));
-            }
-            Branch::Indirection(inode) => unimplemented!(),
-        }
+    pub fn new() -> Self {
+        Self::with_hasher(DefaultHashBuilder::default())
     }
-    let new_cnode = CtrieNode::new(cnode.bitmap(), new_array, generation);
-    new_cnode.to_contracted(level)
 }
 
-pub struct Ctrie<K, V, S = BuildHasherDefault<FxHasher>> {
-    root: Atomic<IndirectionNode<K, V>>,
-    read_only: bool,
-    hash_builder: S,
+#[cfg(feature = "std")]
+impl<K, V> RandomStateCtrie<K, V>
+where
+    K: Key,
+    V: Value,
+{
+    /// Creates an empty ctrie that hashes keys with a new, randomly seeded `RandomState`.
+    ///
+    /// Ctries created this way hash keys differently from each other, so one meant to be diffed
+    /// or combined with this one should be created with `Ctrie::with_hasher` and a clone of
+    /// `hasher()` instead.
+    pub fn with_random_state() -> Self {
+        Self::with_hasher(std::collections::hash_map::RandomState::new())
+    }
 }
 
-impl<K, V> Ctrie<K, V>
+impl<K, V> Default for Ctrie<K, V>
 where
     K: Key,
     V: Value,
 {
-    pub fn new() -> Self {
-        Self::with_hasher(BuildHasherDefault::<FxHasher>::default())
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -145,60 +264,351 @@ where
     S: BuildHasher,
 {
     pub fn with_hasher(hash_builder: S) -> Self {
+        Self::with_width(hash_builder)
+    }
+}
+
+impl<K, V, S, S2> Ctrie<K, V, S, 6, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    /// Creates an empty ctrie that hashes keys to 128 bits with two independent hashers.
+    ///
+    /// Keys are only kept in collision lists, which are searched linearly, if their hashes collide
+    /// under both hashers rather than just one, which makes flooding the ctrie with colliding keys
+    /// much harder. The hashers must hash keys independently of each other, since keys whose
+    /// primary hashes collide would otherwise collide under the secondary hasher too. A fast
+    /// primary hasher like `FxHasher` can be paired with a keyed secondary one like `RandomState`.
+    pub fn with_hashers(primary: S, secondary: S2) -> Self {
+        Self::with_width_and_hashers(primary, secondary)
+    }
+
+    /// Creates an empty ctrie that falls back to a secondary hasher for keys whose primary hashes
+    /// collide, once there are more than `limit` of them.
+    ///
+    /// Keys whose primary hashes collide are kept in a collision list until the list would grow
+    /// past `limit` entries, at which point the list is spread over a C-node by the hashes of the
+    /// secondary hasher. Lookups, inserts and removals only hash the keys in such subtrees with
+    /// the secondary hasher, so the primary hasher can be a fast one like `FxHasher`, while a
+    /// keyed secondary hasher like `RandomState` keeps keys chosen to collide from degrading
+    /// lookups to linear scans.
+    ///
+    /// ```
+    /// # use ctrie::{Ctrie, DefaultHashBuilder};
+    /// # use std::collections::hash_map::RandomState;
+    /// let ctrie: Ctrie<String, u32, _, 6, _> =
+    ///     Ctrie::with_fallback_hasher(DefaultHashBuilder::default(), RandomState::new(), 8);
+    /// ```
+    pub fn with_fallback_hasher(primary: S, secondary: S2, limit: usize) -> Self {
+        Self::with_width_and_fallback_hasher(primary, secondary, limit)
+    }
+}
+
+impl<K, V, S, const W: usize> Ctrie<K, V, S, W>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+{
+    /// Creates an empty ctrie with the given hasher and the width `W` of its type, unlike
+    /// `with_hasher`, which always uses the default width.
+    ///
+    /// ```
+    /// # use ctrie::{Ctrie, FxHasher};
+    /// # use std::hash::BuildHasherDefault;
+    /// let hasher = BuildHasherDefault::<FxHasher>::default();
+    /// let ctrie: Ctrie<u32, u32, _, 4> = Ctrie::with_width(hasher);
+    /// ```
+    ///
+    /// Other widths fail to build:
+    ///
+    /// ```compile_fail
+    /// # use ctrie::{Ctrie, FxHasher};
+    /// # use std::hash::BuildHasherDefault;
+    /// let hasher = BuildHasherDefault::<FxHasher>::default();
+    /// let ctrie: Ctrie<u32, u32, _, 7> = Ctrie::with_width(hasher);
+    /// ```
+    pub fn with_width(hash_builder: S) -> Self {
+        Self::empty(Hashers::new(hash_builder))
+    }
+}
+
+impl<K, V, S, const W: usize, S2> Ctrie<K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    // checked when a ctrie is created, so that other widths fail to build
+    const VALID_WIDTH: () = assert!(W >= 4 && W <= 6, "the width of a ctrie must be 4, 5 or 6");
+
+    /// Like `with_hashers`, but with the width `W` of its type, like `with_width`.
+    pub fn with_width_and_hashers(primary: S, secondary: S2) -> Self {
+        Self::empty(Hashers::with_secondary::<W>(primary, secondary, 0))
+    }
+
+    /// Like `with_fallback_hasher`, but with the width `W` of its type, like `with_width`.
+    pub fn with_width_and_fallback_hasher(primary: S, secondary: S2, limit: usize) -> Self {
+        Self::empty(Hashers::with_secondary::<W>(primary, secondary, limit))
+    }
+
+    fn empty(hashers: Hashers<S, S2>) -> Self {
         let generation = Generation::new();
-        Self {
-            root: Atomic::new(IndirectionNode::new(
+        Self::from_root(
+            IndirectionNode::new(
                 Atomic::new(MainNode::from_ctrie_node(CtrieNode::new(
                     0,
                     vec![],
                     generation.clone(),
                 ))),
                 generation,
-            )),
-            read_only: false,
-            hash_builder,
+            ),
+            false,
+            hashers,
+        )
+    }
+
+    fn from_root(root: IndirectionNode<K, V>, read_only: bool, hashers: Hashers<S, S2>) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::VALID_WIDTH;
+        Self {
+            root: Atomic::new(RootNode::Indirection(root)),
+            read_only,
+            hashers,
+            events: Events::new(),
         }
     }
 
-    fn hash(&self, key: &K) -> u64 {
-        let mut hasher = self.hash_builder.build_hasher();
-        key.hash(&mut hasher);
-        hasher.finish()
+    /// Returns the hasher of the ctrie, or its primary hasher if it has two.
+    ///
+    /// Ctries that are diffed or combined with each other must hash keys the same way, so a ctrie
+    /// meant to be combined with this one should be created with a clone of its hasher.
+    pub fn hasher(&self) -> &S {
+        self.hashers.primary()
+    }
+
+    #[cfg(any(test, feature = "fuzzing"))]
+    fn hash(&self, key: &K) -> u128 {
+        self.hashers.hash(key)
+    }
+
+    /// Returns the whole hash of the key of a singleton node from the hash it stores, which only
+    /// calls the secondary hasher if the node doesn't know it yet.
+    fn snode_hash(&self, snode: &SingletonNode<K, V>) -> u128 {
+        self.hashers.complete(snode.key(), snode.known_hash())
     }
 
-    fn root(&self) -> &Atomic<IndirectionNode<K, V>> {
+    fn root(&self) -> &Atomic<RootNode<K, V>> {
         &self.root
     }
 
+    /// Returns the root i-node, completing any snapshot in progress.
+    fn read_root<'g>(&self, guard: &'g Guard) -> &'g IndirectionNode<K, V> {
+        let root_ptr = rdcss_read_root(self, false, guard);
+        unsafe { root_ptr.deref() }.inode()
+    }
+
     fn read_only(&self) -> bool {
         self.read_only
     }
 
-    pub fn insert<'g>(&self, key: K, value: V, guard: &'g Guard) {
-        let root_ptr = self.root().load(LOAD_ORD, guard);
-        let root = unsafe { root_ptr.deref() };
-        match self.iinsert(
-            root,
-            key.clone(),
-            value.clone(),
-            0,
-            root.generation(),
+    /// Returns a writable snapshot of the ctrie in constant time.
+    ///
+    /// The snapshot and the original ctrie share their nodes, and lazily copy them when either one
+    /// is modified.
+    pub fn snapshot(&self, guard: &Guard) -> Self
+    where
+        S: Clone,
+        S2: Clone,
+    {
+        loop {
+            let root_ptr = rdcss_read_root(self, false, guard);
+            let root = unsafe { root_ptr.deref() }.inode();
+            let main_ptr = gcas_read(root, self, guard);
+            let new_root = IndirectionNode::new(Atomic::from(main_ptr), Generation::new());
+            if rdcss(
+                self,
+                root_ptr,
+                main_ptr,
+                RootNode::Indirection(new_root),
+                guard,
+            ) {
+                return Self::from_root(
+                    IndirectionNode::new(Atomic::from(main_ptr), Generation::new()),
+                    false,
+                    self.hashers.clone(),
+                );
+            }
+        }
+    }
+
+    /// Returns a read-only snapshot of the ctrie in constant time.
+    ///
+    /// Unlike a writable snapshot, reading from a read-only snapshot never copies any nodes.
+    pub fn read_only_snapshot(&self, guard: &Guard) -> Self
+    where
+        S: Clone,
+        S2: Clone,
+    {
+        loop {
+            let root_ptr = rdcss_read_root(self, false, guard);
+            let root = unsafe { root_ptr.deref() }.inode();
+            let main_ptr = gcas_read(root, self, guard);
+            if self.read_only {
+                return Self::from_root(root.clone(), true, self.hashers.clone());
+            }
+            let new_root = IndirectionNode::new(Atomic::from(main_ptr), Generation::new());
+            if rdcss(
+                self,
+                root_ptr,
+                main_ptr,
+                RootNode::Indirection(new_root),
+                guard,
+            ) {
+                // nothing can modify the old generation anymore, so the snapshot can keep using it
+                return Self::from_root(
+                    IndirectionNode::new(Atomic::from(main_ptr), root.generation().clone()),
+                    true,
+                    self.hashers.clone(),
+                );
+            }
+        }
+    }
+
+    /// Returns an iterator over the entries of a read-only snapshot of the ctrie.
+    pub fn iter<'g>(&self, guard: &'g Guard) -> Iter<'g, K, V, S, W, S2>
+    where
+        S: Clone,
+        S2: Clone,
+    {
+        Iter::new(self.read_only_snapshot(guard), guard)
+    }
+
+    /// Returns a parallel iterator over the entries of a read-only snapshot of the ctrie.
+    #[cfg(feature = "rayon")]
+    pub fn par_iter<'g>(&self, guard: &'g Guard) -> ParIter<'g, K, V, S, W, S2>
+    where
+        S: Clone,
+        S2: Clone,
+    {
+        ParIter::new(self.read_only_snapshot(guard), guard)
+    }
+
+    /// Returns an iterator over the changes that turn a read-only snapshot of this ctrie into a
+    /// read-only snapshot of `other`.
+    ///
+    /// Both ctries must use the same hasher. Subtrees shared by the two ctries are skipped, so
+    /// diffing two snapshots of the same ctrie only visits the parts that changed between them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the two ctries hash keys differently.
+    pub fn diff<'g>(&self, other: &Self, guard: &'g Guard) -> Diff<'g, K, V, S, W, S2>
+    where
+        V: PartialEq,
+        S: Clone,
+        S2: Clone,
+    {
+        assert!(
+            self.hashers.same_as(&other.hashers),
+            "can't diff ctries that hash keys differently"
+        );
+        Diff::new(
+            self.read_only_snapshot(guard),
+            other.read_only_snapshot(guard),
             guard,
-        ) {
-            IInsertResult::Ok => {}
-            IInsertResult::Restart => self.insert(key, value, guard),
+        )
+    }
+
+    /// Applies a sequence of changes, such as those returned by `diff`, in order.
+    ///
+    /// Applying the changes from one snapshot to another to a ctrie equal to the first snapshot
+    /// makes it equal to the second. The changes are applied one at a time, so concurrent readers
+    /// can observe some of them before others.
+    pub fn apply<I>(&self, changes: I, guard: &Guard)
+    where
+        I: IntoIterator<Item = Change<K, V>>,
+    {
+        for change in changes {
+            match change {
+                Change::Added { key, value }
+                | Change::Updated {
+                    key, new: value, ..
+                } => self.insert(key, value, guard),
+                Change::Removed { key, .. } => {
+                    self.remove(&key, guard);
+                }
+            }
+        }
+    }
+
+    pub fn insert(&self, key: K, value: V, guard: &Guard) {
+        self.insert_with_hash(self.hashers.primary_hash(&key), key, value, guard);
+    }
+
+    /// Like `insert`, but with the hash of the key already known, so the key isn't hashed again.
+    ///
+    /// `hash` must be the hash the ctrie's hasher gives the key, `self.hasher().hash_one(&key)`.
+    /// Otherwise the entry may never be found again.
+    pub fn insert_with_hash(&self, hash: u64, key: K, value: V, guard: &Guard) {
+        let mut publisher = self.events.publisher();
+        if publisher.active() {
+            let change = match self.insert_entry(hash, key.clone(), value.clone(), guard) {
+                Some(old) => Change::Updated {
+                    key,
+                    old: old.clone(),
+                    new: value,
+                },
+                None => Change::Added { key, value },
+            };
+            publisher.publish(Event::Change(change));
+        } else {
+            self.insert_entry(hash, key, value, guard);
+        }
+    }
+
+    /// Inserts a key-value pair with the given hash, returning the value it replaced.
+    fn insert_entry<'g>(&self, hash: u64, key: K, value: V, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: 'g,
+    {
+        loop {
+            let root = self.read_root(guard);
+            match self.iinsert(
+                root,
+                key.clone(),
+                value.clone(),
+                u128::from(hash),
+                0,
+                None,
+                root.generation(),
+                guard,
+            ) {
+                IInsertResult::Ok(previous) => return previous,
+                IInsertResult::Restart => {}
+            }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn iinsert<'g>(
         &self,
         inode: &IndirectionNode<K, V>,
         key: K,
         value: V,
+        hash: u128,
         level: usize,
+        parent: Option<&IndirectionNode<K, V>>,
         start_generation: &Generation,
         guard: &'g Guard,
-    ) -> IInsertResult {
+    ) -> IInsertResult<'g, V>
+    where
+        K: 'g,
+    {
         // read the main pointer of the i-node
         let main_ptr = gcas_read(inode, self, guard);
         let main = unsafe { main_ptr.deref() };
@@ -206,8 +616,8 @@ where
         match main.kind() {
             MainNodeKind::Ctrie(cnode) => {
                 let bitmap = cnode.bitmap();
-                let key_hash = self.hash(&key);
-                let (flag, position) = flag_and_position(key_hash, level, bitmap);
+                let key_hash = self.hashers.hash_at(&key, hash, level);
+                let (flag, position) = flag_and_position::<W>(key_hash, level, bitmap);
                 if flag & bitmap == 0 {
                     let renewed_cnode = if cnode.generation() != inode.generation() {
                         cnode.renewed(inode.generation().clone(), self, guard)
@@ -218,89 +628,298 @@ where
                         Owned::new(MainNode::from_ctrie_node(renewed_cnode.inserted(
                             flag,
                             position,
-                            Branch::Singleton(SingletonNode::new(key, value)),
+                            Branch::Singleton(SingletonNode::new(key, value, key_hash)),
                             inode.generation().clone(),
                         )))
                         .into_shared(guard);
                     if gcas(inode, main_ptr, new_main_ptr, self, guard) {
-                        IInsertResult::Ok
+                        IInsertResult::Ok(None)
                     } else {
                         IInsertResult::Restart
                     }
                 } else {
                     match cnode.branch(position) {
-                        Branch::Indirection(inode) => {
-                            if start_generation == inode.generation() {
-                                self.iinsert(inode, key, value, level + W, start_generation, guard)
+                        Branch::Indirection(child) => {
+                            if start_generation == child.generation() {
+                                self.iinsert(
+                                    child,
+                                    key,
+                                    value,
+                                    key_hash,
+                                    level + W,
+                                    Some(inode),
+                                    start_generation,
+                                    guard,
+                                )
                             } else {
+                                // the child belongs to an older generation, so renew the c-node
+                                // before descending into it
                                 let renewed_cnode =
                                     cnode.renewed(start_generation.clone(), self, guard);
                                 let new_main_ptr =
                                     Owned::new(MainNode::from_ctrie_node(renewed_cnode))
                                         .into_shared(guard);
                                 if gcas(inode, main_ptr, new_main_ptr, self, guard) {
-                                    self.iinsert(inode, key, value, level, start_generation, guard)
+                                    self.iinsert(
+                                        inode,
+                                        key,
+                                        value,
+                                        key_hash,
+                                        level,
+                                        parent,
+                                        start_generation,
+                                        guard,
+                                    )
                                 } else {
                                     IInsertResult::Restart
                                 }
                             }
                         }
                         Branch::Singleton(snode) => {
-                            if snode.key() != &key {
-                                let renewed_cnode = if cnode.generation() != inode.generation() {
-                                    cnode.renewed(inode.generation().clone(), self, guard)
+                            let renewed_cnode = if cnode.generation() != inode.generation() {
+                                cnode.renewed(inode.generation().clone(), self, guard)
+                            } else {
+                                cnode.clone()
+                            };
+                            let (new_branch, previous) = if snode.key() != &key {
+                                // the slot is taken by a different key, so push both keys one
+                                // level down, which only needs the rest of both hashes if their
+                                // primary hashes collide
+                                let (old_hash, new_hash) = if snode.hash() == key_hash as u64 {
+                                    (
+                                        self.snode_hash(snode),
+                                        self.hashers.complete(&key, key_hash),
+                                    )
                                 } else {
-                                    cnode.clone()
+                                    (snode.known_hash(), key_hash)
                                 };
-                                let new_snode = SingletonNode::new(key, value);
-                                let new_main = MainNode::new(
-                                    snode.clone(),
-                                    self.hash(snode.key()),
+                                let new_snode = SingletonNode::new(key, value, new_hash);
+                                let new_main = MainNode::new::<W>(
+                                    snode.with_hash(old_hash),
+                                    old_hash,
                                     new_snode,
-                                    key_hash,
+                                    new_hash,
                                     level + W,
+                                    self.hashers.list_level(2),
                                     inode.generation().clone(),
                                 );
                                 let new_inode = IndirectionNode::new(
                                     Atomic::new(new_main),
                                     inode.generation().clone(),
                                 );
-                                let new_main_ptr =
-                                    Owned::new(MainNode::from_ctrie_node(renewed_cnode.updated(
-                                        position,
-                                        Branch::Indirection(new_inode),
-                                        inode.generation().clone(),
-                                    )))
-                                    .into_shared(guard);
-                                if gcas(inode, main_ptr, new_main_ptr, self, guard) {
-                                    IInsertResult::Ok
-                                } else {
-                                    IInsertResult::Restart
-                                }
+                                (Branch::Indirection(Arc::new(new_inode)), None)
                             } else {
-                                let new_main_ptr =
-                                    Owned::new(MainNode::from_ctrie_node(cnode.updated(
-                                        position,
-                                        Branch::Singleton(SingletonNode::new(key, value)),
-                                        inode.generation().clone(),
-                                    )))
-                                    .into_shared(guard);
-                                if gcas(inode, main_ptr, new_main_ptr, self, guard) {
-                                    IInsertResult::Ok
-                                } else {
-                                    IInsertResult::Restart
-                                }
+                                (
+                                    Branch::Singleton(SingletonNode::new(key, value, key_hash)),
+                                    Some(snode.value()),
+                                )
+                            };
+                            let new_main_ptr =
+                                Owned::new(MainNode::from_ctrie_node(renewed_cnode.updated(
+                                    position,
+                                    new_branch,
+                                    inode.generation().clone(),
+                                )))
+                                .into_shared(guard);
+                            if gcas(inode, main_ptr, new_main_ptr, self, guard) {
+                                IInsertResult::Ok(previous)
+                            } else {
+                                IInsertResult::Restart
                             }
                         }
                     }
                 }
             }
 
-            MainNodeKind::List(lnode) => unimplemented!(),
+            MainNodeKind::List(lnode) => {
+                let previous = lnode.lookup(&key, guard);
+                let new_main = self.collision_node(
+                    self.list_inserted(lnode, self.list_entry(key, value, hash), guard),
+                    level,
+                    inode.generation(),
+                    guard,
+                );
+                let new_main_ptr = Owned::new(new_main).into_shared(guard);
+                if gcas(inode, main_ptr, new_main_ptr, self, guard) {
+                    IInsertResult::Ok(previous)
+                } else {
+                    IInsertResult::Restart
+                }
+            }
+
+            MainNodeKind::Tomb(_) => {
+                // the i-node is about to be removed from its parent, so help clean it up and
+                // try again
+                if let Some(parent) = parent {
+                    self.clean(parent, level - W, guard);
+                }
+                IInsertResult::Restart
+            }
+
+            MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
+        }
+    }
+
+    /// Returns the singleton node of an entry about to be added to a collision list, which knows
+    /// the whole hash of its key, since lists with a secondary hasher are ordered by it.
+    fn list_entry(&self, key: K, value: V, hash: u128) -> SingletonNode<K, V> {
+        let hash = self.hashers.complete(&key, hash);
+        SingletonNode::new(key, value, hash)
+    }
+
+    /// Returns a collision list with a singleton node inserted, replacing the entry with the same
+    /// key if there is one.
+    ///
+    /// With a secondary hasher, lists are kept sorted by the path order of the whole hashes of
+    /// their keys, so a cursor into a list still points at the same entry once the list is spread
+    /// over a C-node. Otherwise the keys of a list all have the same hash.
+    fn list_inserted(
+        &self,
+        lnode: &ListNode<K, V>,
+        snode: SingletonNode<K, V>,
+        guard: &Guard,
+    ) -> ListNode<K, V> {
+        if self.hashers.bits() == 64 {
+            return lnode.inserted(snode, guard);
+        }
+        let order = |snode: &SingletonNode<K, V>| path_order::<W>(self.snode_hash(snode));
+        let mut entries = lnode
+            .entries(guard)
+            .filter(|entry| entry.key() != snode.key())
+            .cloned()
+            .collect::<Vec<_>>();
+        let new = order(&snode);
+        let at = entries.partition_point(|entry| order(entry) < new);
+        entries.insert(at, snode);
+        ListNode::from_entries(entries)
+    }
+
+    /// Returns the main node for a collision list at `level`, which is the list itself unless it
+    /// has grown too long to stay one, in which case its entries are spread over a C-node.
+    fn collision_node(
+        &self,
+        lnode: ListNode<K, V>,
+        level: usize,
+        generation: &Generation,
+        guard: &Guard,
+    ) -> MainNode<K, V> {
+        if level >= self.hashers.list_level(lnode.length(guard)) {
+            return MainNode::from_list_node(lnode);
+        }
+        let (hashes, pairs) = sorted_entries::<W, _, _>(
+            lnode
+                .entries(guard)
+                .map(|snode| {
+                    (
+                        self.snode_hash(snode),
+                        snode.key().clone(),
+                        snode.value().clone(),
+                    )
+                })
+                .collect(),
+        );
+        MainNode::from_ctrie_node(build_cnode::<W, _, _, _, _, _>(
+            &hashes,
+            &mut pairs.into_iter(),
+            level,
+            &self.hashers,
+            generation,
+        ))
+    }
+
+    /// Looks up the values of several keys at once, all as of the same moment.
+    ///
+    /// The keys are read from a single read-only snapshot, in the order they are laid out in the
+    /// trie, so every node on the way to several of the keys is only visited once.
+    pub fn get_many<'g>(&self, keys: &[K], guard: &'g Guard) -> Vec<Option<&'g V>>
+    where
+        K: 'g,
+        S: Clone,
+        S2: Clone,
+    {
+        let snapshot = self.read_only_snapshot(guard);
+        let mut probes = keys
+            .iter()
+            .enumerate()
+            .map(|(index, key)| (u128::from(snapshot.hashers.primary_hash(key)), key, index))
+            .collect::<Vec<_>>();
+        probes.sort_by_key(|&(hash, _, _)| path_order::<W>(hash));
+        extend_collisions::<W, _, _, _, _>(&snapshot.hashers, &mut probes);
+
+        let mut values = vec![None; keys.len()];
+        let root = snapshot.read_root(guard);
+        let main_ptr = gcas_read(root, &snapshot, guard);
+        snapshot.lookup_sorted(unsafe { main_ptr.deref() }, &probes, 0, &mut values, guard);
+        values
+    }
 
-            MainNodeKind::Tomb(tnode) => unimplemented!(),
+    /// Looks up the keys of a group of probes in the subtree of a main node of a read-only
+    /// snapshot, where the probes are sorted by `path_order`, and only the hashes of probes that
+    /// share their primary hash with another are extended.
+    fn lookup_sorted<'g>(
+        &self,
+        main: &'g MainNode<K, V>,
+        probes: &[(u128, &K, usize)],
+        level: usize,
+        values: &mut [Option<&'g V>],
+        guard: &'g Guard,
+    ) where
+        K: 'g,
+    {
+        match main.kind() {
+            MainNodeKind::Ctrie(cnode) => {
+                let extended;
+                let mut probes = probes;
+                if let [(hash, key, index)] = *probes {
+                    if self.hashers.extends_at(level) {
+                        extended = [(self.hashers.extend(key, hash as u64), key, index)];
+                        probes = &extended;
+                    }
+                }
+                while let Some(&(hash, _, _)) = probes.first() {
+                    // the probes that take the same branch are next to each other
+                    let index = hash_index::<W>(hash, level);
+                    let len = probes
+                        .iter()
+                        .take_while(|&&(hash, _, _)| hash_index::<W>(hash, level) == index)
+                        .count();
+                    let (group, rest) = probes.split_at(len);
+                    probes = rest;
 
-            MainNodeKind::Failed => unimplemented!(),
+                    let (flag, position) = flag_and_position::<W>(hash, level, cnode.bitmap());
+                    if flag & cnode.bitmap() == 0 {
+                        continue;
+                    }
+                    match cnode.branch(position) {
+                        Branch::Indirection(child) => {
+                            let main_ptr = gcas_read(child, self, guard);
+                            let main = unsafe { main_ptr.deref() };
+                            self.lookup_sorted(main, group, level + W, values, guard);
+                        }
+                        Branch::Singleton(snode) => {
+                            for &(_, key, index) in group {
+                                if snode.key() == key {
+                                    values[index] = Some(snode.value());
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            MainNodeKind::List(lnode) => {
+                for &(_, key, index) in probes {
+                    values[index] = lnode.lookup(key, guard);
+                }
+            }
+            MainNodeKind::Tomb(tnode) => {
+                let snode = tnode.snode();
+                for &(_, key, index) in probes {
+                    if snode.key() == key {
+                        values[index] = Some(snode.value());
+                    }
+                }
+            }
+            MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
         }
     }
 
@@ -308,20 +927,36 @@ where
     where
         K: 'g,
     {
-        let root_ptr = self.root.load(LOAD_ORD, guard);
-        let root = unsafe { root_ptr.deref() };
-        match self.ilookup(root, key, 0, root.generation(), guard) {
-            ILookupResult::Value(v) => Some(v),
-            ILookupResult::NotFound => None,
-            ILookupResult::Restart => self.lookup(key, guard),
+        self.get_with_hash(self.hashers.primary_hash(key), key, guard)
+    }
+
+    /// Like `lookup`, but with the hash of the key already known, so the key isn't hashed again.
+    ///
+    /// `hash` must be the hash the ctrie's hasher gives the key, `self.hasher().hash_one(key)`.
+    /// Otherwise the key may not be found.
+    pub fn get_with_hash<'g>(&self, hash: u64, key: &K, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: 'g,
+    {
+        let hash = u128::from(hash);
+        loop {
+            let root = self.read_root(guard);
+            match self.ilookup(root, key, hash, 0, None, root.generation(), guard) {
+                ILookupResult::Value(v) => return Some(v),
+                ILookupResult::NotFound => return None,
+                ILookupResult::Restart => {}
+            }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn ilookup<'g>(
         &self,
         inode: &IndirectionNode<K, V>,
         key: &K,
+        hash: u128,
         level: usize,
+        parent: Option<&IndirectionNode<K, V>>,
         start_generation: &Generation,
         guard: &'g Guard,
     ) -> ILookupResult<'g, V>
@@ -337,8 +972,8 @@ where
                 // if the main node is a c-node, calculate the flag and array position
                 // corresponding to the key
                 let bitmap = cnode.bitmap();
-                let key_hash = self.hash(&key);
-                let (flag, position) = flag_and_position(key_hash, level, bitmap);
+                let key_hash = self.hashers.hash_at(key, hash, level);
+                let (flag, position) = flag_and_position::<W>(key_hash, level, bitmap);
 
                 if flag & bitmap == 0 {
                     // if the bitmap doesn't contain the relevant bit, the key is not present in
@@ -347,16 +982,32 @@ where
                 } else {
                     // otherwise, check the branch at the relevant position in the branch array
                     match cnode.branch(position) {
-                        Branch::Indirection(new_inode) => {
-                            if self.read_only || start_generation == new_inode.generation() {
-                                self.ilookup(new_inode, key, level + W, start_generation, guard)
+                        Branch::Indirection(child) => {
+                            if self.read_only || start_generation == child.generation() {
+                                self.ilookup(
+                                    child,
+                                    key,
+                                    key_hash,
+                                    level + W,
+                                    Some(inode),
+                                    start_generation,
+                                    guard,
+                                )
                             } else {
                                 let new_main_ptr = Owned::new(MainNode::from_ctrie_node(
                                     cnode.renewed(start_generation.clone(), self, guard),
                                 ))
                                 .into_shared(guard);
                                 if gcas(inode, main_ptr, new_main_ptr, self, guard) {
-                                    self.ilookup(inode, key, level, start_generation, guard)
+                                    self.ilookup(
+                                        inode,
+                                        key,
+                                        key_hash,
+                                        level,
+                                        parent,
+                                        start_generation,
+                                        guard,
+                                    )
                                 } else {
                                     ILookupResult::Restart
                                 }
@@ -383,54 +1034,1224 @@ where
                 }
             }
 
-            MainNodeKind::Tomb(tnode) => unimplemented!(),
+            MainNodeKind::Tomb(tnode) => {
+                // read-only snapshots can't be cleaned, but the entombed node is still valid
+                if self.read_only {
+                    if tnode.snode().key() == key {
+                        ILookupResult::Value(tnode.snode().value())
+                    } else {
+                        ILookupResult::NotFound
+                    }
+                } else {
+                    if let Some(parent) = parent {
+                        self.clean(parent, level - W, guard);
+                    }
+                    ILookupResult::Restart
+                }
+            }
 
-            MainNodeKind::Failed => unimplemented!(),
+            MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
         }
     }
 
-    fn print<'g>(&self, guard: &'g Guard)
+    pub fn remove<'g>(&self, key: &K, guard: &'g Guard) -> Option<&'g V>
     where
-        K: Debug,
-        V: Debug,
+        K: 'g,
     {
-        println!("ctrie:");
-        let root_ptr = self.root.load(LOAD_ORD, guard);
-        let root = unsafe { root_ptr.deref() };
-        root.print(0, guard);
+        let mut publisher = self.events.publisher();
+        let removed = self.remove_entry(self.hashers.primary_hash(key), key, guard);
+        if let (true, Some(value)) = (publisher.active(), removed) {
+            publisher.publish(Event::Change(Change::Removed {
+                key: key.clone(),
+                value: value.clone(),
+            }));
+        }
+        removed
     }
-}
-
-enum IInsertResult {
-    Ok,
-    Restart,
-}
-
-enum ILookupResult<'g, V> {
-    Value(&'g V),
-    NotFound,
-    Restart,
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crossbeam::epoch;
 
-    #[test]
-    fn insert_lookup() {
-        let ctrie = Ctrie::new();
-        let guard = &epoch::pin();
+    /// Removes a key with the given hash, returning the value it had.
+    fn remove_entry<'g>(&self, hash: u64, key: &K, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: 'g,
+    {
+        self.remove_entry_if(hash, key, |_| true, guard)
+    }
 
-        for i in (0..1000).map(|i| i * 2) {
-            ctrie.insert(i, i * 3, guard);
+    /// Removes a key with the given hash if `matches` returns `true` for its value, returning the
+    /// value it had.
+    fn remove_entry_if<'g, F>(
+        &self,
+        hash: u64,
+        key: &K,
+        matches: F,
+        guard: &'g Guard,
+    ) -> Option<&'g V>
+    where
+        K: 'g,
+        F: Fn(&V) -> bool,
+    {
+        let hash = u128::from(hash);
+        loop {
+            let root = self.read_root(guard);
+            match self.iremove(root, key, hash, &matches, 0, None, root.generation(), guard) {
+                IRemoveResult::Removed(v) => return Some(v),
+                IRemoveResult::NotFound => return None,
+                IRemoveResult::Restart => {}
+            }
         }
+    }
 
-        for i in (0..1000).map(|i| i * 2) {
-            assert_eq!(ctrie.lookup(&i, guard), Some(&(i * 3)));
-            assert_eq!(ctrie.lookup(&(i + 1), guard), None);
+    /// Removes every entry from the ctrie in constant time.
+    ///
+    /// Snapshots taken before the ctrie is cleared keep their contents.
+    pub fn clear(&self, guard: &Guard) {
+        let mut publisher = self.events.publisher();
+        loop {
+            let root_ptr = rdcss_read_root(self, false, guard);
+            let main_ptr = gcas_read(unsafe { root_ptr.deref() }.inode(), self, guard);
+            let generation = Generation::new();
+            let empty = MainNode::from_ctrie_node(CtrieNode::new(0, vec![], generation.clone()));
+            let new_root = IndirectionNode::new(Atomic::new(empty), generation);
+            if rdcss(
+                self,
+                root_ptr,
+                main_ptr,
+                RootNode::Indirection(new_root),
+                guard,
+            ) {
+                publisher.publish(Event::Cleared);
+                return;
+            }
         }
+    }
 
-        ctrie.print(guard);
+    /// Returns a receiver of every change made to the ctrie from now on, in the order the changes
+    /// took effect.
+    ///
+    /// While anyone is subscribed, writers publish each change under a lock, so they no longer
+    /// make progress concurrently. Dropping the receiver unsubscribes. Changes made to snapshots
+    /// of the ctrie aren't published.
+    #[cfg(feature = "std")]
+    pub fn subscribe(&self) -> std::sync::mpsc::Receiver<Event<K, V>> {
+        self.events.subscribe(None)
+    }
+
+    /// Returns a receiver of the changes made to a key from now on, including the ctrie being
+    /// cleared, in the order the changes took effect.
+    ///
+    /// Watching a key has the same costs as subscribing to every change.
+    #[cfg(feature = "std")]
+    pub fn watch(&self, key: K) -> std::sync::mpsc::Receiver<Event<K, V>> {
+        self.events.subscribe(Some(key))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn iremove<'g, F>(
+        &self,
+        inode: &IndirectionNode<K, V>,
+        key: &K,
+        hash: u128,
+        matches: &F,
+        level: usize,
+        parent: Option<&IndirectionNode<K, V>>,
+        start_generation: &Generation,
+        guard: &'g Guard,
+    ) -> IRemoveResult<'g, V>
+    where
+        K: 'g,
+        F: Fn(&V) -> bool,
+    {
+        // read the main pointer of the i-node
+        let main_ptr = gcas_read(inode, self, guard);
+        let main = unsafe { main_ptr.deref() };
+
+        match main.kind() {
+            MainNodeKind::Ctrie(cnode) => {
+                let bitmap = cnode.bitmap();
+                let key_hash = self.hashers.hash_at(key, hash, level);
+                let (flag, position) = flag_and_position::<W>(key_hash, level, bitmap);
+
+                if flag & bitmap == 0 {
+                    return IRemoveResult::NotFound;
+                }
+
+                let result = match cnode.branch(position) {
+                    Branch::Indirection(child) => {
+                        if start_generation == child.generation() {
+                            self.iremove(
+                                child,
+                                key,
+                                key_hash,
+                                matches,
+                                level + W,
+                                Some(inode),
+                                start_generation,
+                                guard,
+                            )
+                        } else {
+                            let new_main_ptr = Owned::new(MainNode::from_ctrie_node(
+                                cnode.renewed(start_generation.clone(), self, guard),
+                            ))
+                            .into_shared(guard);
+                            if gcas(inode, main_ptr, new_main_ptr, self, guard) {
+                                self.iremove(
+                                    inode,
+                                    key,
+                                    key_hash,
+                                    matches,
+                                    level,
+                                    parent,
+                                    start_generation,
+                                    guard,
+                                )
+                            } else {
+                                IRemoveResult::Restart
+                            }
+                        }
+                    }
+                    Branch::Singleton(snode) => {
+                        if snode.key() != key || !matches(snode.value()) {
+                            IRemoveResult::NotFound
+                        } else {
+                            let new_cnode =
+                                cnode.removed(flag, position, inode.generation().clone());
+                            let new_main_ptr =
+                                Owned::new(new_cnode.to_contracted(level)).into_shared(guard);
+                            if gcas(inode, main_ptr, new_main_ptr, self, guard) {
+                                IRemoveResult::Removed(snode.value())
+                            } else {
+                                IRemoveResult::Restart
+                            }
+                        }
+                    }
+                };
+
+                if let (IRemoveResult::Removed(_), Some(parent)) = (&result, parent) {
+                    // if the c-node was contracted into a tomb, move the remaining singleton node
+                    // up into the parent
+                    let main_ptr = gcas_read(inode, self, guard);
+                    if let MainNodeKind::Tomb(_) = unsafe { main_ptr.deref() }.kind() {
+                        self.clean_parent(
+                            parent,
+                            inode,
+                            key_hash,
+                            level - W,
+                            start_generation,
+                            guard,
+                        );
+                    }
+                }
+                result
+            }
+
+            MainNodeKind::List(lnode) => {
+                let value = match lnode.lookup(key, guard) {
+                    Some(value) if matches(value) => value,
+                    _ => return IRemoveResult::NotFound,
+                };
+                let new_main = match lnode.remove(key, guard) {
+                    (Some(new_lnode), _) if new_lnode.length(guard) == 1 => {
+                        let snode = new_lnode.entries(guard).next().unwrap();
+                        snode.entomb()
+                    }
+                    (Some(new_lnode), _) => MainNode::from_list_node(new_lnode),
+                    (None, _) => unreachable!("l-nodes have at least two entries"),
+                };
+                let new_main_ptr = Owned::new(new_main).into_shared(guard);
+                if gcas(inode, main_ptr, new_main_ptr, self, guard) {
+                    IRemoveResult::Removed(value)
+                } else {
+                    IRemoveResult::Restart
+                }
+            }
+
+            MainNodeKind::Tomb(_) => {
+                if let Some(parent) = parent {
+                    self.clean(parent, level - W, guard);
+                }
+                IRemoveResult::Restart
+            }
+
+            MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
+        }
+    }
+
+    /// Replaces the entombed i-node in the parent's C-node with its singleton node.
+    fn clean_parent(
+        &self,
+        parent: &IndirectionNode<K, V>,
+        inode: &IndirectionNode<K, V>,
+        key_hash: u128,
+        level: usize,
+        start_generation: &Generation,
+        guard: &Guard,
+    ) {
+        loop {
+            let main_ptr = gcas_read(inode, self, guard);
+            let parent_main_ptr = gcas_read(parent, self, guard);
+            let cnode = match unsafe { parent_main_ptr.deref() }.kind() {
+                MainNodeKind::Ctrie(cnode) => cnode,
+                _ => return,
+            };
+            let (flag, position) = flag_and_position::<W>(key_hash, level, cnode.bitmap());
+            if flag & cnode.bitmap() == 0 {
+                return;
+            }
+            match cnode.branch(position) {
+                // the parent still points to the i-node
+                Branch::Indirection(child) if ptr::eq(Arc::as_ptr(child), inode) => {}
+                _ => return,
+            }
+            let tnode = match unsafe { main_ptr.deref() }.kind() {
+                MainNodeKind::Tomb(tnode) => tnode,
+                _ => return,
+            };
+            let new_cnode = cnode.updated(
+                position,
+                Branch::Singleton(tnode.untombed()),
+                parent.generation().clone(),
+            );
+            let new_main_ptr = Owned::new(new_cnode.to_contracted(level)).into_shared(guard);
+            if gcas(parent, parent_main_ptr, new_main_ptr, self, guard)
+                || self.read_root(guard).generation() != start_generation
+            {
+                return;
+            }
+        }
+    }
+
+    /// Compresses the C-node of the given i-node, resurrecting any entombed children.
+    fn clean(&self, inode: &IndirectionNode<K, V>, level: usize, guard: &Guard) {
+        let main_ptr = gcas_read(inode, self, guard);
+        let main = unsafe { main_ptr.deref() };
+        if let MainNodeKind::Ctrie(cnode) = main.kind() {
+            let new_main_ptr =
+                Owned::new(cnode.to_compressed(level, inode.generation().clone(), self, guard))
+                    .into_shared(guard);
+            gcas(inode, main_ptr, new_main_ptr, self, guard);
+        }
+    }
+
+    /// Checks the structural invariants of the ctrie, returning the number of entries.
+    ///
+    /// Only meaningful when no other thread is modifying the ctrie.
+    #[cfg(any(test, feature = "fuzzing"))]
+    #[doc(hidden)]
+    pub fn validate(&self, guard: &Guard) -> Result<usize, String> {
+        let root = self.read_root(guard);
+        self.validate_inode(root, 0, 0, guard)
+    }
+
+    #[cfg(any(test, feature = "fuzzing"))]
+    fn validate_inode(
+        &self,
+        inode: &IndirectionNode<K, V>,
+        level: usize,
+        prefix: u128,
+        guard: &Guard,
+    ) -> Result<usize, String> {
+        // the bits of a hash that have been used to reach this i-node
+        let prefix_mask = if level >= 128 { !0 } else { (1 << level) - 1 };
+        // rehashes the key, rather than trusting the hash cached in the singleton node
+        let check_prefix = |snode: &SingletonNode<K, V>| {
+            let hash = self.hash(snode.key());
+            let known = snode.known_hash();
+            if hash as u64 != snode.hash() || (known >> 64 != 0 && known != hash) {
+                Err(format!("wrong cached hash at level {}", level))
+            } else if hash & prefix_mask != prefix {
+                Err(format!("key at level {} is on the wrong path", level))
+            } else {
+                Ok(hash)
+            }
+        };
+
+        let main_ptr = inode.main().load(LOAD_ORD, guard);
+        let main = unsafe { main_ptr.deref() };
+        if !main.prev().load(LOAD_ORD, guard).is_null() {
+            return Err(format!("uncommitted main node at level {}", level));
+        }
+        match main.kind() {
+            MainNodeKind::Ctrie(cnode) => {
+                if level >= self.hashers.bits() {
+                    return Err(format!("c-node at level {}", level));
+                }
+                if cnode.bitmap().count_ones() as usize != cnode.branches() {
+                    return Err(format!("bitmap and array size differ at level {}", level));
+                }
+                if level > 0 && cnode.branches() == 0 {
+                    return Err(format!("empty non-root c-node at level {}", level));
+                }
+                let mut count = 0;
+                let mut bitmap = cnode.bitmap();
+                for position in 0..cnode.branches() {
+                    let index = bitmap.trailing_zeros() as u64;
+                    bitmap &= bitmap - 1;
+                    match cnode.branch(position) {
+                        Branch::Singleton(snode) => {
+                            let hash = check_prefix(snode)?;
+                            if hash_index::<W>(hash, level) != index {
+                                return Err(format!("key in wrong slot at level {}", level));
+                            }
+                            count += 1;
+                        }
+                        Branch::Indirection(child) => {
+                            let child_prefix = prefix | (u128::from(index) << level);
+                            count += self.validate_inode(child, level + W, child_prefix, guard)?;
+                        }
+                    }
+                }
+                Ok(count)
+            }
+            MainNodeKind::List(lnode) => {
+                if level < self.hashers.list_level(lnode.length(guard)) {
+                    return Err(format!("l-node at level {}", level));
+                }
+                let mut keys: Vec<&K> = vec![];
+                let mut last = 0;
+                for snode in lnode.entries(guard) {
+                    let order = path_order::<W>(check_prefix(snode)?);
+                    if order < last {
+                        return Err("unsorted l-node".to_owned());
+                    }
+                    last = order;
+                    if keys.contains(&snode.key()) {
+                        return Err("duplicate key in l-node".to_owned());
+                    }
+                    keys.push(snode.key());
+                }
+                if keys.len() < 2 {
+                    return Err("l-node with fewer than two entries".to_owned());
+                }
+                Ok(keys.len())
+            }
+            MainNodeKind::Tomb(tnode) => {
+                if level == 0 {
+                    return Err("tomb node at the root".to_owned());
+                }
+                check_prefix(tnode.snode())?;
+                Ok(1)
+            }
+            MainNodeKind::Failed => Err(format!("failed node at level {}", level)),
+        }
+    }
+
+    #[cfg(all(test, feature = "std"))]
+    fn print(&self, guard: &Guard)
+    where
+        K: Debug,
+        V: Debug,
+    {
+        println!("ctrie:");
+        self.read_root(guard).print(0, guard);
+    }
+}
+
+enum IInsertResult<'g, V> {
+    Ok(Option<&'g V>),
+    Restart,
+}
+
+enum IRemoveResult<'g, V> {
+    Removed(&'g V),
+    NotFound,
+    Restart,
+}
+
+enum ILookupResult<'g, V> {
+    Value(&'g V),
+    NotFound,
+    Restart,
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::hashers::tests::Tiny;
+    use core::{
+        hash::{BuildHasherDefault, Hasher},
+        sync::atomic::{AtomicBool, AtomicUsize},
+    };
+    use crossbeam::epoch;
+
+    #[test]
+    fn insert_lookup() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+
+        for i in (0..1000).map(|i| i * 2) {
+            ctrie.insert(i, i * 3, guard);
+        }
+
+        for i in (0..1000).map(|i| i * 2) {
+            assert_eq!(ctrie.lookup(&i, guard), Some(&(i * 3)));
+            assert_eq!(ctrie.lookup(&(i + 1), guard), None);
+        }
+
+        assert_eq!(ctrie.validate(guard), Ok(1000));
+        ctrie.print(guard);
+    }
+
+    #[test]
+    fn insert_remove() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+
+        for i in 0..1000 {
+            ctrie.insert(i, i, guard);
+        }
+        for i in (0..1000).filter(|i| i % 3 != 0) {
+            assert_eq!(ctrie.remove(&i, guard), Some(&i));
+            assert_eq!(ctrie.remove(&i, guard), None);
+        }
+
+        for i in 0..1000 {
+            let expected = if i % 3 == 0 { Some(&i) } else { None };
+            assert_eq!(ctrie.lookup(&i, guard), expected);
+        }
+        assert_eq!(ctrie.validate(guard), Ok(334));
+
+        for i in (0..1000).filter(|i| i % 3 == 0) {
+            assert_eq!(ctrie.remove(&i, guard), Some(&i));
+        }
+        assert_eq!(ctrie.validate(guard), Ok(0));
+        assert_eq!(ctrie.iter(guard).count(), 0);
+    }
+
+    #[test]
+    fn insert_remove_collisions() {
+        let ctrie = Ctrie::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        let guard = &epoch::pin();
+
+        for i in 0..100u32 {
+            ctrie.insert(i, i, guard);
+        }
+        for i in (0..100u32).filter(|i| i % 4 != 0) {
+            assert_eq!(ctrie.remove(&i, guard), Some(&i));
+            assert_eq!(ctrie.validate(guard).map(|_| ()), Ok(()));
+        }
+        for i in 0..100u32 {
+            let expected = if i % 4 == 0 { Some(&i) } else { None };
+            assert_eq!(ctrie.lookup(&i, guard), expected);
+        }
+        assert_eq!(ctrie.validate(guard), Ok(25));
+    }
+
+    #[test]
+    fn concurrent_insert_remove() {
+        let ctrie = Ctrie::new();
+
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                let ctrie = &ctrie;
+                scope.spawn(move || {
+                    let guard = &epoch::pin();
+                    for i in (0..1000).map(|i| i * 4 + t) {
+                        ctrie.insert(i, i, guard);
+                    }
+                    for i in (0..1000).map(|i| i * 4 + t).filter(|i| i % 2 == 0) {
+                        assert_eq!(ctrie.remove(&i, guard), Some(&i));
+                    }
+                });
+            }
+        });
+
+        let guard = &epoch::pin();
+        for i in 0..4000 {
+            let expected = if i % 2 == 1 { Some(&i) } else { None };
+            assert_eq!(ctrie.lookup(&i, guard), expected);
+        }
+        assert_eq!(ctrie.validate(guard), Ok(2000));
+    }
+
+    #[test]
+    fn snapshot_isolation() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+
+        for i in 0..100 {
+            ctrie.insert(i, i, guard);
+        }
+        let snapshot = ctrie.snapshot(guard);
+        let read_only = ctrie.read_only_snapshot(guard);
+
+        for i in 50..150 {
+            ctrie.insert(i, i + 1, guard);
+        }
+        for i in 0..10 {
+            snapshot.insert(i, 0, guard);
+        }
+
+        for i in 0..150 {
+            let in_ctrie = if i < 50 { i } else { i + 1 };
+            assert_eq!(ctrie.lookup(&i, guard), Some(&in_ctrie));
+            let in_snapshot = if i < 10 {
+                Some(&0)
+            } else if i < 100 {
+                Some(&i)
+            } else {
+                None
+            };
+            assert_eq!(snapshot.lookup(&i, guard), in_snapshot);
+            assert_eq!(
+                read_only.lookup(&i, guard),
+                if i < 100 { Some(&i) } else { None }
+            );
+        }
+        assert_eq!(ctrie.validate(guard), Ok(150));
+        assert_eq!(snapshot.validate(guard), Ok(100));
+        assert_eq!(read_only.validate(guard), Ok(100));
+    }
+
+    #[test]
+    fn concurrent_snapshots() {
+        let ctrie = Ctrie::new();
+
+        std::thread::scope(|scope| {
+            for t in 0..3 {
+                let ctrie = &ctrie;
+                scope.spawn(move || {
+                    let guard = &epoch::pin();
+                    for i in 0..2000 {
+                        ctrie.insert((t, i), i, guard);
+                    }
+                });
+            }
+            let ctrie = &ctrie;
+            scope.spawn(move || {
+                let guard = &epoch::pin();
+                for _ in 0..50 {
+                    // each thread inserts its keys in order, so every snapshot must contain a
+                    // prefix of each thread's keys
+                    let snapshot = ctrie.read_only_snapshot(guard);
+                    for t in 0..3 {
+                        let count = snapshot.iter(guard).filter(|(k, _)| k.0 == t).count();
+                        for i in 0..2000 {
+                            assert_eq!(snapshot.lookup(&(t, i), guard).is_some(), i < count);
+                        }
+                    }
+                }
+            });
+        });
+
+        let guard = &epoch::pin();
+        assert_eq!(ctrie.validate(guard), Ok(6000));
+    }
+
+    #[test]
+    fn iter() {
+        let ctrie = Ctrie::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        let guard = &epoch::pin();
+
+        for i in 0..500 {
+            ctrie.insert(i, i * 2, guard);
+        }
+        let iter = ctrie.iter(guard);
+        for i in 500..600 {
+            ctrie.insert(i, i * 2, guard);
+        }
+
+        let mut entries = iter.map(|(&k, &v)| (k, v)).collect::<Vec<_>>();
+        entries.sort();
+        assert_eq!(entries, (0..500).map(|i| (i, i * 2)).collect::<Vec<_>>());
+        assert_eq!(ctrie.iter(guard).count(), 600);
+    }
+
+    #[test]
+    fn clear() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+
+        for i in 0..100 {
+            ctrie.insert(i, i, guard);
+        }
+        let snapshot = ctrie.read_only_snapshot(guard);
+        ctrie.clear(guard);
+        assert_eq!(ctrie.validate(guard), Ok(0));
+        assert_eq!(ctrie.lookup(&1, guard), None);
+
+        ctrie.insert(1, 2, guard);
+        assert_eq!(ctrie.lookup(&1, guard), Some(&2));
+        assert_eq!(ctrie.validate(guard), Ok(1));
+        assert_eq!(snapshot.validate(guard), Ok(100));
+    }
+
+    fn sorted_diff<S: BuildHasher + Clone>(
+        old: &Ctrie<u32, u32, S>,
+        new: &Ctrie<u32, u32, S>,
+    ) -> Vec<Change<u32, u32>> {
+        let guard = &epoch::pin();
+        let mut changes = old.diff(new, guard).collect::<Vec<_>>();
+        changes.sort_by_key(|change| *change.key());
+        changes
+    }
+
+    #[test]
+    fn diff() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+
+        for i in 0..1000 {
+            ctrie.insert(i, i, guard);
+        }
+        let old = ctrie.read_only_snapshot(guard);
+        for i in 0..10 {
+            ctrie.remove(&i, guard);
+        }
+        for i in 500..505 {
+            ctrie.insert(i, 0, guard);
+        }
+        ctrie.insert(600, 600, guard);
+        ctrie.insert(1000, 1000, guard);
+        let new = ctrie.read_only_snapshot(guard);
+
+        let mut expected = (0..10)
+            .map(|i| Change::Removed { key: i, value: i })
+            .collect::<Vec<_>>();
+        expected.extend((500..505).map(|i| Change::Updated {
+            key: i,
+            old: i,
+            new: 0,
+        }));
+        expected.push(Change::Added {
+            key: 1000,
+            value: 1000,
+        });
+        assert_eq!(sorted_diff(&old, &new), expected);
+        assert_eq!(sorted_diff(&new, &new), vec![]);
+    }
+
+    #[test]
+    fn diff_collisions() {
+        let ctrie = Ctrie::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        let guard = &epoch::pin();
+
+        for i in 0..200 {
+            ctrie.insert(i, i, guard);
+        }
+        let old = ctrie.read_only_snapshot(guard);
+        for i in (0..200).filter(|i| i % 3 == 0) {
+            ctrie.remove(&i, guard);
+        }
+        for i in (0..300).filter(|i| i % 5 == 0) {
+            ctrie.insert(i, i + 1, guard);
+        }
+        let new = ctrie.read_only_snapshot(guard);
+
+        let expected = (0..300)
+            .filter_map(|i| {
+                let old = old.lookup(&i, guard).copied();
+                let new = new.lookup(&i, guard).copied();
+                match (old, new) {
+                    (Some(old), Some(new)) if old != new => {
+                        Some(Change::Updated { key: i, old, new })
+                    }
+                    (Some(value), None) => Some(Change::Removed { key: i, value }),
+                    (None, Some(value)) => Some(Change::Added { key: i, value }),
+                    _ => None,
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sorted_diff(&old, &new), expected);
+
+        // unrelated ctries with the same hasher can be diffed too
+        let other = Ctrie::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        for (&key, &value) in new.iter(guard) {
+            other.insert(key, value, guard);
+        }
+        assert_eq!(sorted_diff(&new, &other), vec![]);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't diff ctries that hash keys differently")]
+    fn diff_mismatched_hashers() {
+        let guard = &epoch::pin();
+        let a = RandomStateCtrie::with_random_state();
+        let b = RandomStateCtrie::with_random_state();
+        a.insert(1, 1, guard);
+        b.insert(1, 1, guard);
+        a.diff(&b, guard).for_each(drop);
+    }
+
+    #[test]
+    fn subscribe() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+
+        ctrie.insert(0, 0, guard);
+        let all = ctrie.subscribe();
+        let watched = ctrie.watch(1);
+        ctrie.insert(1, 1, guard);
+        ctrie.insert(2, 2, guard);
+        ctrie.insert(1, 3, guard);
+        ctrie.remove(&0, guard);
+        ctrie.remove(&4, guard);
+        ctrie.clear(guard);
+        assert_eq!(ctrie.validate(guard), Ok(0));
+
+        assert_eq!(
+            all.try_iter().collect::<Vec<_>>(),
+            vec![
+                Event::Change(Change::Added { key: 1, value: 1 }),
+                Event::Change(Change::Added { key: 2, value: 2 }),
+                Event::Change(Change::Updated {
+                    key: 1,
+                    old: 1,
+                    new: 3
+                }),
+                Event::Change(Change::Removed { key: 0, value: 0 }),
+                Event::Cleared,
+            ]
+        );
+        assert_eq!(
+            watched.try_iter().collect::<Vec<_>>(),
+            vec![
+                Event::Change(Change::Added { key: 1, value: 1 }),
+                Event::Change(Change::Updated {
+                    key: 1,
+                    old: 1,
+                    new: 3
+                }),
+                Event::Cleared,
+            ]
+        );
+
+        // dropping the receivers unsubscribes
+        drop(all);
+        drop(watched);
+        ctrie.insert(1, 1, guard);
+        assert!(!ctrie.events.publisher().active());
+    }
+
+    #[test]
+    fn concurrent_subscribe() {
+        let ctrie = Ctrie::new();
+        let changes = ctrie.subscribe();
+
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                let ctrie = &ctrie;
+                scope.spawn(move || {
+                    let guard = &epoch::pin();
+                    for i in 0..500 {
+                        let key = (i * 7 + t) % 64;
+                        if i % 3 == 0 {
+                            ctrie.remove(&key, guard);
+                        } else {
+                            ctrie.insert(key, t, guard);
+                        }
+                    }
+                });
+            }
+        });
+
+        // replaying the changes in the order they were published gives the final contents
+        let mut model = std::collections::HashMap::new();
+        for change in changes.try_iter() {
+            match change {
+                Event::Change(Change::Added { key, value }) => {
+                    assert_eq!(model.insert(key, value), None)
+                }
+                Event::Change(Change::Updated { key, old, new }) => {
+                    assert_eq!(model.insert(key, new), Some(old))
+                }
+                Event::Change(Change::Removed { key, value }) => {
+                    assert_eq!(model.remove(&key), Some(value))
+                }
+                Event::Cleared => model.clear(),
+            }
+        }
+        let guard = &epoch::pin();
+        let mut entries = ctrie.iter(guard).map(|(&k, &v)| (k, v)).collect::<Vec<_>>();
+        let mut expected = model.into_iter().collect::<Vec<_>>();
+        entries.sort();
+        expected.sort();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn subscribe_during_writes() {
+        let ctrie = Ctrie::new();
+        let done = AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            for t in 0..4u32 {
+                let (ctrie, done) = (&ctrie, &done);
+                scope.spawn(move || {
+                    let guard = &epoch::pin();
+                    let mut i = 0u32;
+                    while !done.load(Ordering::Relaxed) {
+                        let key = (i * 7 + t) % 16;
+                        if i.is_multiple_of(3) {
+                            ctrie.remove(&key, guard);
+                        } else {
+                            ctrie.insert(key, i * 4 + t, guard);
+                        }
+                        i += 1;
+                    }
+                });
+            }
+
+            // every subscription returns while writers keep going, and misses none of the changes
+            // made after it, so each change to a key follows on from the one before it
+            for _ in 0..50 {
+                let changes = ctrie.subscribe();
+                std::thread::sleep(std::time::Duration::from_millis(1));
+                let mut values = std::collections::HashMap::new();
+                for change in changes.try_iter() {
+                    let Event::Change(change) = change else {
+                        panic!("the ctrie is never cleared");
+                    };
+                    let (key, before, after) = match change {
+                        Change::Added { key, value } => (key, None, Some(value)),
+                        Change::Updated { key, old, new } => (key, Some(old), Some(new)),
+                        Change::Removed { key, value } => (key, Some(value), None),
+                    };
+                    if let Some(previous) = values.insert(key, after) {
+                        assert_eq!(previous, before);
+                    }
+                }
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+
+    #[test]
+    fn path_order() {
+        // the lowest chunk is the most significant
+        assert_eq!(super::path_order::<6>(1), 1 << 122);
+        assert_eq!(super::path_order::<6>(1 << 6), 1 << 116);
+        assert_eq!(super::path_order::<6>(1 << 63), 1 << 65);
+        assert!(super::path_order::<6>(2) > super::path_order::<6>(1 << 6 | 1));
+        // the last chunk is narrower unless the width divides 128
+        assert_eq!(super::path_order::<6>(1 << 127), 1 << 1);
+        assert_eq!(super::path_order::<4>(1), 1 << 124);
+        assert_eq!(super::path_order::<5>(1), 1 << 123);
+        assert_eq!(super::path_order::<5>(1 << 127), 1 << 2);
+    }
+
+    #[test]
+    fn get_many() {
+        let ctrie = Ctrie::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        let guard = &epoch::pin();
+        for i in 0..200 {
+            ctrie.insert(i, i * 2, guard);
+        }
+        for i in (0..200).step_by(3) {
+            ctrie.remove(&i, guard);
+        }
+
+        let keys = (0..300).rev().chain(0..10).collect::<Vec<_>>();
+        let expected = keys
+            .iter()
+            .map(|key| ctrie.lookup(key, guard))
+            .collect::<Vec<_>>();
+        assert_eq!(ctrie.get_many(&keys, guard), expected);
+        assert_eq!(ctrie.get_many(&[], guard), vec![]);
+
+        let ctrie = Ctrie::new();
+        for i in 0..1000 {
+            ctrie.insert(i, i, guard);
+        }
+        let keys = (0..2000).step_by(7).collect::<Vec<_>>();
+        let values = ctrie.get_many(&keys, guard);
+        for (key, value) in keys.iter().zip(values) {
+            assert_eq!(value, if *key < 1000 { Some(key) } else { None });
+        }
+    }
+
+    #[test]
+    fn get_many_secondary_hashes() {
+        let guard = &epoch::pin();
+        // keys whose primary hashes collide are told apart by their secondary hashes
+        let ctrie = Ctrie::with_fallback_hasher(Tiny::default(), DefaultHashBuilder::default(), 2);
+        for i in 0..300u32 {
+            ctrie.insert(i, i, guard);
+        }
+        let keys = (0..400).rev().chain(0..10).collect::<Vec<_>>();
+        let expected = keys
+            .iter()
+            .map(|key| ctrie.lookup(key, guard))
+            .collect::<Vec<_>>();
+        assert_eq!(ctrie.get_many(&keys, guard), expected);
+        assert_eq!(ctrie.get_many(&[5], guard), vec![Some(&5)]);
+
+        // and no other keys are hashed by the secondary hasher
+        let secondary = CountingHasher::default();
+        let ctrie = Ctrie::with_hashers(CountingHasher::default(), secondary.clone());
+        for i in 0..1000u32 {
+            ctrie.insert(i, i, guard);
+        }
+        let before = secondary.count();
+        let keys = (0..2000).collect::<Vec<_>>();
+        let values = ctrie.get_many(&keys, guard);
+        for (key, value) in keys.iter().zip(values) {
+            assert_eq!(value, if *key < 1000 { Some(key) } else { None });
+        }
+        assert_eq!(secondary.count(), before);
+    }
+
+    #[test]
+    fn get_many_consistent() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        let keys = (0..8).collect::<Vec<_>>();
+        for &key in &keys {
+            ctrie.insert(key, 100, guard);
+        }
+
+        std::thread::scope(|scope| {
+            let ctrie = &ctrie;
+            scope.spawn(move || {
+                let guard = &epoch::pin();
+                for i in 0..500 {
+                    let (from, to) = (i % 8, (i * 3 + 1) % 8);
+                    ctrie.transaction(
+                        |tx| {
+                            let a = *tx.get(&from).unwrap();
+                            let b = *tx.get(&to).unwrap();
+                            if from != to {
+                                tx.insert(from, a - 1);
+                                tx.insert(to, b + 1);
+                            }
+                        },
+                        guard,
+                    );
+                }
+            });
+            scope.spawn(move || {
+                let guard = &epoch::pin();
+                for _ in 0..500 {
+                    let sum = ctrie
+                        .get_many(&keys, guard)
+                        .into_iter()
+                        .map(|value| value.unwrap())
+                        .sum::<i32>();
+                    assert_eq!(sum, 800);
+                }
+            });
+        });
+    }
+
+    #[test]
+    fn send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Ctrie<u32, String>>();
+        assert_send_sync::<Ctrie<String, Vec<u8>>>();
+    }
+
+    #[test]
+    fn concurrent_insert_lookup() {
+        let ctrie = Ctrie::new();
+
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                let ctrie = &ctrie;
+                scope.spawn(move || {
+                    let guard = &epoch::pin();
+                    for i in (0..1000).map(|i| i * 4 + t) {
+                        ctrie.insert(i, i * 3, guard);
+                        assert_eq!(ctrie.lookup(&i, guard), Some(&(i * 3)));
+                    }
+                });
+            }
+        });
+
+        let guard = &epoch::pin();
+        for i in 0..4000 {
+            assert_eq!(ctrie.lookup(&i, guard), Some(&(i * 3)));
+        }
+        assert_eq!(ctrie.validate(guard), Ok(4000));
+    }
+
+    fn check_width<S: BuildHasher + Clone + Default, const W: usize>(n: u32) {
+        let ctrie = Ctrie::<u32, u32, S, W>::with_width(S::default());
+        let guard = &epoch::pin();
+        for i in 0..n {
+            ctrie.insert(i, i, guard);
+        }
+        let snapshot = ctrie.snapshot(guard);
+        for i in (0..n).filter(|i| i % 3 != 0) {
+            assert_eq!(ctrie.remove(&i, guard), Some(&i));
+        }
+        for i in 0..n {
+            let expected = (i % 3 == 0).then_some(&i);
+            assert_eq!(ctrie.lookup(&i, guard), expected);
+            assert_eq!(snapshot.lookup(&i, guard), Some(&i));
+        }
+        let kept = n.div_ceil(3) as usize;
+        assert_eq!(ctrie.validate(guard), Ok(kept));
+        assert_eq!(snapshot.validate(guard), Ok(n as usize));
+        assert_eq!(snapshot.diff(&ctrie, guard).count(), n as usize - kept);
+
+        let mut builder = CtrieBuilder::<u32, u32, S, W>::with_width(S::default());
+        builder.extend((0..n).map(|i| (i, i)));
+        let built = builder.build();
+        assert_eq!(built.validate(guard), Ok(n as usize));
+        assert_eq!(built.diff(&snapshot, guard).count(), 0);
+    }
+
+    #[test]
+    fn widths() {
+        check_width::<BuildHasherDefault<FxHasher>, 4>(5000);
+        check_width::<BuildHasherDefault<FxHasher>, 5>(5000);
+        check_width::<BuildHasherDefault<FxHasher>, 6>(5000);
+        check_width::<BuildHasherDefault<TinyHasher>, 4>(100);
+        check_width::<BuildHasherDefault<TinyHasher>, 5>(100);
+    }
+
+    /// Hashes every key to one of a handful of values, so that keys collide all the way down.
+    #[derive(Default)]
+    pub(crate) struct TinyHasher(u64);
+
+    impl Hasher for TinyHasher {
+        fn finish(&self) -> u64 {
+            let h = self.0 % 4;
+            (h & 1) | ((h >> 1) << 33)
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for &byte in bytes {
+                self.0 = self.0.wrapping_mul(31).wrapping_add(u64::from(byte));
+            }
+        }
+    }
+
+    /// Builds `FxHasher`s and counts how many it has built.
+    #[derive(Clone, Default)]
+    pub(crate) struct CountingHasher(Arc<AtomicUsize>);
+
+    impl CountingHasher {
+        pub(crate) fn count(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl BuildHasher for CountingHasher {
+        type Hasher = FxHasher;
+
+        fn build_hasher(&self) -> FxHasher {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            FxHasher::default()
+        }
+    }
+
+    #[test]
+    fn cached_hashes() {
+        let hasher = CountingHasher::default();
+        let ctrie = Ctrie::with_hasher(hasher.clone());
+        let guard = &epoch::pin();
+        // splitting singletons into deeper c-nodes reuses their hashes
+        for i in 0..2000u32 {
+            ctrie.insert(i, i, guard);
+        }
+        assert_eq!(hasher.count(), 2000);
+
+        // renewing the nodes of a snapshot, and comparing and copying tries, don't hash either
+        let snapshot = ctrie.snapshot(guard);
+        for i in 0..100 {
+            ctrie.remove(&i, guard);
+        }
+        assert_eq!(hasher.count(), 2100);
+        assert_eq!(snapshot.diff(&ctrie, guard).count(), 100);
+        let doubled = ctrie.map_values(|_, &value| value * 2, guard);
+        let odds = doubled.filter(|&key, _| key % 2 == 1, guard);
+        let merged = snapshot.union(&odds, |_, _, &value| value, guard);
+        // apart from a probe value, hashed by both sides of the diff and the union to check that
+        // they hash keys the same way
+        assert_eq!(hasher.count(), 2104);
+
+        for i in 0..2000 {
+            let hash = ctrie.hasher().hash_one(i);
+            let expected = if i % 2 == 1 && i >= 100 { i * 2 } else { i };
+            assert_eq!(merged.get_with_hash(hash, &i, guard), Some(&expected));
+            merged.insert_with_hash(hash, i, i, guard);
+        }
+        assert_eq!(hasher.count(), 4104);
+        assert!(merged.iter(guard).all(|(key, value)| key == value));
+        assert_eq!(merged.validate(guard), Ok(2000));
+    }
+
+    #[test]
+    fn secondary_hashes() {
+        let secondary = CountingHasher::default();
+        let ctrie = Ctrie::with_hashers(CountingHasher::default(), secondary.clone());
+        let guard = &epoch::pin();
+        // keys whose primary hashes don't collide are never hashed by the secondary hasher,
+        // however often their singletons are split, copied or rebuilt
+        for i in 0..2000u32 {
+            ctrie.insert(i, i, guard);
+        }
+        let snapshot = ctrie.snapshot(guard);
+        for i in 0..100 {
+            assert_eq!(ctrie.remove(&i, guard), Some(&i));
+        }
+        ctrie.insert_batch((2000..3000).map(|i| (i, i)), guard);
+        ctrie.retain(|&key, _| key % 3 != 0, guard);
+        assert_eq!(snapshot.diff(&ctrie, guard).count(), 1400);
+        let merged = snapshot.union(&ctrie, |_, _, &value| value, guard);
+        for i in 0..3000 {
+            let expected = (i < 2000 || i % 3 != 0).then_some(&i);
+            assert_eq!(merged.lookup(&i, guard), expected);
+        }
+        // only the probe value the diff and the union check the hashers with
+        assert_eq!(secondary.count(), 4);
+        assert_eq!(merged.validate(guard), Ok(2667));
+    }
+
+    #[test]
+    fn cached_secondary_hashes() {
+        let secondary = CountingHasher::default();
+        let ctrie = Ctrie::with_fallback_hasher(Tiny::default(), secondary.clone(), 4);
+        let guard = &epoch::pin();
+        // every key is hashed by the secondary hasher once, when it first shares a primary hash
+        // with another, and never again as its list grows or is spread over C-nodes. Zero is left
+        // out, since a key whose secondary hash is zero is hashed again whenever it's needed.
+        for i in 1..=1000u32 {
+            ctrie.insert(i, i, guard);
+        }
+        assert_eq!(secondary.count(), 1000);
+
+        // operations on a key hash it once at most, however deep the key is
+        for i in 1..=1000 {
+            assert_eq!(ctrie.lookup(&i, guard), Some(&i));
+            ctrie.insert(i, i + 1, guard);
+        }
+        assert_eq!(secondary.count(), 3000);
+
+        // paging through the entries uses the hashes the nodes know
+        let mut cursor = Some(HashCursor::start());
+        let mut count = 0;
+        while let Some(start) = cursor {
+            let (page, next) = ctrie.scan_from(start, 7, guard);
+            count += page.len();
+            cursor = next;
+        }
+        assert_eq!(count, 1000);
+        assert_eq!(secondary.count(), 3000);
+
+        for i in (1..=1000).step_by(2) {
+            assert_eq!(ctrie.remove(&i, guard), Some(&(i + 1)));
+        }
+        assert_eq!(ctrie.validate(guard), Ok(500));
+    }
+
+    #[test]
+    fn insert_lookup_collisions() {
+        let ctrie = Ctrie::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        let guard = &epoch::pin();
+
+        for i in 0..100u32 {
+            ctrie.insert(i, i, guard);
+        }
+        for i in (0..100u32).step_by(3) {
+            ctrie.insert(i, i + 1000, guard);
+        }
+
+        for i in 0..100u32 {
+            let expected = if i % 3 == 0 { i + 1000 } else { i };
+            assert_eq!(ctrie.lookup(&i, guard), Some(&expected));
+        }
+        assert_eq!(ctrie.lookup(&100, guard), None);
+        assert_eq!(ctrie.validate(guard), Ok(100));
     }
 }
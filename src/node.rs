@@ -2,14 +2,16 @@ mod ctrie;
 mod indirection;
 mod list;
 mod main;
+mod root;
 mod singleton;
 mod tomb;
 
 pub use self::{
     ctrie::{Branch, CtrieNode},
     indirection::IndirectionNode,
-    list::ListNode,
+    list::{ListEntries, ListNode},
     main::{MainNode, MainNodeKind},
+    root::{RdcssDescriptor, RootNode, ABORTED, COMMITTED, UNDECIDED},
     singleton::SingletonNode,
     tomb::TombNode,
 };
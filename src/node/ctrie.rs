@@ -1,13 +1,19 @@
 use crate::{
+    gcas::gcas_read,
     node::{IndirectionNode, MainNode, SingletonNode},
-    Ctrie, Generation, Key, Value,
+    resurrect, Ctrie, Generation, Key, Value,
 };
+use alloc::{sync::Arc, vec::Vec};
+#[cfg(feature = "std")]
+use core::fmt::Debug;
+use core::hash::BuildHasher;
 use crossbeam::epoch::Guard;
-use std::{fmt::Debug, hash::BuildHasher};
 
 #[derive(Clone)]
 pub enum Branch<K, V> {
-    Indirection(IndirectionNode<K, V>),
+    /// I-nodes are shared between copies of a C-node, so that an update to the i-node through
+    /// one copy is visible through all of them.
+    Indirection(Arc<IndirectionNode<K, V>>),
     Singleton(SingletonNode<K, V>),
 }
 
@@ -64,18 +70,29 @@ where
         }
     }
 
-    pub fn renewed<S: BuildHasher>(
+    /// Removes a branch from the C-node, returning a new node.
+    pub fn removed(&self, flag: u64, position: usize, generation: Generation) -> Self {
+        let mut new_array = self.array.clone();
+        new_array.remove(position);
+        Self {
+            bitmap: self.bitmap & !flag,
+            array: new_array,
+            generation,
+        }
+    }
+
+    pub fn renewed<S: BuildHasher, const W: usize, S2: BuildHasher>(
         &self,
         generation: Generation,
-        ctrie: &Ctrie<K, V, S>,
+        ctrie: &Ctrie<K, V, S, W, S2>,
         guard: &Guard,
     ) -> Self {
         let mut new_array = Vec::with_capacity(self.array.len());
         for branch in &self.array {
             let new_branch = match branch {
-                Branch::Indirection(inode) => {
-                    Branch::Indirection(inode.copy_to_generation(generation.clone(), ctrie, guard))
-                }
+                Branch::Indirection(inode) => Branch::Indirection(Arc::new(
+                    inode.copy_to_generation(generation.clone(), ctrie, guard),
+                )),
                 Branch::Singleton(snode) => Branch::Singleton(snode.clone()),
             };
             new_array.push(new_branch);
@@ -83,15 +100,40 @@ where
         Self {
             array: new_array,
             bitmap: self.bitmap,
-            generation: generation,
+            generation,
         }
     }
 
-    pub fn to_contracted(&self, level: u64) -> MainNode<K, V>
-    where
-        K: Key,
-        V: Value,
-    {
+    /// Replaces every i-node branch whose main node is a tomb with the entombed singleton node,
+    /// then contracts the result.
+    pub fn to_compressed<S: BuildHasher, const W: usize, S2: BuildHasher>(
+        &self,
+        level: usize,
+        generation: Generation,
+        ctrie: &Ctrie<K, V, S, W, S2>,
+        guard: &Guard,
+    ) -> MainNode<K, V> {
+        let mut new_array = Vec::with_capacity(self.array.len());
+        for branch in &self.array {
+            let new_branch = match branch {
+                Branch::Indirection(inode) => {
+                    let main_ptr = gcas_read(inode, ctrie, guard);
+                    let main = unsafe { main_ptr.deref() };
+                    resurrect(inode.clone(), main)
+                }
+                Branch::Singleton(snode) => Branch::Singleton(snode.clone()),
+            };
+            new_array.push(new_branch);
+        }
+        let new_cnode = Self {
+            array: new_array,
+            bitmap: self.bitmap,
+            generation,
+        };
+        new_cnode.to_contracted(level)
+    }
+
+    pub fn to_contracted(&self, level: usize) -> MainNode<K, V> {
         if level > 0 && self.array.len() == 1 {
             match &self.array[0] {
                 Branch::Singleton(snode) => snode.entomb(),
@@ -118,12 +160,13 @@ where
         &self.generation
     }
 
-    pub fn print<'g>(&self, indent: usize, guard: &'g Guard)
+    #[cfg(feature = "std")]
+    pub fn print(&self, indent: usize, guard: &Guard)
     where
         K: Debug,
         V: Debug,
     {
-        let tab = std::iter::repeat(' ').take(indent).collect::<String>();
+        let tab = " ".repeat(indent);
         println!("{}cnode:", tab);
         println!("{}bitmap: {:064b}", tab, self.bitmap);
         println!("{}array:", tab);
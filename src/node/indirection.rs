@@ -1,6 +1,10 @@
-use crate::{gcas::*, node::MainNode, Ctrie, Generation, Key, Value, LOAD_ORD};
+#[cfg(feature = "std")]
+use crate::LOAD_ORD;
+use crate::{gcas::*, node::MainNode, Ctrie, Generation, Key, Value};
+#[cfg(feature = "std")]
+use core::fmt::Debug;
+use core::hash::BuildHasher;
 use crossbeam::epoch::{Atomic, Guard};
-use std::{fmt::Debug, hash::BuildHasher};
 
 pub struct IndirectionNode<K, V> {
     main: Atomic<MainNode<K, V>>,
@@ -17,11 +21,11 @@ where
         Self { main, generation }
     }
 
-    pub fn copy_to_generation<'g, S: BuildHasher>(
+    pub fn copy_to_generation<S: BuildHasher, const W: usize, S2: BuildHasher>(
         &self,
         generation: Generation,
-        ctrie: &Ctrie<K, V, S>,
-        guard: &'g Guard,
+        ctrie: &Ctrie<K, V, S, W, S2>,
+        guard: &Guard,
     ) -> Self {
         let main = gcas_read(self, ctrie, guard);
         Self {
@@ -38,12 +42,13 @@ where
         &self.generation
     }
 
-    pub fn print<'g>(&self, indent: usize, guard: &'g Guard)
+    #[cfg(feature = "std")]
+    pub fn print(&self, indent: usize, guard: &Guard)
     where
         K: Debug,
         V: Debug,
     {
-        let tab = std::iter::repeat(' ').take(indent).collect::<String>();
+        let tab = " ".repeat(indent);
         println!("{}inode:", tab);
         let main_ptr = self.main.load(LOAD_ORD, guard);
         let main = unsafe { main_ptr.deref() };
@@ -1,4 +1,7 @@
 use crate::{node::SingletonNode, Key, Value, LOAD_ORD};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use core::fmt::Debug;
 use crossbeam::epoch::{Atomic, Guard};
 
 /// A node that represents an immutable linked list of singleton nodes.
@@ -15,18 +18,31 @@ where
     K: Key,
     V: Value,
 {
-    /// Creates a new list node with one singleton node with the given key and value.
-    pub fn new(key: K, value: V) -> Self {
+    /// Creates a new list node with the given singleton node.
+    pub fn new(head: SingletonNode<K, V>) -> Self {
         Self {
-            head: SingletonNode::new(key, value),
+            head,
             tail: Atomic::null(),
         }
     }
 
+    /// Creates a list of the given singleton nodes, in the same order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no singleton nodes.
+    pub fn from_entries(mut entries: Vec<SingletonNode<K, V>>) -> Self {
+        let mut list = Self::new(entries.pop().expect("a list has at least one entry"));
+        while let Some(snode) = entries.pop() {
+            list = list.prepended(snode);
+        }
+        list
+    }
+
     /// Returns the number of nodes in the list.
     ///
     /// Guaranteed to be at least one.
-    pub fn length<'g>(&self, guard: &'g Guard) -> usize {
+    pub fn length(&self, guard: &Guard) -> usize {
         // list node always contains at one element: self.head
         let mut length = 1;
         let mut tail_ptr = self.tail.load(LOAD_ORD, guard);
@@ -44,16 +60,35 @@ where
         }
     }
 
-    /// Adds a new singleton node with the given key and value to the beginning of the list.
+    /// Adds a singleton node to the beginning of the list.
     ///
     /// Returns the new list.
-    pub fn add(&self, key: K, value: V) -> Self {
+    pub fn add(&self, head: SingletonNode<K, V>) -> Self {
         Self {
-            head: SingletonNode::new(key, value),
+            head,
             tail: Atomic::new(self.clone()),
         }
     }
 
+    /// Like `add`, but moves the list into the new one instead of copying its head.
+    pub fn prepended(self, head: SingletonNode<K, V>) -> Self {
+        Self {
+            head,
+            tail: Atomic::new(self),
+        }
+    }
+
+    /// Inserts a singleton node into the list, replacing the node with the same key if there is
+    /// one.
+    ///
+    /// Returns the new list.
+    pub fn inserted(&self, snode: SingletonNode<K, V>, guard: &Guard) -> Self {
+        match self.remove(snode.key(), guard) {
+            (Some(rest), _) => rest.prepended(snode),
+            (None, _) => Self::new(snode),
+        }
+    }
+
     /// Removes the element corresponding to the given key from the list.
     ///
     /// Returns the new list or `None` if the new list is empty. Also returns a boolean
@@ -100,7 +135,7 @@ where
 
     /// Attempts to locate the singleton node with the given key in the list, returning its
     /// corresponding value if found.
-    pub fn lookup<'g>(&'g self, key: &K, guard: &'g Guard) -> Option<&V> {
+    pub fn lookup<'g>(&'g self, key: &K, guard: &'g Guard) -> Option<&'g V> {
         if key == self.head.key() {
             // key found
             Some(self.head.value())
@@ -125,17 +160,63 @@ where
             }
         }
     }
+
+    /// Returns an iterator over the singleton nodes in the list.
+    pub fn entries<'g>(&'g self, guard: &'g Guard) -> ListEntries<'g, K, V> {
+        ListEntries {
+            next: Some(self),
+            guard,
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn print(&self, indent: usize, guard: &Guard)
+    where
+        K: Debug,
+        V: Debug,
+    {
+        let tab = " ".repeat(indent);
+        println!("{}lnode:", tab);
+        for snode in self.entries(guard) {
+            snode.print(indent + 2);
+        }
+    }
+}
+
+/// An iterator over the singleton nodes of a list node.
+pub struct ListEntries<'g, K, V> {
+    next: Option<&'g ListNode<K, V>>,
+    guard: &'g Guard,
+}
+
+impl<'g, K, V> Iterator for ListEntries<'g, K, V> {
+    type Item = &'g SingletonNode<K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let lnode = self.next?;
+        let tail_ptr = lnode.tail.load(LOAD_ORD, self.guard);
+        // a null tail marks the end of the list
+        self.next = unsafe { tail_ptr.as_ref() };
+        Some(&lnode.head)
+    }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod test {
     use super::*;
     use crossbeam::epoch;
 
+    /// Returns a singleton node whose key hashes to zero, like all keys of a list share a hash.
+    fn snode(key: char, value: i32) -> SingletonNode<char, i32> {
+        SingletonNode::new(key, value, 0)
+    }
+
     #[test]
     fn add_lookup_remove() {
         // [('a', 1), ('b', 2), ('c', 3)]
-        let list = ListNode::new('c', 3).add('b', 2).add('a', 1);
+        let list = ListNode::new(snode('c', 3))
+            .add(snode('b', 2))
+            .add(snode('a', 1));
 
         let guard = &epoch::pin();
 
@@ -164,4 +245,25 @@ mod test {
         assert!(did_remove);
         assert!(list.is_none());
     }
+
+    #[test]
+    fn inserted_replaces() {
+        let guard = &epoch::pin();
+
+        let list = ListNode::new(snode('b', 2)).add(snode('a', 1));
+        let list = list
+            .inserted(snode('b', 20), guard)
+            .inserted(snode('c', 3), guard);
+
+        assert_eq!(list.length(guard), 3);
+        assert_eq!(list.lookup(&'a', guard), Some(&1));
+        assert_eq!(list.lookup(&'b', guard), Some(&20));
+        assert_eq!(list.lookup(&'c', guard), Some(&3));
+
+        let keys = list
+            .entries(guard)
+            .map(|snode| *snode.key())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!['c', 'b', 'a']);
+    }
 }
@@ -1,9 +1,15 @@
 use crate::{
+    hash_index,
     node::{Branch, CtrieNode, IndirectionNode, ListNode, SingletonNode, TombNode},
-    Generation, Key, Value, LAST_W_BITS, W,
+    path_order, Generation, Key, Value,
 };
-use crossbeam::epoch::{Atomic, Guard};
-use std::{cmp, fmt::Debug};
+use alloc::{sync::Arc, vec};
+use core::cmp;
+#[cfg(feature = "std")]
+use core::fmt::Debug;
+use crossbeam::epoch::Atomic;
+#[cfg(feature = "std")]
+use crossbeam::epoch::Guard;
 
 #[derive(Clone)]
 pub enum MainNodeKind<K, V> {
@@ -24,17 +30,18 @@ where
     K: Key,
     V: Value,
 {
-    pub fn new(
+    pub fn new<const W: usize>(
         x: SingletonNode<K, V>,
-        x_hash: u64,
+        x_hash: u128,
         y: SingletonNode<K, V>,
-        y_hash: u64,
+        y_hash: u128,
         level: usize,
+        bits: usize,
         generation: Generation,
     ) -> Self {
-        if level < 64 {
-            let x_index = (x_hash >> level) & LAST_W_BITS;
-            let y_index = (y_hash >> level) & LAST_W_BITS;
+        if level < bits {
+            let x_index = hash_index::<W>(x_hash, level);
+            let y_index = hash_index::<W>(y_hash, level);
             let x_flag = 1 << x_index;
             let y_flag = 1 << y_index;
             let bitmap = x_flag | y_flag;
@@ -57,8 +64,10 @@ where
                     prev: Atomic::null(),
                 },
                 cmp::Ordering::Equal => {
-                    let main = Self::new(x, x_hash, y, y_hash, level + W, generation.clone());
-                    let inode = IndirectionNode::new(Atomic::new(main), generation.clone());
+                    let main =
+                        Self::new::<W>(x, x_hash, y, y_hash, level + W, bits, generation.clone());
+                    let inode =
+                        Arc::new(IndirectionNode::new(Atomic::new(main), generation.clone()));
                     Self {
                         kind: MainNodeKind::Ctrie(CtrieNode::new(
                             bitmap,
@@ -70,7 +79,13 @@ where
                 }
             }
         } else {
-            unimplemented!()
+            // all hash bits have been used up, so the keys have colliding hashes, and only differ
+            // past `split` if there is a secondary hasher, which orders the list
+            if path_order::<W>(y_hash) < path_order::<W>(x_hash) {
+                Self::from_list_node(ListNode::new(x).prepended(y))
+            } else {
+                Self::from_list_node(ListNode::new(y).prepended(x))
+            }
         }
     }
 
@@ -110,16 +125,19 @@ where
         &self.prev
     }
 
-    pub fn print<'g>(&self, indent: usize, guard: &'g Guard)
+    #[cfg(feature = "std")]
+    pub fn print(&self, indent: usize, guard: &Guard)
     where
         K: Debug,
         V: Debug,
     {
-        let tab = std::iter::repeat(' ').take(indent).collect::<String>();
+        let tab = " ".repeat(indent);
         println!("{}main:", tab);
         match &self.kind {
             MainNodeKind::Ctrie(cnode) => cnode.print(indent, guard),
-            _ => unimplemented!(),
+            MainNodeKind::List(lnode) => lnode.print(indent, guard),
+            MainNodeKind::Tomb(tnode) => tnode.print(indent),
+            MainNodeKind::Failed => println!("{}failed", tab),
         }
     }
 }
@@ -0,0 +1,89 @@
+use crate::{
+    node::{IndirectionNode, MainNode},
+    Key, Value,
+};
+use core::sync::atomic::AtomicU8;
+use crossbeam::epoch::Atomic;
+
+/// The node that the root pointer of a ctrie points to.
+///
+/// Usually an i-node, but temporarily replaced by a descriptor while a RDCSS operation is in
+/// progress.
+pub enum RootNode<K, V> {
+    Indirection(IndirectionNode<K, V>),
+    Descriptor(RdcssDescriptor<K, V>),
+}
+
+impl<K, V> RootNode<K, V>
+where
+    K: Key,
+    V: Value,
+{
+    /// Returns the root i-node.
+    ///
+    /// Panics if this is a descriptor, which `rdcss_read_root` never returns.
+    pub fn inode(&self) -> &IndirectionNode<K, V> {
+        match self {
+            RootNode::Indirection(inode) => inode,
+            RootNode::Descriptor(_) => unreachable!("root descriptor was not completed"),
+        }
+    }
+}
+
+/// The decision of a RDCSS descriptor has not been made yet.
+pub const UNDECIDED: u8 = 0;
+
+/// The new root was installed.
+pub const COMMITTED: u8 = 1;
+
+/// The old root was put back.
+pub const ABORTED: u8 = 2;
+
+/// A restricted double-compare single-swap descriptor.
+///
+/// Replaces the root `old` with `new`, but only if the main node of `old` is `expected_main`.
+pub struct RdcssDescriptor<K, V> {
+    old: Atomic<RootNode<K, V>>,
+    expected_main: Atomic<MainNode<K, V>>,
+    new: Atomic<RootNode<K, V>>,
+    decision: AtomicU8,
+}
+
+impl<K, V> RdcssDescriptor<K, V>
+where
+    K: Key,
+    V: Value,
+{
+    pub fn new(
+        old: Atomic<RootNode<K, V>>,
+        expected_main: Atomic<MainNode<K, V>>,
+        new: Atomic<RootNode<K, V>>,
+    ) -> Self {
+        Self {
+            old,
+            expected_main,
+            new,
+            decision: AtomicU8::new(UNDECIDED),
+        }
+    }
+
+    pub fn old(&self) -> &Atomic<RootNode<K, V>> {
+        &self.old
+    }
+
+    pub fn expected_main(&self) -> &Atomic<MainNode<K, V>> {
+        &self.expected_main
+    }
+
+    pub fn new_root(&self) -> &Atomic<RootNode<K, V>> {
+        &self.new
+    }
+
+    /// Returns whether the swap was committed, aborted, or is still undecided.
+    ///
+    /// The decision is made exactly once, before the root pointer is changed, so every thread
+    /// completing the descriptor agrees on the outcome.
+    pub fn decision(&self) -> &AtomicU8 {
+        &self.decision
+    }
+}
@@ -2,15 +2,21 @@ use crate::{
     node::{MainNode, TombNode},
     Key, Value,
 };
-use std::fmt::Debug;
+#[cfg(feature = "std")]
+use core::fmt::Debug;
 
 /// A node that represents a single entry in a ctrie.
 ///
-/// Contains a key and a corresponding value.
+/// Contains a key, a corresponding value, and the hash of the key as far as it is known, so that
+/// moving the entry around the trie never has to hash the key again. That is the primary hash, and
+/// the rest of the whole hash once the entry has been placed where the secondary hash is used.
 #[derive(Clone)]
 pub struct SingletonNode<K, V> {
     key: K,
     value: V,
+    hash: u64,
+    // the high bits of the whole hash, or zero if they aren't known yet
+    high: u64,
 }
 
 impl<K, V> SingletonNode<K, V>
@@ -18,9 +24,20 @@ where
     K: Key,
     V: Value,
 {
-    /// Creates a new singleton node with the given key and value.
-    pub fn new(key: K, value: V) -> Self {
-        Self { key, value }
+    /// Creates a new singleton node with the given key, value, and hash of the key as far as it
+    /// is known.
+    pub fn new(key: K, value: V, hash: u128) -> Self {
+        Self {
+            key,
+            value,
+            hash: hash as u64,
+            high: (hash >> 64) as u64,
+        }
+    }
+
+    /// Returns a copy of the singleton node that knows the given hash of its key.
+    pub fn with_hash(&self, hash: u128) -> Self {
+        Self::new(self.key.clone(), self.value.clone(), hash)
     }
 
     /// Returns the key of the singleton node.
@@ -33,16 +50,28 @@ where
         &self.value
     }
 
+    /// Returns the primary hash of the key of the singleton node.
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    /// Returns the hash of the key of the singleton node as far as it is known, whose high bits
+    /// are zero until it is extended by the secondary hasher.
+    pub fn known_hash(&self) -> u128 {
+        u128::from(self.hash) | u128::from(self.high) << 64
+    }
+
     pub fn entomb(&self) -> MainNode<K, V> {
         MainNode::from_tomb_node(TombNode::new(self.clone()))
     }
 
+    #[cfg(feature = "std")]
     pub fn print(&self, indent: usize)
     where
         K: Debug,
         V: Debug,
     {
-        let tab = std::iter::repeat(' ').take(indent).collect::<String>();
+        let tab = " ".repeat(indent);
         println!("{}snode: ({:?}, {:?})", tab, self.key, self.value);
     }
 }
@@ -1,5 +1,11 @@
 use crate::{node::SingletonNode, Key, Value};
+#[cfg(feature = "std")]
+use core::fmt::Debug;
 
+/// A node that marks a singleton node as removed from its i-node.
+///
+/// The entombed singleton node is moved back up into the parent C-node when the parent is
+/// cleaned.
 #[derive(Clone)]
 pub struct TombNode<K, V> {
     snode: SingletonNode<K, V>,
@@ -14,7 +20,23 @@ where
         Self { snode }
     }
 
+    /// Returns the entombed singleton node.
+    pub fn snode(&self) -> &SingletonNode<K, V> {
+        &self.snode
+    }
+
     pub fn untombed(&self) -> SingletonNode<K, V> {
         self.snode.clone()
     }
+
+    #[cfg(feature = "std")]
+    pub fn print(&self, indent: usize)
+    where
+        K: Debug,
+        V: Debug,
+    {
+        let tab = " ".repeat(indent);
+        println!("{}tnode:", tab);
+        self.snode.print(indent + 2);
+    }
 }
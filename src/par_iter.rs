@@ -0,0 +1,241 @@
+//! Parallel iteration over snapshots with rayon.
+
+use crate::{
+    gcas::gcas_read,
+    node::{Branch, CtrieNode, IndirectionNode, MainNode, MainNodeKind, SingletonNode},
+    Ctrie, Key, Value,
+};
+use core::{hash::BuildHasher, marker::PhantomData, ops::Range};
+use crossbeam::epoch::{self, Guard};
+use rayon::iter::{
+    plumbing::{bridge_unindexed, Folder, UnindexedConsumer, UnindexedProducer},
+    ParallelIterator,
+};
+
+/// A parallel iterator over the entries of a ctrie.
+///
+/// Iterates over a read-only snapshot, so it is not affected by concurrent modifications. Work is
+/// split between threads at the branches of C-nodes, descending into a subtree when a single
+/// branch is left.
+pub struct ParIter<'g, K, V, S, const W: usize = 6, S2 = S> {
+    snapshot: Ctrie<K, V, S, W, S2>,
+    // the caller's guard isn't `Send`, so only its lifetime is kept, and every thread walking the
+    // snapshot pins its own
+    _guard: PhantomData<&'g ()>,
+}
+
+impl<'g, K, V, S, const W: usize, S2> ParIter<'g, K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    pub(crate) fn new(snapshot: Ctrie<K, V, S, W, S2>, _guard: &'g Guard) -> Self {
+        debug_assert!(snapshot.read_only());
+        Self {
+            snapshot,
+            _guard: PhantomData,
+        }
+    }
+}
+
+impl<'g, K, V, S, const W: usize, S2> ParallelIterator for ParIter<'g, K, V, S, W, S2>
+where
+    K: Key + Send + Sync + 'g,
+    V: Value + Send + Sync + 'g,
+    S: BuildHasher + Send + Sync,
+    S2: BuildHasher + Send + Sync,
+{
+    type Item = (&'g K, &'g V);
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result
+    where
+        C: UnindexedConsumer<Self::Item>,
+    {
+        let guard = &epoch::pin();
+        let main = extend(unsafe {
+            gcas_read(self.snapshot.read_root(guard), &self.snapshot, guard).deref()
+        });
+        let cnode = match main.kind() {
+            MainNodeKind::Ctrie(cnode) => cnode,
+            _ => unreachable!("the root main node is always a c-node"),
+        };
+        let producer = Producer {
+            snapshot: &self.snapshot,
+            cnode,
+            range: 0..cnode.branches(),
+        };
+        bridge_unindexed(producer, consumer)
+    }
+}
+
+/// Extends the lifetime of a node read with a thread's own guard to that of the caller's guard.
+///
+/// Nodes are never freed while a guard is pinned, and the caller's guard stays pinned as long as
+/// the iterator or its items are in use.
+fn extend<'g, T>(node: &T) -> &'g T {
+    unsafe { &*(node as *const T) }
+}
+
+/// A range of branches of a C-node in a snapshot.
+struct Producer<'a, 'g, K, V, S, const W: usize, S2> {
+    snapshot: &'a Ctrie<K, V, S, W, S2>,
+    cnode: &'g CtrieNode<K, V>,
+    range: Range<usize>,
+}
+
+impl<'a, 'g, K, V, S, const W: usize, S2> Producer<'a, 'g, K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    fn main(&self, inode: &IndirectionNode<K, V>, guard: &Guard) -> &'g MainNode<K, V> {
+        extend(unsafe { gcas_read(inode, self.snapshot, guard).deref() })
+    }
+
+    fn fold_main<F>(&self, main: &'g MainNode<K, V>, mut folder: F, guard: &Guard) -> F
+    where
+        F: Folder<(&'g K, &'g V)>,
+    {
+        match main.kind() {
+            MainNodeKind::Ctrie(cnode) => {
+                for position in 0..cnode.branches() {
+                    if folder.full() {
+                        break;
+                    }
+                    folder = self.fold_branch(cnode.branch(position), folder, guard);
+                }
+                folder
+            }
+            MainNodeKind::List(lnode) => folder.consume_iter(
+                lnode
+                    .entries(guard)
+                    .map(|snode| entry(extend::<SingletonNode<K, V>>(snode))),
+            ),
+            MainNodeKind::Tomb(tnode) => folder.consume(entry(tnode.snode())),
+            MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
+        }
+    }
+
+    fn fold_branch<F>(&self, branch: &'g Branch<K, V>, folder: F, guard: &Guard) -> F
+    where
+        F: Folder<(&'g K, &'g V)>,
+    {
+        match branch {
+            Branch::Singleton(snode) => folder.consume(entry(snode)),
+            Branch::Indirection(inode) => self.fold_main(self.main(inode, guard), folder, guard),
+        }
+    }
+}
+
+fn entry<K: Key, V: Value>(snode: &SingletonNode<K, V>) -> (&K, &V) {
+    (snode.key(), snode.value())
+}
+
+impl<'a, 'g, K, V, S, const W: usize, S2> UnindexedProducer for Producer<'a, 'g, K, V, S, W, S2>
+where
+    K: Key + Send + Sync + 'g,
+    V: Value + Send + Sync + 'g,
+    S: BuildHasher + Send + Sync,
+    S2: BuildHasher + Send + Sync,
+{
+    type Item = (&'g K, &'g V);
+
+    fn split(mut self) -> (Self, Option<Self>) {
+        let guard = &epoch::pin();
+        loop {
+            let Range { start, end } = self.range;
+            if end - start > 1 {
+                let middle = start + (end - start) / 2;
+                let other = Self {
+                    snapshot: self.snapshot,
+                    cnode: self.cnode,
+                    range: middle..end,
+                };
+                self.range = start..middle;
+                return (self, Some(other));
+            }
+            // a single branch can still be split if it leads to another c-node
+            let inode = match self
+                .range
+                .clone()
+                .next()
+                .map(|position| self.cnode.branch(position))
+            {
+                Some(Branch::Indirection(inode)) => inode,
+                _ => return (self, None),
+            };
+            match self.main(inode, guard).kind() {
+                MainNodeKind::Ctrie(cnode) => {
+                    self.cnode = cnode;
+                    self.range = 0..cnode.branches();
+                }
+                _ => return (self, None),
+            }
+        }
+    }
+
+    fn fold_with<F>(self, mut folder: F) -> F
+    where
+        F: Folder<Self::Item>,
+    {
+        let guard = &epoch::pin();
+        for position in self.range.clone() {
+            if folder.full() {
+                break;
+            }
+            folder = self.fold_branch(self.cnode.branch(position), folder, guard);
+        }
+        folder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::TinyHasher, Ctrie};
+    use core::hash::BuildHasherDefault;
+    use crossbeam::epoch;
+    use rayon::iter::ParallelIterator;
+    use std::vec::Vec;
+
+    #[test]
+    fn par_iter() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..10_000u64 {
+            ctrie.insert(i, i * 2, guard);
+        }
+        let iter = ctrie.par_iter(guard);
+        // later writes aren't seen
+        ctrie.insert(10_000, 0, guard);
+        ctrie.remove(&0, guard);
+
+        let mut entries = iter.map(|(&k, &v)| (k, v)).collect::<Vec<_>>();
+        entries.sort();
+        assert_eq!(entries, (0..10_000).map(|i| (i, i * 2)).collect::<Vec<_>>());
+        assert_eq!(
+            ctrie.par_iter(guard).map(|(_, &v)| v).sum::<u64>(),
+            ctrie.iter(guard).map(|(_, &v)| v).sum::<u64>()
+        );
+        assert!(ctrie.par_iter(guard).any(|(&k, _)| k == 10_000));
+    }
+
+    #[test]
+    fn par_iter_collisions() {
+        let ctrie = Ctrie::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        let guard = &epoch::pin();
+        for i in 0..500u32 {
+            ctrie.insert(i, i, guard);
+        }
+        for i in (0..500).step_by(3) {
+            ctrie.remove(&i, guard);
+        }
+
+        let mut keys = ctrie.par_iter(guard).map(|(&k, _)| k).collect::<Vec<_>>();
+        keys.sort_unstable();
+        assert_eq!(keys, (0..500).filter(|i| i % 3 != 0).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,409 @@
+//! A compact binary format for ctrie snapshots.
+//!
+//! | field       | encoding                                             |
+//! |-------------|------------------------------------------------------|
+//! | magic       | the bytes `CTRI`                                     |
+//! | version     | `u32`                                                |
+//! | entry count | `u64`                                                |
+//! | entries     | key length (`u32`), key, value length (`u32`), value |
+//! | checksum    | `u32`, the CRC-32 of everything before it            |
+//!
+//! All integers are little-endian, and keys and values are encoded with their `Codec` impls.
+
+pub mod change_set;
+pub mod checkpoint;
+pub mod incremental;
+pub mod wal;
+
+use crate::{checksum::Crc32, codec::Codec, Ctrie, CtrieBuilder, Key, Value};
+use core::{convert::TryFrom, fmt, hash::BuildHasher};
+use crossbeam::epoch;
+use std::{
+    error::Error,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+const MAGIC: &[u8; 4] = b"CTRI";
+
+/// The version of the format written by this version of the crate.
+pub const VERSION: u32 = 1;
+
+/// An error that occurred while saving or loading a snapshot.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The underlying reader or writer failed.
+    Io(io::Error),
+    /// The input doesn't start with the snapshot magic bytes.
+    BadMagic,
+    /// The snapshot was written in a format version this crate can't read.
+    UnsupportedVersion(u32),
+    /// The input ended before the end of the snapshot.
+    Truncated,
+    /// The checksum doesn't match the contents of the snapshot.
+    ChecksumMismatch,
+    /// A key or value couldn't be decoded.
+    InvalidEntry,
+    /// A page of an incremental snapshot is malformed, or was written with a different hasher.
+    InvalidPage,
+    /// A page of an incremental snapshot isn't in any of the segments loaded so far.
+    MissingPage(u128),
+    /// The segments of an incremental snapshot are missing or out of order.
+    BrokenChain { expected: u64, found: u64 },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "i/o error: {}", err),
+            SnapshotError::BadMagic => f.write_str("not a ctrie snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::Truncated => f.write_str("snapshot is truncated"),
+            SnapshotError::ChecksumMismatch => f.write_str("snapshot checksum mismatch"),
+            SnapshotError::InvalidEntry => f.write_str("snapshot contains an invalid entry"),
+            SnapshotError::InvalidPage => f.write_str("snapshot contains an invalid page"),
+            SnapshotError::MissingPage(id) => write!(f, "snapshot page {:032x} is missing", id),
+            SnapshotError::BrokenChain { expected, found } => write!(
+                f,
+                "expected snapshot segment {}, found segment {}",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SnapshotError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            SnapshotError::Truncated
+        } else {
+            SnapshotError::Io(err)
+        }
+    }
+}
+
+/// A writer that keeps a checksum of everything written through it.
+pub(crate) struct ChecksumWriter<W> {
+    inner: W,
+    crc: Crc32,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            crc: Crc32::new(),
+        }
+    }
+
+    /// Writes the checksum of everything written so far, and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let checksum = self.crc.finish();
+        self.inner.write_all(&checksum.to_le_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader that keeps a checksum of everything read through it.
+pub(crate) struct ChecksumReader<R> {
+    inner: R,
+    crc: Crc32,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            crc: Crc32::new(),
+        }
+    }
+
+    /// Reads a checksum and compares it to the checksum of everything read so far.
+    pub fn finish(mut self) -> Result<R, SnapshotError> {
+        let expected = self.crc.finish();
+        let mut checksum = [0; 4];
+        self.inner.read_exact(&mut checksum)?;
+        if u32::from_le_bytes(checksum) == expected {
+            Ok(self.inner)
+        } else {
+            Err(SnapshotError::ChecksumMismatch)
+        }
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.crc.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+pub(crate) fn write_u32<W: Write>(writer: &mut W, n: u32) -> io::Result<()> {
+    writer.write_all(&n.to_le_bytes())
+}
+
+pub(crate) fn write_u64<W: Write>(writer: &mut W, n: u64) -> io::Result<()> {
+    writer.write_all(&n.to_le_bytes())
+}
+
+pub(crate) fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Writes a value as its length followed by its encoding, using `buf` as scratch space.
+pub(crate) fn write_encoded<W: Write, T: Codec>(
+    writer: &mut W,
+    value: &T,
+    buf: &mut Vec<u8>,
+) -> io::Result<()> {
+    buf.clear();
+    value.encode(buf);
+    let len = u32::try_from(buf.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "encoding is too long"))?;
+    write_u32(writer, len)?;
+    writer.write_all(buf)
+}
+
+/// Reads a value written by `write_encoded`, using `buf` as scratch space.
+pub(crate) fn read_encoded<R: Read, T: Codec>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> Result<T, SnapshotError> {
+    let len = read_u32(reader)?;
+    buf.clear();
+    // read through `take`, so a corrupted length can't make us allocate a huge buffer up front
+    reader.take(u64::from(len)).read_to_end(buf)?;
+    if buf.len() != len as usize {
+        return Err(SnapshotError::Truncated);
+    }
+    T::decode(buf).ok_or(SnapshotError::InvalidEntry)
+}
+
+/// Writes the magic bytes and version of a format.
+pub(crate) fn write_header<W: Write>(
+    writer: &mut W,
+    magic: &[u8; 4],
+    version: u32,
+) -> io::Result<()> {
+    writer.write_all(magic)?;
+    write_u32(writer, version)
+}
+
+/// Reads and checks the magic bytes and version of a format.
+pub(crate) fn read_header<R: Read>(
+    reader: &mut R,
+    magic: &[u8; 4],
+    version: u32,
+) -> Result<(), SnapshotError> {
+    let mut found = [0; 4];
+    reader.read_exact(&mut found)?;
+    if &found != magic {
+        return Err(SnapshotError::BadMagic);
+    }
+    let found = read_u32(reader)?;
+    if found != version {
+        return Err(SnapshotError::UnsupportedVersion(found));
+    }
+    Ok(())
+}
+
+/// Syncs the directory containing `path`, so a rename or creation in it is durable.
+pub(crate) fn sync_parent(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Writes a snapshot of `ctrie` to a temporary file next to `path`, and atomically renames it to
+/// `path`.
+pub(crate) fn write_snapshot_file<K, V, S, const W: usize, S2>(
+    ctrie: &Ctrie<K, V, S, W, S2>,
+    path: &Path,
+) -> Result<(), SnapshotError>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+    S: BuildHasher + Clone,
+    S2: BuildHasher + Clone,
+{
+    let mut tmp = OsString::from(path);
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    ctrie.save_snapshot(&mut writer)?;
+    let file = writer.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_parent(path)?;
+    Ok(())
+}
+
+impl<K, V, S, const W: usize, S2> Ctrie<K, V, S, W, S2>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    /// Writes the contents of the ctrie to `writer` in the binary snapshot format.
+    ///
+    /// The contents are written from a read-only snapshot, so the ctrie can be modified while the
+    /// snapshot is written. Writes are small, so `writer` should be buffered.
+    pub fn save_snapshot<O: Write>(&self, writer: O) -> Result<(), SnapshotError>
+    where
+        S: Clone,
+        S2: Clone,
+    {
+        let guard = &epoch::pin();
+        let snapshot = self.read_only_snapshot(guard);
+        let count = snapshot.iter(guard).count();
+
+        let mut writer = ChecksumWriter::new(writer);
+        write_header(&mut writer, MAGIC, VERSION)?;
+        write_u64(&mut writer, count as u64)?;
+        let mut buf = Vec::new();
+        for (key, value) in snapshot.iter(guard) {
+            write_encoded(&mut writer, key, &mut buf)?;
+            write_encoded(&mut writer, value, &mut buf)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+impl<K, V, S, const W: usize> Ctrie<K, V, S, W>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+    S: BuildHasher,
+{
+    /// Reads a ctrie written by `save_snapshot`, using the given hasher.
+    pub fn load_with_hasher<R: Read>(reader: R, hash_builder: S) -> Result<Self, SnapshotError> {
+        let mut reader = ChecksumReader::new(reader);
+        read_header(&mut reader, MAGIC, VERSION)?;
+        let count = read_u64(&mut reader)?;
+
+        let mut builder = CtrieBuilder::with_width(hash_builder);
+        let mut buf = Vec::new();
+        for _ in 0..count {
+            let key = read_encoded(&mut reader, &mut buf)?;
+            let value = read_encoded(&mut reader, &mut buf)?;
+            builder.insert(key, value);
+        }
+        reader.finish()?;
+        Ok(builder.build())
+    }
+
+    /// Reads a ctrie written by `save_snapshot`.
+    pub fn load<R: Read>(reader: R) -> Result<Self, SnapshotError>
+    where
+        S: Default,
+    {
+        Self::load_with_hasher(reader, S::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved() -> Vec<u8> {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..50u32 {
+            ctrie.insert(i, format!("value {}", i), guard);
+        }
+        let mut bytes = vec![];
+        ctrie.save_snapshot(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn save_load() {
+        let ctrie: Ctrie<u32, String> = Ctrie::load(&saved()[..]).unwrap();
+        let guard = &epoch::pin();
+        for i in 0..50u32 {
+            assert_eq!(ctrie.lookup(&i, guard), Some(&format!("value {}", i)));
+        }
+        assert_eq!(ctrie.validate(guard), Ok(50));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = saved();
+        for len in 0..bytes.len() {
+            match Ctrie::<u32, String>::load(&bytes[..len]) {
+                Err(SnapshotError::Truncated) => {}
+                other => panic!("expected truncation at {}, got {:?}", len, other.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted() {
+        let mut bytes = saved();
+        bytes[0] = b'X';
+        assert!(matches!(
+            Ctrie::<u32, String>::load(&bytes[..]),
+            Err(SnapshotError::BadMagic)
+        ));
+
+        let mut bytes = saved();
+        bytes[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Ctrie::<u32, String>::load(&bytes[..]),
+            Err(SnapshotError::UnsupportedVersion(version)) if version == VERSION + 1
+        ));
+
+        let mut bytes = saved();
+        let last = bytes.len() - 5;
+        bytes[last] ^= 1;
+        assert!(matches!(
+            Ctrie::<u32, String>::load(&bytes[..]),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+    }
+}
@@ -0,0 +1,235 @@
+//! An encoding of the changes between two snapshots, for shipping them to replicas.
+//!
+//! A leader diffs successive snapshots of its ctrie and writes each diff as a change set, and a
+//! follower that starts from the same contents and applies the change sets in order ends up with
+//! the same contents as the leader. Change sets are self-delimiting, so several can be written
+//! back to back to a file or socket.
+//!
+//! | field    | encoding                                                         |
+//! |----------|------------------------------------------------------------------|
+//! | magic    | the bytes `CTRC`                                                 |
+//! | version  | `u32`                                                            |
+//! | sequence | `u64`, chosen by the leader to let followers detect gaps         |
+//! | count    | `u64`, the number of changes                                     |
+//! | changes  | for each change, a tag, the key, and the value or values         |
+//! | checksum | `u32`, the CRC-32 of everything before it                        |
+//!
+//! An addition is tagged `0u8` and followed by its value, a removal is tagged `1u8` and followed
+//! by the removed value, and an update is tagged `2u8` and followed by the old and new values.
+//! Keys and values are encoded like in the full snapshot format.
+
+use crate::{
+    codec::Codec,
+    persist::{
+        read_encoded, read_header, read_u64, write_encoded, write_header, write_u64,
+        ChecksumReader, ChecksumWriter, SnapshotError,
+    },
+    Change, Ctrie, Key, Value,
+};
+use crossbeam::epoch::Guard;
+use std::{
+    hash::BuildHasher,
+    io::{Read, Write},
+};
+
+const MAGIC: &[u8; 4] = b"CTRC";
+
+/// The version of the change set format written by this version of the crate.
+pub const VERSION: u32 = 1;
+
+const ADDED: u8 = 0;
+const REMOVED: u8 = 1;
+const UPDATED: u8 = 2;
+
+/// A sequence of changes that can be written to and read from a byte stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeSet<K, V> {
+    /// The position of the change set in the stream of change sets sent by a leader.
+    pub sequence: u64,
+    /// The changes, in the order they should be applied.
+    pub changes: Vec<Change<K, V>>,
+}
+
+impl<K, V> ChangeSet<K, V>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+{
+    /// Returns the changes that turn a read-only snapshot of `old` into a read-only snapshot of
+    /// `new`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `old` and `new` hash keys differently.
+    pub fn diff<S, const W: usize, S2>(
+        old: &Ctrie<K, V, S, W, S2>,
+        new: &Ctrie<K, V, S, W, S2>,
+        sequence: u64,
+        guard: &Guard,
+    ) -> Self
+    where
+        V: PartialEq,
+        S: BuildHasher + Clone,
+        S2: BuildHasher + Clone,
+    {
+        Self {
+            sequence,
+            changes: old.diff(new, guard).collect(),
+        }
+    }
+
+    /// Writes the change set to `writer`.
+    ///
+    /// Writes are small, so `writer` should be buffered.
+    pub fn write<W: Write>(&self, writer: W) -> Result<(), SnapshotError> {
+        let mut writer = ChecksumWriter::new(writer);
+        write_header(&mut writer, MAGIC, VERSION)?;
+        write_u64(&mut writer, self.sequence)?;
+        write_u64(&mut writer, self.changes.len() as u64)?;
+        let mut buf = Vec::new();
+        for change in &self.changes {
+            match change {
+                Change::Added { key, value } => {
+                    writer.write_all(&[ADDED])?;
+                    write_encoded(&mut writer, key, &mut buf)?;
+                    write_encoded(&mut writer, value, &mut buf)?;
+                }
+                Change::Removed { key, value } => {
+                    writer.write_all(&[REMOVED])?;
+                    write_encoded(&mut writer, key, &mut buf)?;
+                    write_encoded(&mut writer, value, &mut buf)?;
+                }
+                Change::Updated { key, old, new } => {
+                    writer.write_all(&[UPDATED])?;
+                    write_encoded(&mut writer, key, &mut buf)?;
+                    write_encoded(&mut writer, old, &mut buf)?;
+                    write_encoded(&mut writer, new, &mut buf)?;
+                }
+            }
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Reads a change set written by `write`.
+    ///
+    /// Reads exactly the bytes of one change set, so the next one can be read from the same
+    /// reader.
+    pub fn read<R: Read>(reader: R) -> Result<Self, SnapshotError> {
+        let mut reader = ChecksumReader::new(reader);
+        read_header(&mut reader, MAGIC, VERSION)?;
+        let sequence = read_u64(&mut reader)?;
+        let count = read_u64(&mut reader)?;
+
+        // don't trust the count for the initial capacity, since it hasn't been checked yet
+        let mut changes = Vec::new();
+        let mut buf = Vec::new();
+        for _ in 0..count {
+            let mut tag = [0];
+            reader.read_exact(&mut tag)?;
+            let change = match tag[0] {
+                ADDED => Change::Added {
+                    key: read_encoded(&mut reader, &mut buf)?,
+                    value: read_encoded(&mut reader, &mut buf)?,
+                },
+                REMOVED => Change::Removed {
+                    key: read_encoded(&mut reader, &mut buf)?,
+                    value: read_encoded(&mut reader, &mut buf)?,
+                },
+                UPDATED => Change::Updated {
+                    key: read_encoded(&mut reader, &mut buf)?,
+                    old: read_encoded(&mut reader, &mut buf)?,
+                    new: read_encoded(&mut reader, &mut buf)?,
+                },
+                _ => return Err(SnapshotError::InvalidEntry),
+            };
+            changes.push(change);
+        }
+        reader.finish()?;
+        Ok(Self { sequence, changes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::epoch;
+
+    #[test]
+    fn replicate() {
+        let leader = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..100u32 {
+            leader.insert(i, format!("{}", i), guard);
+        }
+
+        let mut bytes = vec![];
+        leader.save_snapshot(&mut bytes).unwrap();
+        let follower = Ctrie::load_with_hasher(&bytes[..], leader.hasher().clone()).unwrap();
+
+        // ship two rounds of changes through one stream
+        let mut stream = vec![];
+        let mut previous = leader.read_only_snapshot(guard);
+        for sequence in 0..2 {
+            for i in 0..20u32 {
+                let key = i * 7 + sequence as u32;
+                if i % 2 == 0 {
+                    leader.remove(&key, guard);
+                } else {
+                    leader.insert(key + 100, format!("{} {}", key, sequence), guard);
+                }
+            }
+            leader.insert(50, format!("updated {}", sequence), guard);
+            let current = leader.read_only_snapshot(guard);
+            ChangeSet::diff(&previous, &current, sequence, guard)
+                .write(&mut stream)
+                .unwrap();
+            previous = current;
+        }
+
+        let mut reader = &stream[..];
+        for sequence in 0..2 {
+            let set = ChangeSet::read(&mut reader).unwrap();
+            assert_eq!(set.sequence, sequence);
+            follower.apply(set.changes, guard);
+        }
+        assert!(reader.is_empty());
+        assert_eq!(leader.diff(&follower, guard).count(), 0);
+        assert_eq!(follower.validate(guard), leader.validate(guard));
+    }
+
+    #[test]
+    fn corrupted() {
+        let set = ChangeSet {
+            sequence: 3,
+            changes: vec![
+                Change::Added {
+                    key: 1u32,
+                    value: 2u32,
+                },
+                Change::Removed { key: 3, value: 4 },
+                Change::Updated {
+                    key: 5,
+                    old: 6,
+                    new: 7,
+                },
+            ],
+        };
+        let mut bytes = vec![];
+        set.write(&mut bytes).unwrap();
+        assert_eq!(ChangeSet::read(&bytes[..]).unwrap(), set);
+
+        for len in 0..bytes.len() {
+            assert!(matches!(
+                ChangeSet::<u32, u32>::read(&bytes[..len]),
+                Err(SnapshotError::Truncated)
+            ));
+        }
+        // flip a bit of the sequence number
+        bytes[8] ^= 1;
+        assert!(matches!(
+            ChangeSet::<u32, u32>::read(&bytes[..]),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+    }
+}
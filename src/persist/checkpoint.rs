@@ -0,0 +1,428 @@
+//! Periodic checkpoints of a ctrie, written by a background thread.
+//!
+//! Each checkpoint is a full snapshot written to `checkpoint-<sequence>.ctrie` in a directory,
+//! where the sequence number grows by one with every checkpoint. A checkpoint is first written to
+//! a temporary file and then renamed into place, so a crash never leaves a partial checkpoint
+//! behind, and only the most recent ones are kept. Temporary files left by a crash are deleted by
+//! the next checkpointer started on the directory.
+
+use crate::{
+    codec::Codec,
+    persist::{write_snapshot_file, SnapshotError},
+    Ctrie, Key, Value,
+};
+use crossbeam::epoch::Guard;
+use std::{
+    fs,
+    hash::BuildHasher,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const PREFIX: &str = "checkpoint-";
+const SUFFIX: &str = ".ctrie";
+const TMP_SUFFIX: &str = ".tmp";
+
+/// When a `Checkpointer` writes checkpoints, and how many it keeps.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointOptions {
+    /// Write a checkpoint after this much time has passed since the last one.
+    pub interval: Option<Duration>,
+    /// Write a checkpoint after this many mutations have been recorded since the last one.
+    pub mutations: Option<usize>,
+    /// The number of checkpoints to keep. Older ones are deleted. At least one is always kept.
+    pub keep: usize,
+}
+
+impl Default for CheckpointOptions {
+    fn default() -> Self {
+        Self {
+            interval: Some(Duration::from_secs(60)),
+            mutations: None,
+            keep: 3,
+        }
+    }
+}
+
+/// Returns the sequence number of a checkpoint file name.
+fn parse_sequence(name: &str) -> Option<u64> {
+    let digits = name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// Returns the checkpoints in `dir`, oldest first.
+fn checkpoints(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut checkpoints = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(sequence) = entry.file_name().to_str().and_then(parse_sequence) {
+            checkpoints.push((sequence, entry.path()));
+        }
+    }
+    checkpoints.sort();
+    Ok(checkpoints)
+}
+
+/// Deletes the temporary files of checkpoints that were never finished.
+fn remove_partial(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let partial = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(TMP_SUFFIX))
+            .and_then(parse_sequence)
+            .is_some();
+        if partial {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the path of the most recent checkpoint written to `dir` by a `Checkpointer`, if any.
+pub fn latest_checkpoint<P: AsRef<Path>>(dir: P) -> io::Result<Option<PathBuf>> {
+    Ok(checkpoints(dir.as_ref())?.pop().map(|(_, path)| path))
+}
+
+struct Inner<K, V, S, const W: usize, S2> {
+    ctrie: Arc<Ctrie<K, V, S, W, S2>>,
+    dir: PathBuf,
+    options: CheckpointOptions,
+    // mutations recorded since the last checkpoint
+    mutations: AtomicUsize,
+    state: Mutex<State>,
+    wake: Condvar,
+    // the sequence number of the next checkpoint, locked while a checkpoint is written
+    sequence: Mutex<u64>,
+}
+
+struct State {
+    stopping: bool,
+    error: Option<SnapshotError>,
+}
+
+impl<K, V, S, const W: usize, S2> Inner<K, V, S, W, S2>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+    S: BuildHasher + Clone,
+    S2: BuildHasher + Clone,
+{
+    fn due(&self) -> bool {
+        match self.options.mutations {
+            Some(n) => self.mutations.load(Ordering::Relaxed) >= n,
+            None => false,
+        }
+    }
+
+    fn checkpoint(&self) -> Result<PathBuf, SnapshotError> {
+        let mut sequence = self.sequence.lock().unwrap();
+        // mutations recorded from here on may not be in the snapshot, so count them towards the
+        // next checkpoint
+        self.mutations.store(0, Ordering::Relaxed);
+        let path = self
+            .dir
+            .join(format!("{}{:020}{}", PREFIX, *sequence, SUFFIX));
+        write_snapshot_file(&*self.ctrie, &path)?;
+        *sequence += 1;
+
+        let checkpoints = checkpoints(&self.dir)?;
+        let stale = checkpoints.len().saturating_sub(self.options.keep.max(1));
+        for (_, path) in &checkpoints[..stale] {
+            fs::remove_file(path)?;
+        }
+        remove_partial(&self.dir)?;
+        Ok(path)
+    }
+
+    fn run(&self) {
+        let mut last = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        while !state.stopping {
+            let elapsed = last.elapsed();
+            let timed_out = matches!(self.options.interval, Some(interval) if elapsed >= interval);
+            if timed_out || self.due() {
+                drop(state);
+                let result = self.checkpoint();
+                last = Instant::now();
+                state = self.state.lock().unwrap();
+                if let Err(err) = result {
+                    state.error = Some(err);
+                }
+                continue;
+            }
+            state = match self.options.interval {
+                Some(interval) => self.wake.wait_timeout(state, interval - elapsed).unwrap().0,
+                None => self.wake.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+/// Writes checkpoints of a ctrie from a background thread, without stopping writers.
+///
+/// Each checkpoint is written from a read-only snapshot, so the ctrie can be modified while it is
+/// written. Mutations made through the checkpointer are counted towards the `mutations` trigger;
+/// mutations made through another handle to the ctrie can be counted with `record_mutations`.
+pub struct Checkpointer<K, V, S, const W: usize = 6, S2 = S>
+where
+    K: Key + Codec + Send + Sync + 'static,
+    V: Value + Codec + Send + Sync + 'static,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+    S2: BuildHasher + Clone + Send + Sync + 'static,
+{
+    inner: Arc<Inner<K, V, S, W, S2>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<K, V, S, const W: usize, S2> Checkpointer<K, V, S, W, S2>
+where
+    K: Key + Codec + Send + Sync + 'static,
+    V: Value + Codec + Send + Sync + 'static,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+    S2: BuildHasher + Clone + Send + Sync + 'static,
+{
+    /// Starts writing checkpoints of `ctrie` to `dir`, which is created if it doesn't exist.
+    ///
+    /// Sequence numbers continue from the checkpoints already in `dir`, and the temporary files of
+    /// checkpoints that were interrupted by a crash are deleted.
+    pub fn start<P: AsRef<Path>>(
+        ctrie: Arc<Ctrie<K, V, S, W, S2>>,
+        dir: P,
+        options: CheckpointOptions,
+    ) -> io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+        remove_partial(&dir)?;
+        let sequence = checkpoints(&dir)?
+            .last()
+            .map_or(0, |&(sequence, _)| sequence + 1);
+        let inner = Arc::new(Inner {
+            ctrie,
+            dir,
+            options,
+            mutations: AtomicUsize::new(0),
+            state: Mutex::new(State {
+                stopping: false,
+                error: None,
+            }),
+            wake: Condvar::new(),
+            sequence: Mutex::new(sequence),
+        });
+        let thread = {
+            let inner = inner.clone();
+            thread::Builder::new()
+                .name("ctrie-checkpointer".to_owned())
+                .spawn(move || inner.run())?
+        };
+        Ok(Self {
+            inner,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the ctrie being checkpointed.
+    pub fn ctrie(&self) -> &Arc<Ctrie<K, V, S, W, S2>> {
+        &self.inner.ctrie
+    }
+
+    /// Inserts a key-value pair, and counts it as a mutation.
+    pub fn insert(&self, key: K, value: V, guard: &Guard) {
+        self.inner.ctrie.insert(key, value, guard);
+        self.record_mutations(1);
+    }
+
+    /// Removes a key, and counts it as a mutation.
+    pub fn remove<'g>(&self, key: &K, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: 'g,
+    {
+        let value = self.inner.ctrie.remove(key, guard);
+        self.record_mutations(1);
+        value
+    }
+
+    /// Counts mutations made to the ctrie through another handle.
+    pub fn record_mutations(&self, n: usize) {
+        let before = self.inner.mutations.fetch_add(n, Ordering::Relaxed);
+        if let Some(threshold) = self.inner.options.mutations {
+            if before < threshold && before + n >= threshold {
+                // lock the state so the wakeup can't slip in between the thread's check and wait
+                let _state = self.inner.state.lock().unwrap();
+                self.inner.wake.notify_one();
+            }
+        }
+    }
+
+    /// Writes a checkpoint now, on the calling thread, and returns its path.
+    pub fn checkpoint(&self) -> Result<PathBuf, SnapshotError> {
+        self.inner.checkpoint()
+    }
+
+    /// Returns the error of the last checkpoint written by the background thread that failed, if
+    /// it hasn't been taken yet.
+    pub fn take_error(&self) -> Option<SnapshotError> {
+        self.inner.state.lock().unwrap().error.take()
+    }
+
+    /// Stops the background thread, and writes a final checkpoint.
+    pub fn shutdown(mut self) -> Result<PathBuf, SnapshotError> {
+        self.stop();
+        self.inner.checkpoint()
+    }
+
+    fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.inner.state.lock().unwrap().stopping = true;
+            self.inner.wake.notify_one();
+            // a panic on the thread has already been reported, and there's nothing to clean up
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<K, V, S, const W: usize, S2> Drop for Checkpointer<K, V, S, W, S2>
+where
+    K: Key + Codec + Send + Sync + 'static,
+    V: Value + Codec + Send + Sync + 'static,
+    S: BuildHasher + Clone + Send + Sync + 'static,
+    S2: BuildHasher + Clone + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::epoch;
+
+    fn wait_for_sequence(dir: &Path, sequence: u64) {
+        let start = Instant::now();
+        while !matches!(checkpoints(dir).unwrap().last(), Some(&(last, _)) if last >= sequence) {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "no checkpoint written"
+            );
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn load(path: &Path) -> Ctrie<u32, u32> {
+        Ctrie::load(fs::File::open(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn after_mutations() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = CheckpointOptions {
+            interval: None,
+            mutations: Some(10),
+            keep: 3,
+        };
+        let checkpointer =
+            Checkpointer::start(Arc::new(Ctrie::new()), dir.path(), options).unwrap();
+        let guard = &epoch::pin();
+        for i in 0..9u32 {
+            checkpointer.insert(i, i, guard);
+        }
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(latest_checkpoint(dir.path()).unwrap(), None);
+
+        checkpointer.insert(9, 9, guard);
+        wait_for_sequence(dir.path(), 0);
+        let latest = latest_checkpoint(dir.path()).unwrap().unwrap();
+        assert_eq!(load(&latest).validate(guard), Ok(10));
+        assert!(checkpointer.take_error().is_none());
+    }
+
+    #[test]
+    fn on_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = CheckpointOptions {
+            interval: Some(Duration::from_millis(10)),
+            mutations: None,
+            keep: 2,
+        };
+        let ctrie = Arc::new(Ctrie::new());
+        let checkpointer = Checkpointer::start(ctrie.clone(), dir.path(), options).unwrap();
+        let guard = &epoch::pin();
+        ctrie.insert(1, 1, guard);
+        checkpointer.record_mutations(1);
+        wait_for_sequence(dir.path(), 3);
+        drop(checkpointer);
+
+        // only the most recent checkpoints are kept
+        let kept = checkpoints(dir.path()).unwrap();
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0].0 + 1, kept[1].0);
+        assert_eq!(load(&kept[1].1).validate(guard), Ok(1));
+    }
+
+    #[test]
+    fn resume_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = CheckpointOptions {
+            interval: None,
+            mutations: None,
+            keep: 1,
+        };
+        let guard = &epoch::pin();
+        let checkpointer =
+            Checkpointer::start(Arc::new(Ctrie::new()), dir.path(), options.clone()).unwrap();
+        checkpointer.insert(1, 1, guard);
+        checkpointer.checkpoint().unwrap();
+        checkpointer.insert(2, 2, guard);
+        let last = checkpointer.shutdown().unwrap();
+        assert_eq!(latest_checkpoint(dir.path()).unwrap(), Some(last.clone()));
+        assert_eq!(checkpoints(dir.path()).unwrap().len(), 1);
+
+        let ctrie = Arc::new(load(&last));
+        assert_eq!(ctrie.validate(guard), Ok(2));
+        let checkpointer = Checkpointer::start(ctrie, dir.path(), options).unwrap();
+        let path = checkpointer.checkpoint().unwrap();
+        assert_eq!(
+            path.file_name().unwrap().to_str().and_then(parse_sequence),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn remove_partial_checkpoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = CheckpointOptions {
+            interval: None,
+            mutations: None,
+            keep: 1,
+        };
+        // the temporary file of a checkpoint interrupted by a crash, and an unrelated file
+        let partial = dir.path().join("checkpoint-00000000000000000007.ctrie.tmp");
+        let unrelated = dir.path().join("notes.tmp");
+        fs::write(&partial, b"partial").unwrap();
+        fs::write(&unrelated, b"notes").unwrap();
+
+        let checkpointer =
+            Checkpointer::start(Arc::new(Ctrie::new()), dir.path(), options).unwrap();
+        assert!(!partial.exists());
+        assert!(unrelated.exists());
+
+        // one left behind while the checkpointer runs is deleted when old checkpoints are
+        fs::write(&partial, b"partial").unwrap();
+        checkpointer.insert(1u32, 1u32, &epoch::pin());
+        checkpointer.checkpoint().unwrap();
+        assert!(!partial.exists());
+        assert!(unrelated.exists());
+        assert_eq!(checkpoints(dir.path()).unwrap().len(), 1);
+    }
+}
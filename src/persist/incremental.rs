@@ -0,0 +1,648 @@
+//! Incremental snapshots, which only write the parts of a ctrie that changed since the last one.
+//!
+//! The main node of every i-node in a snapshot is written as a content-addressed page, which
+//! refers to the pages of its child i-nodes by id. Snapshots share every node that wasn't modified
+//! between them, so a checkpoint only needs to write the pages of main nodes it hasn't seen
+//! before, and can refer to the pages of unchanged subtrees written by earlier checkpoints.
+//!
+//! Each checkpoint is written as a segment:
+//!
+//! | field    | encoding                                                             |
+//! |----------|----------------------------------------------------------------------|
+//! | magic    | the bytes `CTRP`                                                     |
+//! | version  | `u32`                                                                |
+//! | sequence | `u64`, the position of the segment in the chain, starting at 0       |
+//! | pages    | for each page, the tag `1u8`, id (`u128`), length (`u32`) and bytes  |
+//! | end      | the tag `0u8`                                                        |
+//! | root     | `u128`, the id of the root page                                      |
+//! | manifest | count (`u64`) and ids (`u128`) of the pages reused from earlier segments |
+//! | checksum | `u32`, the CRC-32 of everything before it                            |
+//!
+//! A page is either a C-node (tag `0u8`, bitmap `u64`, then for each branch either `0u8` and an
+//! entry or `1u8` and a child page id), an L-node (tag `1u8`, entry count `u32`, entries) or a
+//! tomb (tag `2u8`, entry). Entries are encoded like in the full snapshot format.
+
+use crate::{
+    checksum::content_id,
+    codec::Codec,
+    gcas::gcas_read,
+    node::{
+        Branch, CtrieNode, IndirectionNode, ListNode, MainNode, MainNodeKind, SingletonNode,
+        TombNode,
+    },
+    persist::{
+        read_encoded, read_header, read_u32, read_u64, write_encoded, write_header, write_u32,
+        write_u64, ChecksumReader, ChecksumWriter, SnapshotError,
+    },
+    Ctrie, Generation, Key, Value,
+};
+use crossbeam::epoch::{self, Atomic, Guard, Shared};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::BuildHasher,
+    io::{Read, Write},
+    sync::Arc,
+};
+
+const MAGIC: &[u8; 4] = b"CTRP";
+
+/// The version of the segment format written by this version of the crate.
+pub const VERSION: u32 = 1;
+
+const PAGE_TAG: u8 = 1;
+const END_TAG: u8 = 0;
+
+const CTRIE_PAGE: u8 = 0;
+const LIST_PAGE: u8 = 1;
+const TOMB_PAGE: u8 = 2;
+
+const ENTRY_BRANCH: u8 = 0;
+const CHILD_BRANCH: u8 = 1;
+
+/// A summary of a segment written by a checkpoint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    /// The position of the segment in the chain.
+    pub sequence: u64,
+    /// The id of the root page.
+    pub root: u128,
+    /// The ids of the pages written to this segment.
+    pub written: Vec<u128>,
+    /// The ids of the pages written by earlier segments that this checkpoint refers to.
+    pub reused: Vec<u128>,
+}
+
+/// Writes a chain of incremental snapshots of a ctrie.
+///
+/// The segments written by one writer must be loaded together, in order, by `load_chain`.
+pub struct IncrementalWriter<K, V, S, const W: usize = 6, S2 = S> {
+    // the page of every main node of the last checkpoint's snapshot, keyed by address
+    pages: HashMap<usize, NodePage>,
+    // every page id written so far
+    written: HashSet<u128>,
+    // the snapshot of the last checkpoint, which keeps the nodes in `pages` alive so that their
+    // addresses can't be reused by other nodes
+    previous: Option<Ctrie<K, V, S, W, S2>>,
+    sequence: u64,
+}
+
+impl<K, V, S, const W: usize, S2> IncrementalWriter<K, V, S, W, S2>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+    S: BuildHasher + Clone,
+    S2: BuildHasher + Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            pages: HashMap::new(),
+            written: HashSet::new(),
+            previous: None,
+            sequence: 0,
+        }
+    }
+
+    /// Returns the sequence number of the next segment.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Writes a snapshot of the ctrie as the next segment of the chain.
+    ///
+    /// Only the pages that weren't written by an earlier checkpoint are written. If writing fails,
+    /// the writer is left as it was, and the segment should be discarded.
+    pub fn checkpoint<O: Write>(
+        &mut self,
+        ctrie: &Ctrie<K, V, S, W, S2>,
+        writer: O,
+    ) -> Result<Manifest, SnapshotError> {
+        let guard = &epoch::pin();
+        let snapshot = ctrie.read_only_snapshot(guard);
+
+        let mut writer = ChecksumWriter::new(writer);
+        write_header(&mut writer, MAGIC, VERSION)?;
+        write_u64(&mut writer, self.sequence)?;
+
+        let mut checkpoint = Checkpoint {
+            writer: &mut writer,
+            previous_pages: &self.pages,
+            previous_written: &self.written,
+            pages: HashMap::new(),
+            written: vec![],
+            reused: vec![],
+            reused_set: HashSet::new(),
+            reused_nodes: vec![],
+            buf: vec![],
+        };
+        let root = snapshot.read_root(guard);
+        let root_id = checkpoint.write_main(gcas_read(root, &snapshot, guard), &snapshot, guard)?;
+        let Checkpoint {
+            mut pages,
+            written,
+            reused,
+            reused_nodes,
+            ..
+        } = checkpoint;
+
+        writer.write_all(&[END_TAG])?;
+        writer.write_all(&root_id.to_le_bytes())?;
+        write_u64(&mut writer, reused.len() as u64)?;
+        for id in &reused {
+            writer.write_all(&id.to_le_bytes())?;
+        }
+        writer.finish()?;
+
+        // the segment is complete, so later checkpoints can refer to its pages. Only the nodes of
+        // this snapshot are kept: the others may be freed once the previous snapshot is dropped.
+        let mut stack = reused_nodes;
+        while let Some(address) = stack.pop() {
+            if let Some(page) = self.pages.remove(&address) {
+                stack.extend(&page.children);
+                pages.insert(address, page);
+            }
+        }
+        self.pages = pages;
+        self.written.extend(written.iter().copied());
+        self.previous = Some(snapshot);
+        let manifest = Manifest {
+            sequence: self.sequence,
+            root: root_id,
+            written,
+            reused,
+        };
+        self.sequence += 1;
+        Ok(manifest)
+    }
+}
+
+impl<K, V, S, const W: usize, S2> Default for IncrementalWriter<K, V, S, W, S2>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+    S: BuildHasher + Clone,
+    S2: BuildHasher + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The page of a main node written by a checkpoint.
+struct NodePage {
+    id: u128,
+    // the addresses of the main nodes of its child i-nodes
+    children: Vec<usize>,
+}
+
+/// The state of a checkpoint in progress.
+struct Checkpoint<'a, O> {
+    writer: &'a mut O,
+    previous_pages: &'a HashMap<usize, NodePage>,
+    previous_written: &'a HashSet<u128>,
+    pages: HashMap<usize, NodePage>,
+    written: Vec<u128>,
+    reused: Vec<u128>,
+    reused_set: HashSet<u128>,
+    // the addresses of the main nodes whose pages were taken from an earlier checkpoint
+    reused_nodes: Vec<usize>,
+    buf: Vec<u8>,
+}
+
+impl<'a, O: Write> Checkpoint<'a, O> {
+    /// Writes the page of a main node and of everything below it, returning its id.
+    fn write_main<K, V, S, const W: usize, S2>(
+        &mut self,
+        main_ptr: Shared<MainNode<K, V>>,
+        snapshot: &Ctrie<K, V, S, W, S2>,
+        guard: &Guard,
+    ) -> Result<u128, SnapshotError>
+    where
+        K: Key + Codec,
+        V: Value + Codec,
+        S: BuildHasher,
+        S2: BuildHasher,
+    {
+        let address = main_ptr.as_raw() as usize;
+        if let Some(previous) = self.previous_pages.get(&address) {
+            // every node below this one is unchanged since an earlier checkpoint
+            let id = previous.id;
+            self.reuse(id);
+            self.reused_nodes.push(address);
+            return Ok(id);
+        }
+        if let Some(page) = self.pages.get(&address) {
+            return Ok(page.id);
+        }
+
+        let mut page = vec![];
+        let mut children = vec![];
+        match unsafe { main_ptr.deref() }.kind() {
+            MainNodeKind::Ctrie(cnode) => {
+                page.push(CTRIE_PAGE);
+                write_u64(&mut page, cnode.bitmap())?;
+                for position in 0..cnode.branches() {
+                    match cnode.branch(position) {
+                        Branch::Singleton(snode) => {
+                            page.push(ENTRY_BRANCH);
+                            self.write_entry(&mut page, snode)?;
+                        }
+                        Branch::Indirection(inode) => {
+                            let child_main_ptr = gcas_read(inode, snapshot, guard);
+                            let child_id = self.write_main(child_main_ptr, snapshot, guard)?;
+                            children.push(child_main_ptr.as_raw() as usize);
+                            page.push(CHILD_BRANCH);
+                            page.write_all(&child_id.to_le_bytes())?;
+                        }
+                    }
+                }
+            }
+            MainNodeKind::List(lnode) => {
+                page.push(LIST_PAGE);
+                write_u32(&mut page, lnode.length(guard) as u32)?;
+                for snode in lnode.entries(guard) {
+                    self.write_entry(&mut page, snode)?;
+                }
+            }
+            MainNodeKind::Tomb(tnode) => {
+                page.push(TOMB_PAGE);
+                self.write_entry(&mut page, tnode.snode())?;
+            }
+            MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
+        }
+
+        let id = content_id(&page);
+        self.pages.insert(address, NodePage { id, children });
+        if self.previous_written.contains(&id) {
+            self.reuse(id);
+        } else if !self.written.contains(&id) {
+            self.writer.write_all(&[PAGE_TAG])?;
+            self.writer.write_all(&id.to_le_bytes())?;
+            write_u32(self.writer, page.len() as u32)?;
+            self.writer.write_all(&page)?;
+            self.written.push(id);
+        }
+        Ok(id)
+    }
+
+    fn write_entry<K, V>(
+        &mut self,
+        page: &mut Vec<u8>,
+        snode: &SingletonNode<K, V>,
+    ) -> Result<(), SnapshotError>
+    where
+        K: Key + Codec,
+        V: Value + Codec,
+    {
+        write_encoded(page, snode.key(), &mut self.buf)?;
+        write_encoded(page, snode.value(), &mut self.buf)?;
+        Ok(())
+    }
+
+    fn reuse(&mut self, id: u128) {
+        if self.reused_set.insert(id) {
+            self.reused.push(id);
+        }
+    }
+}
+
+/// Loads the ctrie of the last checkpoint in a chain of segments, using the given hasher.
+///
+/// The segments must be given in the order they were written, starting with the first one. The
+/// hasher must hash keys the same way as the hasher of the ctrie that was checkpointed, and the
+/// ctrie must have the same width, since the structure of the ctrie is loaded as it was written.
+pub fn load_chain<K, V, S, const W: usize, R, I>(
+    segments: I,
+    hash_builder: S,
+) -> Result<Ctrie<K, V, S, W>, SnapshotError>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+    S: BuildHasher,
+    R: Read,
+    I: IntoIterator<Item = R>,
+{
+    let mut pages = HashMap::new();
+    let mut root = None;
+    let mut buf = vec![];
+    for (sequence, segment) in segments.into_iter().enumerate() {
+        let mut reader = ChecksumReader::new(segment);
+        read_header(&mut reader, MAGIC, VERSION)?;
+        let found = read_u64(&mut reader)?;
+        if found != sequence as u64 {
+            return Err(SnapshotError::BrokenChain {
+                expected: sequence as u64,
+                found,
+            });
+        }
+
+        loop {
+            let mut tag = [0];
+            reader.read_exact(&mut tag)?;
+            match tag[0] {
+                END_TAG => break,
+                PAGE_TAG => {
+                    let id = read_u128(&mut reader)?;
+                    let len = read_u32(&mut reader)?;
+                    buf.clear();
+                    (&mut reader).take(u64::from(len)).read_to_end(&mut buf)?;
+                    if buf.len() != len as usize {
+                        return Err(SnapshotError::Truncated);
+                    }
+                    if content_id(&buf) != id {
+                        return Err(SnapshotError::InvalidPage);
+                    }
+                    pages.insert(id, buf.clone());
+                }
+                _ => return Err(SnapshotError::InvalidPage),
+            }
+        }
+
+        let root_id = read_u128(&mut reader)?;
+        let reused = read_u64(&mut reader)?;
+        for _ in 0..reused {
+            let id = read_u128(&mut reader)?;
+            if !pages.contains_key(&id) {
+                return Err(SnapshotError::MissingPage(id));
+            }
+        }
+        reader.finish()?;
+        root = Some(root_id);
+    }
+
+    let generation = Generation::new();
+    let ctrie = Ctrie::with_width(hash_builder);
+    let root_id = match root {
+        Some(root_id) => root_id,
+        // an empty chain holds an empty ctrie
+        None => return Ok(ctrie),
+    };
+    let loader = Loader {
+        ctrie: &ctrie,
+        pages: &pages,
+        generation: generation.clone(),
+    };
+    let main = loader.load_main(root_id, 0, 0)?;
+    if !matches!(main.kind(), MainNodeKind::Ctrie(_)) {
+        return Err(SnapshotError::InvalidPage);
+    }
+    Ok(Ctrie::from_root(
+        IndirectionNode::new(Atomic::new(main), generation),
+        false,
+        ctrie.hashers,
+    ))
+}
+
+struct Loader<'a, K, V, S, const W: usize, S2> {
+    ctrie: &'a Ctrie<K, V, S, W, S2>,
+    pages: &'a HashMap<u128, Vec<u8>>,
+    generation: Generation,
+}
+
+impl<'a, K, V, S, const W: usize, S2> Loader<'a, K, V, S, W, S2>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    /// Loads the main node of the page with the given id, whose keys have the given hash prefix.
+    fn load_main(
+        &self,
+        id: u128,
+        level: usize,
+        prefix: u128,
+    ) -> Result<MainNode<K, V>, SnapshotError> {
+        let mut page = &self.pages.get(&id).ok_or(SnapshotError::MissingPage(id))?[..];
+        let mut buf = vec![];
+        let mut kind = [0];
+        page.read_exact(&mut kind)?;
+        let main = match kind[0] {
+            CTRIE_PAGE if level < self.ctrie.hashers.bits() => {
+                let bitmap = read_u64(&mut page)?;
+                let mut array = Vec::with_capacity(bitmap.count_ones() as usize);
+                let mut remaining = bitmap;
+                while remaining != 0 {
+                    let index = u64::from(remaining.trailing_zeros());
+                    remaining &= remaining - 1;
+                    let child_prefix = prefix | (u128::from(index) << level);
+
+                    let mut tag = [0];
+                    page.read_exact(&mut tag)?;
+                    let branch = match tag[0] {
+                        ENTRY_BRANCH => {
+                            let snode = self.load_entry(&mut page, &mut buf)?;
+                            self.check_prefix(&snode, level + W, child_prefix)?;
+                            Branch::Singleton(snode)
+                        }
+                        CHILD_BRANCH => {
+                            let child_id = read_u128(&mut page)?;
+                            let main = self.load_main(child_id, level + W, child_prefix)?;
+                            Branch::Indirection(Arc::new(IndirectionNode::new(
+                                Atomic::new(main),
+                                self.generation.clone(),
+                            )))
+                        }
+                        _ => return Err(SnapshotError::InvalidPage),
+                    };
+                    array.push(branch);
+                }
+                MainNode::from_ctrie_node(CtrieNode::new(bitmap, array, self.generation.clone()))
+            }
+            LIST_PAGE if level >= self.ctrie.hashers.list_level(2) => {
+                let count = read_u32(&mut page)?;
+                if level < self.ctrie.hashers.list_level(count as usize) {
+                    return Err(SnapshotError::InvalidPage);
+                }
+                let mut entries = vec![];
+                for _ in 0..count {
+                    let snode = self.load_entry(&mut page, &mut buf)?;
+                    self.check_prefix(&snode, level, prefix)?;
+                    entries.push(snode);
+                }
+                if entries.is_empty() {
+                    return Err(SnapshotError::InvalidPage);
+                }
+                MainNode::from_list_node(ListNode::from_entries(entries))
+            }
+            TOMB_PAGE if level > 0 => {
+                let snode = self.load_entry(&mut page, &mut buf)?;
+                self.check_prefix(&snode, level, prefix)?;
+                MainNode::from_tomb_node(TombNode::new(snode))
+            }
+            _ => return Err(SnapshotError::InvalidPage),
+        };
+        if page.is_empty() {
+            Ok(main)
+        } else {
+            Err(SnapshotError::InvalidPage)
+        }
+    }
+
+    fn load_entry(
+        &self,
+        page: &mut &[u8],
+        buf: &mut Vec<u8>,
+    ) -> Result<SingletonNode<K, V>, SnapshotError> {
+        let key = read_encoded(page, buf)?;
+        let value = read_encoded(page, buf)?;
+        let hash = self.ctrie.hashers.primary_hash(&key);
+        Ok(SingletonNode::new(key, value, u128::from(hash)))
+    }
+
+    /// Checks that the entry belongs below the given hash prefix, which fails if the chain was
+    /// written with a different hash function.
+    fn check_prefix(
+        &self,
+        snode: &SingletonNode<K, V>,
+        bits: usize,
+        prefix: u128,
+    ) -> Result<(), SnapshotError> {
+        let mask = if bits >= 128 { !0 } else { (1 << bits) - 1 };
+        let hash = self
+            .ctrie
+            .hashers
+            .hash_at(snode.key(), snode.known_hash(), bits);
+        if hash & mask == prefix & mask {
+            Ok(())
+        } else {
+            Err(SnapshotError::InvalidPage)
+        }
+    }
+}
+
+fn read_u128<R: Read>(reader: &mut R) -> std::io::Result<u128> {
+    let mut bytes = [0; 16];
+    reader.read_exact(&mut bytes)?;
+    Ok(u128::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FxHasher;
+    use std::hash::BuildHasherDefault;
+
+    type Fx = BuildHasherDefault<FxHasher>;
+
+    fn assert_contents(ctrie: &Ctrie<u32, u32, Fx>, expected: impl Fn(u32) -> Option<u32>) {
+        let guard = &epoch::pin();
+        let mut count = 0;
+        for i in 0..2000 {
+            assert_eq!(ctrie.lookup(&i, guard), expected(i).as_ref());
+            count += expected(i).is_some() as usize;
+        }
+        assert_eq!(ctrie.validate(guard), Ok(count));
+    }
+
+    #[test]
+    fn checkpoint_chain() {
+        let ctrie = Ctrie::with_hasher(Fx::default());
+        let guard = &epoch::pin();
+        for i in 0..1000 {
+            ctrie.insert(i, i, guard);
+        }
+
+        let mut writer = IncrementalWriter::new();
+        let mut segments = vec![vec![]];
+        let first = writer.checkpoint(&ctrie, &mut segments[0]).unwrap();
+        assert_eq!(first.sequence, 0);
+        assert!(first.reused.is_empty());
+
+        // an unchanged ctrie only refers to the root page
+        segments.push(vec![]);
+        let unchanged = writer.checkpoint(&ctrie, &mut segments[1]).unwrap();
+        assert!(unchanged.written.is_empty());
+        assert_eq!(unchanged.reused, vec![first.root]);
+
+        for i in 0..5 {
+            ctrie.insert(i * 100, 0, guard);
+        }
+        segments.push(vec![]);
+        let changed = writer.checkpoint(&ctrie, &mut segments[2]).unwrap();
+        assert!(!changed.written.is_empty());
+        assert!(changed.written.len() * 10 < first.written.len());
+
+        let loaded: Ctrie<u32, u32, Fx> =
+            load_chain(segments.iter().map(|s| &s[..]), Fx::default()).unwrap();
+        assert_contents(&loaded, |i| match i {
+            i if i < 500 && i % 100 == 0 => Some(0),
+            i if i < 1000 => Some(i),
+            _ => None,
+        });
+
+        // the loaded ctrie is writable
+        loaded.insert(1500, 1500, guard);
+        assert_eq!(loaded.lookup(&1500, guard), Some(&1500));
+    }
+
+    #[test]
+    fn forgets_dropped_nodes() {
+        let ctrie = Ctrie::with_hasher(Fx::default());
+        let guard = &epoch::pin();
+        let mut writer = IncrementalWriter::new();
+        let mut segments = vec![];
+        for round in 0..20 {
+            for i in 0..100 {
+                ctrie.remove(&(round * 100 + i), guard);
+                ctrie.insert(round * 100 + i + 1000, i, guard);
+            }
+            let mut segment = vec![];
+            writer.checkpoint(&ctrie, &mut segment).unwrap();
+            segments.push(segment);
+
+            // only the pages of the nodes of the last snapshot are remembered
+            let mut fresh = IncrementalWriter::new();
+            fresh.checkpoint(&ctrie, &mut vec![]).unwrap();
+            assert_eq!(writer.pages.len(), fresh.pages.len());
+        }
+
+        let loaded: Ctrie<u32, u32, Fx> =
+            load_chain(segments.iter().map(|s| &s[..]), Fx::default()).unwrap();
+        let expected = (2000..3000).map(|i| (i, i % 100));
+        assert!(expected
+            .clone()
+            .all(|(i, value)| loaded.lookup(&i, guard) == Some(&value)));
+        assert_eq!(loaded.validate(guard), Ok(1000));
+    }
+
+    #[test]
+    fn broken_chain() {
+        let ctrie = Ctrie::with_hasher(Fx::default());
+        let guard = &epoch::pin();
+        let mut writer = IncrementalWriter::new();
+        let mut segments = vec![];
+        for round in 0..3 {
+            for i in 0..100 {
+                ctrie.insert(round * 100 + i, i, guard);
+            }
+            let mut segment = vec![];
+            writer.checkpoint(&ctrie, &mut segment).unwrap();
+            segments.push(segment);
+        }
+
+        let load = |segments: &[&Vec<u8>]| {
+            load_chain::<u32, u32, _, 6, _, _>(segments.iter().map(|s| &s[..]), Fx::default())
+        };
+        assert!(matches!(
+            load(&[&segments[0], &segments[2]]),
+            Err(SnapshotError::BrokenChain {
+                expected: 1,
+                found: 2
+            })
+        ));
+        assert!(matches!(
+            load(&[&segments[1]]),
+            Err(SnapshotError::BrokenChain { .. })
+        ));
+        let mut truncated = segments[2].clone();
+        truncated.truncate(truncated.len() - 1);
+        assert!(matches!(
+            load(&[&segments[0], &segments[1], &truncated]),
+            Err(SnapshotError::Truncated)
+        ));
+        assert_contents(
+            &load(&[&segments[0], &segments[1], &segments[2]]).unwrap(),
+            |i| if i < 300 { Some(i % 100) } else { None },
+        );
+    }
+}
@@ -0,0 +1,709 @@
+//! A write-ahead log of mutations, for recovering a ctrie after a crash.
+//!
+//! A `DurableCtrie` appends a record to the log before applying each mutation, and a checkpoint
+//! writes a full snapshot and starts a new, empty log. `Ctrie::recover` rebuilds the ctrie by
+//! replaying the log over the last snapshot.
+//!
+//! | field   | encoding                                                               |
+//! |---------|------------------------------------------------------------------------|
+//! | magic   | the bytes `CTRW`                                                       |
+//! | version | `u32`                                                                  |
+//! | records | payload length (`u32`), CRC-32 of the payload (`u32`), payload         |
+//!
+//! A payload is either an insert (tag `1u8`, key, value) or a remove (tag `2u8`, key), with keys
+//! and values encoded like in the full snapshot format. A record that is cut short or fails its
+//! checksum was being written during a crash, so replay stops there.
+//!
+//! While a checkpoint is in progress, records are appended to a second log next to the first,
+//! with `.next` added to its name. Replaying a record twice has no effect, so recovery replays
+//! both logs whenever the second exists.
+
+use crate::{
+    checksum::Crc32,
+    codec::Codec,
+    persist::{
+        read_encoded, read_header, read_u32, sync_parent, write_encoded, write_header,
+        write_snapshot_file, SnapshotError,
+    },
+    Ctrie, DefaultHashBuilder, Key, Value,
+};
+use crossbeam::epoch::{self, Guard};
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    hash::BuildHasher,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+const MAGIC: &[u8; 4] = b"CTRW";
+
+/// The version of the log format written by this version of the crate.
+pub const VERSION: u32 = 1;
+
+const HEADER_LEN: u64 = 8;
+
+const INSERT: u8 = 1;
+const REMOVE: u8 = 2;
+
+/// When a `DurableCtrie` flushes its log to disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Sync after every record, so a mutation is durable as soon as it returns.
+    Always,
+    /// Sync after every `n` records, so up to `n - 1` acknowledged mutations can be lost.
+    EveryN(usize),
+    /// Leave syncing to the operating system.
+    Never,
+}
+
+/// A mutation read back from a log.
+enum Record<K, V> {
+    Insert(K, V),
+    Remove(K),
+}
+
+fn next_path(wal_path: &Path) -> PathBuf {
+    let mut path = OsString::from(wal_path);
+    path.push(".next");
+    path.into()
+}
+
+/// Creates an empty log at `path`, replacing any existing file.
+fn create_log(path: &Path) -> io::Result<File> {
+    let mut file = File::create(path)?;
+    write_header(&mut file, MAGIC, VERSION)?;
+    file.sync_all()?;
+    sync_parent(path)?;
+    Ok(file)
+}
+
+/// Encodes a record into `record`, using `buf` as scratch space.
+fn encode_record<K: Codec, V: Codec>(
+    record: &mut Vec<u8>,
+    key: &K,
+    value: Option<&V>,
+    buf: &mut Vec<u8>,
+) -> io::Result<()> {
+    // leave room for the length and checksum
+    record.clear();
+    record.extend_from_slice(&[0; 8]);
+    match value {
+        Some(value) => {
+            record.push(INSERT);
+            write_encoded(record, key, buf)?;
+            write_encoded(record, value, buf)?;
+        }
+        None => {
+            record.push(REMOVE);
+            write_encoded(record, key, buf)?;
+        }
+    }
+    let payload = &record[8..];
+    let len = payload.len() as u32;
+    let mut crc = Crc32::new();
+    crc.update(payload);
+    let checksum = crc.finish();
+    record[..4].copy_from_slice(&len.to_le_bytes());
+    record[4..8].copy_from_slice(&checksum.to_le_bytes());
+    Ok(())
+}
+
+/// Reads the next record of a log, or `None` at the end of the valid part of the log.
+fn read_record<R: Read, K: Codec, V: Codec>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> Result<Option<Record<K, V>>, SnapshotError> {
+    let len = match read_u32(reader) {
+        Ok(len) => len,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let checksum = match read_u32(reader) {
+        Ok(checksum) => checksum,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut payload = Vec::new();
+    reader.take(u64::from(len)).read_to_end(&mut payload)?;
+    if payload.len() != len as usize {
+        return Ok(None);
+    }
+    let mut crc = Crc32::new();
+    crc.update(&payload);
+    if crc.finish() != checksum {
+        return Ok(None);
+    }
+
+    // the checksum matched, so a payload that can't be decoded wasn't torn by a crash
+    let (&tag, mut payload) = payload.split_first().ok_or(SnapshotError::InvalidEntry)?;
+    let record = match tag {
+        INSERT => Record::Insert(
+            read_encoded(&mut payload, buf)?,
+            read_encoded(&mut payload, buf)?,
+        ),
+        REMOVE => Record::Remove(read_encoded(&mut payload, buf)?),
+        _ => return Err(SnapshotError::InvalidEntry),
+    };
+    if !payload.is_empty() {
+        return Err(SnapshotError::InvalidEntry);
+    }
+    Ok(Some(record))
+}
+
+/// Replays the log at `path` over `ctrie`, and returns the length of its valid part.
+///
+/// A missing log, or one cut short in its header, is treated as empty.
+fn replay<K, V, S, const W: usize, S2>(
+    ctrie: &Ctrie<K, V, S, W, S2>,
+    path: &Path,
+) -> Result<u64, SnapshotError>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    let mut reader = BufReader::new(file);
+    match read_header(&mut reader, MAGIC, VERSION) {
+        Ok(()) => {}
+        Err(SnapshotError::Truncated) => return Ok(0),
+        Err(err) => return Err(err),
+    }
+
+    let guard = &epoch::pin();
+    let mut valid = HEADER_LEN;
+    let mut buf = Vec::new();
+    let mut reader = CountingReader {
+        inner: reader,
+        count: HEADER_LEN,
+    };
+    while let Some(record) = read_record(&mut reader, &mut buf)? {
+        match record {
+            Record::Insert(key, value) => ctrie.insert(key, value, guard),
+            Record::Remove(key) => {
+                ctrie.remove(&key, guard);
+            }
+        }
+        valid = reader.count;
+    }
+    Ok(valid)
+}
+
+/// A reader that counts the bytes read through it.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// Loads the snapshot at `snapshot_path`, or an empty ctrie if there isn't one.
+fn load_snapshot<K, V, S, const W: usize>(
+    snapshot_path: &Path,
+    hash_builder: S,
+) -> Result<Ctrie<K, V, S, W>, SnapshotError>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+    S: BuildHasher,
+{
+    match File::open(snapshot_path) {
+        Ok(file) => Ctrie::load_with_hasher(BufReader::new(file), hash_builder),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Ctrie::with_width(hash_builder)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+impl<K, V, S, const W: usize> Ctrie<K, V, S, W>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+    S: BuildHasher,
+{
+    /// Rebuilds a ctrie from the snapshot and write-ahead log kept by a `DurableCtrie`, using the
+    /// given hasher.
+    ///
+    /// A missing snapshot is treated as empty, and replay stops at a record that was only
+    /// partially written.
+    pub fn recover_with_hasher<P: AsRef<Path>, Q: AsRef<Path>>(
+        snapshot_path: P,
+        wal_path: Q,
+        hash_builder: S,
+    ) -> Result<Self, SnapshotError> {
+        let wal_path = wal_path.as_ref();
+        let ctrie = load_snapshot(snapshot_path.as_ref(), hash_builder)?;
+        replay(&ctrie, wal_path)?;
+        replay(&ctrie, &next_path(wal_path))?;
+        Ok(ctrie)
+    }
+
+    /// Rebuilds a ctrie from the snapshot and write-ahead log kept by a `DurableCtrie`.
+    pub fn recover<P: AsRef<Path>, Q: AsRef<Path>>(
+        snapshot_path: P,
+        wal_path: Q,
+    ) -> Result<Self, SnapshotError>
+    where
+        S: Default,
+    {
+        Self::recover_with_hasher(snapshot_path, wal_path, S::default())
+    }
+}
+
+/// The file a log appends records to.
+trait LogFile: Write + Seek {
+    fn set_len(&self, len: u64) -> io::Result<()>;
+    fn sync_data(&self) -> io::Result<()>;
+}
+
+impl LogFile for File {
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+struct Log<F = File> {
+    file: F,
+    // whether `file` is the `.next` log of a checkpoint that hasn't finished
+    on_next: bool,
+    policy: FsyncPolicy,
+    unsynced: usize,
+    record: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl<F: LogFile> Log<F> {
+    fn append<K: Codec, V: Codec>(&mut self, key: &K, value: Option<&V>) -> io::Result<()> {
+        encode_record(&mut self.record, key, value, &mut self.buf)?;
+        let start = self.file.stream_position()?;
+        if let Err(err) = self.write_record() {
+            // cut off the record: replay would stop at a partial one and never reach the records
+            // appended after it, and the mutation of a whole one is never applied
+            self.file.set_len(start)?;
+            self.file.seek(SeekFrom::Start(start))?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn write_record(&mut self) -> io::Result<()> {
+        self.file.write_all(&self.record)?;
+        let sync = match self.policy {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryN(n) => self.unsynced + 1 >= n,
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            self.sync()
+        } else {
+            self.unsynced += 1;
+            Ok(())
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+/// A ctrie that logs every mutation to a write-ahead log, so it can be recovered after a crash.
+///
+/// Mutations are logged and applied in the same order under a lock, so they are serialized with
+/// each other, but lookups go straight to the ctrie.
+pub struct DurableCtrie<K, V, S = DefaultHashBuilder, const W: usize = 6> {
+    ctrie: Ctrie<K, V, S, W>,
+    log: Mutex<Log>,
+    // held for the duration of a checkpoint, so only one runs at a time
+    checkpoint: Mutex<()>,
+    snapshot_path: PathBuf,
+    wal_path: PathBuf,
+}
+
+impl<K, V> DurableCtrie<K, V>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+{
+    /// Recovers the ctrie stored at the given paths, or creates an empty one, and opens its log
+    /// for writing.
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(
+        snapshot_path: P,
+        wal_path: Q,
+        policy: FsyncPolicy,
+    ) -> Result<Self, SnapshotError> {
+        Self::open_with_hasher(snapshot_path, wal_path, policy, Default::default())
+    }
+}
+
+impl<K, V, S, const W: usize> DurableCtrie<K, V, S, W>
+where
+    K: Key + Codec,
+    V: Value + Codec,
+    S: BuildHasher + Clone,
+{
+    /// Like `open`, but using the given hasher.
+    pub fn open_with_hasher<P: AsRef<Path>, Q: AsRef<Path>>(
+        snapshot_path: P,
+        wal_path: Q,
+        policy: FsyncPolicy,
+        hash_builder: S,
+    ) -> Result<Self, SnapshotError> {
+        let snapshot_path = snapshot_path.as_ref().to_owned();
+        let wal_path = wal_path.as_ref().to_owned();
+        let next = next_path(&wal_path);
+
+        let ctrie = load_snapshot(&snapshot_path, hash_builder)?;
+        let valid = replay(&ctrie, &wal_path)?;
+        let file = if next.exists() {
+            // a checkpoint was interrupted, so finish it with the recovered contents
+            replay(&ctrie, &next)?;
+            write_snapshot_file(&ctrie, &snapshot_path)?;
+            let file = create_log(&wal_path)?;
+            fs::remove_file(&next)?;
+            file
+        } else if valid < HEADER_LEN {
+            create_log(&wal_path)?
+        } else {
+            // drop a partially written record, so new records aren't appended after it
+            let mut file = OpenOptions::new().write(true).open(&wal_path)?;
+            file.set_len(valid)?;
+            file.seek(SeekFrom::End(0))?;
+            file.sync_all()?;
+            file
+        };
+
+        Ok(Self {
+            ctrie,
+            log: Mutex::new(Log {
+                file,
+                on_next: false,
+                policy,
+                unsynced: 0,
+                record: Vec::new(),
+                buf: Vec::new(),
+            }),
+            checkpoint: Mutex::new(()),
+            snapshot_path,
+            wal_path,
+        })
+    }
+
+    /// Returns the underlying ctrie. Mutations made through it directly aren't logged.
+    pub fn ctrie(&self) -> &Ctrie<K, V, S, W> {
+        &self.ctrie
+    }
+
+    /// Logs and inserts a key-value pair.
+    pub fn insert(&self, key: K, value: V, guard: &Guard) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        log.append(&key, Some(&value))?;
+        self.ctrie.insert(key, value, guard);
+        Ok(())
+    }
+
+    /// Logs and removes a key, returning its value if it was present.
+    pub fn remove<'g>(&self, key: &K, guard: &'g Guard) -> io::Result<Option<&'g V>>
+    where
+        K: 'g,
+    {
+        let mut log = self.log.lock().unwrap();
+        log.append::<K, V>(key, None)?;
+        Ok(self.ctrie.remove(key, guard))
+    }
+
+    /// Looks up the value of a key.
+    pub fn lookup<'g>(&self, key: &K, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: 'g,
+    {
+        self.ctrie.lookup(key, guard)
+    }
+
+    /// Syncs every record logged so far to disk, regardless of the fsync policy.
+    pub fn sync(&self) -> io::Result<()> {
+        self.log.lock().unwrap().sync()
+    }
+
+    /// Writes a snapshot of the ctrie and truncates the log.
+    ///
+    /// Mutations can continue while the snapshot is written; they are logged to a second log,
+    /// which replaces the first once the snapshot is in place. If the checkpoint fails, mutations
+    /// keep going to the second log, and the next checkpoint picks up where this one left off.
+    pub fn checkpoint(&self) -> Result<(), SnapshotError> {
+        let _checkpoint = self.checkpoint.lock().unwrap();
+        let next = next_path(&self.wal_path);
+        let guard = &epoch::pin();
+
+        let snapshot = {
+            let mut log = self.log.lock().unwrap();
+            log.sync()?;
+            // after a failed checkpoint, the second log holds records that aren't in any
+            // snapshot yet, so it must not be truncated
+            if !log.on_next {
+                log.file = create_log(&next)?;
+                log.on_next = true;
+            }
+            self.ctrie.read_only_snapshot(guard)
+        };
+        write_snapshot_file(&snapshot, &self.snapshot_path)?;
+
+        let mut log = self.log.lock().unwrap();
+        fs::rename(&next, &self.wal_path)?;
+        log.on_next = false;
+        sync_parent(&self.wal_path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn paths(dir: &TempDir) -> (PathBuf, PathBuf) {
+        (dir.path().join("snapshot"), dir.path().join("wal"))
+    }
+
+    /// A log file whose syncs fail while `fail` is set.
+    struct FailingSync {
+        file: File,
+        fail: bool,
+    }
+
+    impl Write for FailingSync {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.file.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl Seek for FailingSync {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    impl LogFile for FailingSync {
+        fn set_len(&self, len: u64) -> io::Result<()> {
+            self.file.set_len(len)
+        }
+
+        fn sync_data(&self) -> io::Result<()> {
+            if self.fail {
+                Err(io::Error::other("sync failed"))
+            } else {
+                self.file.sync_data()
+            }
+        }
+    }
+
+    #[test]
+    fn recover_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let (snapshot, wal) = paths(&dir);
+        let guard = &epoch::pin();
+        {
+            let durable = DurableCtrie::open(&snapshot, &wal, FsyncPolicy::Always).unwrap();
+            for i in 0..100u32 {
+                durable.insert(i, i * 2, guard).unwrap();
+            }
+            for i in 0..50u32 {
+                assert_eq!(durable.remove(&i, guard).unwrap(), Some(&(i * 2)));
+            }
+            durable.insert(99, 0, guard).unwrap();
+        }
+
+        let ctrie: Ctrie<u32, u32> = Ctrie::recover(&snapshot, &wal).unwrap();
+        for i in 0..50u32 {
+            assert_eq!(ctrie.lookup(&i, guard), None);
+        }
+        for i in 50..99u32 {
+            assert_eq!(ctrie.lookup(&i, guard), Some(&(i * 2)));
+        }
+        assert_eq!(ctrie.lookup(&99, guard), Some(&0));
+        assert_eq!(ctrie.validate(guard), Ok(50));
+    }
+
+    #[test]
+    fn recover_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let (snapshot, wal) = paths(&dir);
+        let guard = &epoch::pin();
+        {
+            let durable = DurableCtrie::open(&snapshot, &wal, FsyncPolicy::EveryN(8)).unwrap();
+            for i in 0..100u32 {
+                durable.insert(i, i, guard).unwrap();
+            }
+            durable.checkpoint().unwrap();
+            assert_eq!(fs::metadata(&wal).unwrap().len(), HEADER_LEN);
+            for i in 0..10u32 {
+                durable.remove(&i, guard).unwrap();
+            }
+            durable.sync().unwrap();
+        }
+
+        let ctrie: Ctrie<u32, u32> = Ctrie::recover(&snapshot, &wal).unwrap();
+        assert_eq!(ctrie.validate(guard), Ok(90));
+
+        // reopening picks up where the log left off
+        let durable: DurableCtrie<u32, u32> =
+            DurableCtrie::open(&snapshot, &wal, FsyncPolicy::Never).unwrap();
+        durable.insert(0, 1, guard).unwrap();
+        drop(durable);
+        let ctrie: Ctrie<u32, u32> = Ctrie::recover(&snapshot, &wal).unwrap();
+        assert_eq!(ctrie.lookup(&0, guard), Some(&1));
+        assert_eq!(ctrie.validate(guard), Ok(91));
+    }
+
+    #[test]
+    fn failed_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, wal) = paths(&dir);
+        // the snapshot can't be written until its directory exists
+        let snapshot = dir.path().join("snapshots").join("snapshot");
+        let guard = &epoch::pin();
+        let durable = DurableCtrie::open(&snapshot, &wal, FsyncPolicy::Always).unwrap();
+        for i in 0..30u32 {
+            durable.insert(i, i, guard).unwrap();
+            if i % 10 == 9 {
+                assert!(durable.checkpoint().is_err());
+            }
+        }
+        // records logged since the first failed checkpoint survive the retries
+        let ctrie: Ctrie<u32, u32> = Ctrie::recover(&snapshot, &wal).unwrap();
+        assert_eq!(ctrie.validate(guard), Ok(30));
+
+        fs::create_dir(dir.path().join("snapshots")).unwrap();
+        durable.checkpoint().unwrap();
+        assert!(!next_path(&wal).exists());
+        durable.remove(&0, guard).unwrap();
+        drop(durable);
+        let ctrie: Ctrie<u32, u32> = Ctrie::recover(&snapshot, &wal).unwrap();
+        assert_eq!(ctrie.lookup(&0, guard), None);
+        assert_eq!(ctrie.validate(guard), Ok(29));
+    }
+
+    #[test]
+    fn torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let (snapshot, wal) = paths(&dir);
+        let guard = &epoch::pin();
+        {
+            let durable = DurableCtrie::open(&snapshot, &wal, FsyncPolicy::Never).unwrap();
+            for i in 0..10u32 {
+                durable.insert(i, i, guard).unwrap();
+            }
+        }
+        let len = fs::metadata(&wal).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&wal)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        let ctrie: Ctrie<u32, u32> = Ctrie::recover(&snapshot, &wal).unwrap();
+        assert_eq!(ctrie.lookup(&9, guard), None);
+        assert_eq!(ctrie.validate(guard), Ok(9));
+
+        // the torn record is dropped before anything else is logged
+        let durable: DurableCtrie<u32, u32> =
+            DurableCtrie::open(&snapshot, &wal, FsyncPolicy::Never).unwrap();
+        durable.insert(10, 10, guard).unwrap();
+        drop(durable);
+        let ctrie: Ctrie<u32, u32> = Ctrie::recover(&snapshot, &wal).unwrap();
+        assert_eq!(ctrie.lookup(&10, guard), Some(&10));
+        assert_eq!(ctrie.validate(guard), Ok(10));
+    }
+
+    #[test]
+    fn failed_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let (snapshot, wal) = paths(&dir);
+        let guard = &epoch::pin();
+
+        let mut log = Log {
+            file: FailingSync {
+                file: create_log(&wal).unwrap(),
+                fail: false,
+            },
+            on_next: false,
+            policy: FsyncPolicy::Always,
+            unsynced: 0,
+            record: Vec::new(),
+            buf: Vec::new(),
+        };
+        for i in 0..5u32 {
+            log.append(&i, Some(&i)).unwrap();
+        }
+        // a record that couldn't be synced was reported as not logged, so it must not be replayed
+        log.file.fail = true;
+        assert!(log.append(&5u32, Some(&5u32)).is_err());
+        log.file.fail = false;
+        log.append(&6u32, Some(&6u32)).unwrap();
+        drop(log);
+
+        let recovered: Ctrie<u32, u32> = Ctrie::recover(&snapshot, &wal).unwrap();
+        assert_eq!(recovered.lookup(&5, guard), None);
+        assert_eq!(recovered.lookup(&6, guard), Some(&6));
+        assert_eq!(recovered.validate(guard), Ok(6));
+    }
+
+    #[test]
+    fn interrupted_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let (snapshot, wal) = paths(&dir);
+        let guard = &epoch::pin();
+
+        // simulate a crash after the snapshot was replaced, but before the logs were swapped
+        let ctrie = Ctrie::new();
+        let mut log = Log {
+            file: create_log(&wal).unwrap(),
+            on_next: false,
+            policy: FsyncPolicy::Never,
+            unsynced: 0,
+            record: Vec::new(),
+            buf: Vec::new(),
+        };
+        for i in 0..20u32 {
+            log.append(&i, Some(&i)).unwrap();
+            ctrie.insert(i, i, guard);
+        }
+        write_snapshot_file(&ctrie, &snapshot).unwrap();
+        log.file = create_log(&next_path(&wal)).unwrap();
+        log.append::<u32, u32>(&0, None).unwrap();
+        log.append(&20u32, Some(&20u32)).unwrap();
+        drop(log);
+
+        let recovered: Ctrie<u32, u32> = Ctrie::recover(&snapshot, &wal).unwrap();
+        assert_eq!(recovered.lookup(&0, guard), None);
+        assert_eq!(recovered.validate(guard), Ok(20));
+
+        let durable: DurableCtrie<u32, u32> =
+            DurableCtrie::open(&snapshot, &wal, FsyncPolicy::Never).unwrap();
+        assert!(!next_path(&wal).exists());
+        assert_eq!(fs::metadata(&wal).unwrap().len(), HEADER_LEN);
+        assert_eq!(durable.ctrie().validate(guard), Ok(20));
+    }
+}
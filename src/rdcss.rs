@@ -0,0 +1,128 @@
+use crate::{
+    gcas::gcas_read,
+    node::{MainNode, RdcssDescriptor, RootNode, ABORTED, COMMITTED, UNDECIDED},
+    Ctrie, Key, Value, CAS_ORD, LOAD_ORD,
+};
+use core::{hash::BuildHasher, sync::atomic::Ordering};
+use crossbeam::epoch::{Atomic, Guard, Owned, Shared};
+
+/// Swaps the root of the ctrie from `old_ptr` to `new`, but only if the main node of the old root
+/// is still `expected_main`.
+///
+/// Returns whether the swap happened.
+pub fn rdcss<K, V, S, const W: usize, S2>(
+    ctrie: &Ctrie<K, V, S, W, S2>,
+    old_ptr: Shared<RootNode<K, V>>,
+    expected_main: Shared<MainNode<K, V>>,
+    new: RootNode<K, V>,
+    guard: &Guard,
+) -> bool
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    let descriptor = RdcssDescriptor::new(
+        Atomic::from(old_ptr),
+        Atomic::from(expected_main),
+        Atomic::new(new),
+    );
+    let descriptor_ptr = Owned::new(RootNode::Descriptor(descriptor)).into_shared(guard);
+
+    if ctrie
+        .root()
+        .compare_and_set(old_ptr, descriptor_ptr, CAS_ORD, guard)
+        .is_ok()
+    {
+        rdcss_complete(ctrie, false, guard);
+        match unsafe { descriptor_ptr.deref() } {
+            RootNode::Descriptor(descriptor) => {
+                descriptor.decision().load(Ordering::Acquire) == COMMITTED
+            }
+            RootNode::Indirection(_) => unreachable!(),
+        }
+    } else {
+        false
+    }
+}
+
+/// Reads the root of the ctrie, completing any RDCSS operation in progress.
+///
+/// If `abort` is set, an undecided operation is aborted rather than committed. The returned node
+/// is always an i-node.
+pub fn rdcss_read_root<'g, K, V, S, const W: usize, S2>(
+    ctrie: &Ctrie<K, V, S, W, S2>,
+    abort: bool,
+    guard: &'g Guard,
+) -> Shared<'g, RootNode<K, V>>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    let root_ptr = ctrie.root().load(LOAD_ORD, guard);
+    match unsafe { root_ptr.deref() } {
+        RootNode::Indirection(_) => root_ptr,
+        RootNode::Descriptor(_) => rdcss_complete(ctrie, abort, guard),
+    }
+}
+
+fn rdcss_complete<'g, K, V, S, const W: usize, S2>(
+    ctrie: &Ctrie<K, V, S, W, S2>,
+    abort: bool,
+    guard: &'g Guard,
+) -> Shared<'g, RootNode<K, V>>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    loop {
+        let root_ptr = ctrie.root().load(LOAD_ORD, guard);
+        let descriptor = match unsafe { root_ptr.deref() } {
+            RootNode::Indirection(_) => return root_ptr,
+            RootNode::Descriptor(descriptor) => descriptor,
+        };
+
+        let old_ptr = descriptor.old().load(LOAD_ORD, guard);
+        let new_ptr = descriptor.new_root().load(LOAD_ORD, guard);
+
+        if descriptor.decision().load(Ordering::Acquire) == UNDECIDED {
+            let proposal = if abort {
+                ABORTED
+            } else {
+                // reading the old main node may itself abort the descriptor, in which case the
+                // compare-exchange below fails
+                let old = unsafe { old_ptr.deref() };
+                let old_main_ptr = gcas_read(old.inode(), ctrie, guard);
+                if old_main_ptr == descriptor.expected_main().load(LOAD_ORD, guard) {
+                    COMMITTED
+                } else {
+                    ABORTED
+                }
+            };
+            let _ = descriptor.decision().compare_exchange(
+                UNDECIDED,
+                proposal,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+        }
+
+        let target_ptr = if descriptor.decision().load(Ordering::Acquire) == COMMITTED {
+            new_ptr
+        } else {
+            old_ptr
+        };
+        if ctrie
+            .root()
+            .compare_and_set(root_ptr, target_ptr, CAS_ORD, guard)
+            .is_ok()
+        {
+            return target_ptr;
+        }
+    }
+}
@@ -0,0 +1,450 @@
+use crate::{
+    bulk::{extend_collisions, groups},
+    gcas::{gcas, gcas_read},
+    node::{Branch, CtrieNode, IndirectionNode, ListNode, MainNode, MainNodeKind, SingletonNode},
+    Change, Ctrie, Event, Generation, Key, Value,
+};
+use alloc::vec::Vec;
+use core::{hash::BuildHasher, ops::Range};
+use crossbeam::epoch::{Guard, Owned};
+
+/// The keys to remove from a subtree, sorted by path order, with their hashes and the values they
+/// had in the snapshot.
+struct Doomed<'k, K, V> {
+    hashes: &'k [u128],
+    keys: &'k [K],
+    values: &'k [&'k V],
+    // the index of the first key in the whole batch
+    offset: usize,
+}
+
+impl<'k, K: Key, V: Value + PartialEq> Doomed<'k, K, V> {
+    fn slice(&self, range: Range<usize>) -> Self {
+        Self {
+            hashes: &self.hashes[range.clone()],
+            keys: &self.keys[range.clone()],
+            values: &self.values[range.clone()],
+            offset: self.offset + range.start,
+        }
+    }
+
+    /// Returns the position of the key of a singleton if it is doomed and still has the value it
+    /// had in the snapshot.
+    fn position(&self, snode: &SingletonNode<K, V>) -> Option<usize> {
+        let at = self.keys.iter().position(|k| k == snode.key())?;
+        (*self.values[at] == *snode.value()).then_some(at)
+    }
+
+    fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.keys.len()
+    }
+}
+
+impl<K, V, S, const W: usize, S2> Ctrie<K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher + Clone,
+    S2: BuildHasher + Clone,
+{
+    /// Removes every entry for which `f` returns `false`.
+    ///
+    /// `f` is called on the entries of a read-only snapshot taken when `retain` starts, so every
+    /// entry present for the whole call is visited exactly once, and entries inserted during the
+    /// call aren't visited at all. The keys to remove are then removed from the live ctrie with
+    /// one GCAS per C-node holding any of them, contracting C-nodes left with a single entry like
+    /// `remove` does. Keys whose C-nodes are written to concurrently are removed one at a time.
+    ///
+    /// An entry is only removed if it still has the value `f` was called on, so entries updated
+    /// during the call are left in place. Values are compared rather than their addresses, since
+    /// every write after the snapshot copies the C-node it passes through, values included.
+    pub fn retain<F>(&self, mut f: F, guard: &Guard)
+    where
+        F: FnMut(&K, &V) -> bool,
+        V: PartialEq,
+    {
+        let snapshot = self.read_only_snapshot(guard);
+        let mut doomed = snapshot
+            .iter(guard)
+            .singletons()
+            .filter(|snode| !f(snode.key(), snode.value()))
+            .map(|snode| (snode.known_hash(), snode.key().clone(), snode.value()))
+            .collect::<Vec<_>>();
+        if doomed.is_empty() {
+            return;
+        }
+        extend_collisions::<W, _, _, _, _>(&self.hashers, &mut doomed);
+        let mut hashes = Vec::with_capacity(doomed.len());
+        let mut keys = Vec::with_capacity(doomed.len());
+        let mut values = Vec::with_capacity(doomed.len());
+        for (hash, key, value) in doomed {
+            hashes.push(hash);
+            keys.push(key);
+            values.push(value);
+        }
+
+        let mut publisher = self.events.publisher();
+        let mut removed = Vec::new();
+        let mut leftovers = Vec::new();
+        let root = self.read_root(guard);
+        self.iretain(
+            root,
+            Doomed {
+                hashes: &hashes,
+                keys: &keys,
+                values: &values,
+                offset: 0,
+            },
+            0,
+            None,
+            root.generation(),
+            &mut removed,
+            &mut leftovers,
+            guard,
+        );
+        for range in leftovers {
+            for index in range {
+                let expected = values[index];
+                let hash = hashes[index] as u64;
+                if let Some(value) =
+                    self.remove_entry_if(hash, &keys[index], |value| value == expected, guard)
+                {
+                    removed.push((index, value));
+                }
+            }
+        }
+
+        if publisher.active() {
+            for (index, value) in removed {
+                publisher.publish(Event::Change(Change::Removed {
+                    key: keys[index].clone(),
+                    value: value.clone(),
+                }));
+            }
+        }
+    }
+
+    /// Removes a run of sorted keys from the subtree of an i-node, adding the index of every
+    /// removed key and its value to `removed`, and the ranges of the keys that couldn't be removed
+    /// because of concurrent writes to `leftovers`.
+    ///
+    /// Only the hashes of keys that share their primary hash with another key are extended, like
+    /// `extend_collisions` does.
+    #[allow(clippy::too_many_arguments)]
+    fn iretain<'g>(
+        &self,
+        inode: &IndirectionNode<K, V>,
+        doomed: Doomed<'_, K, V>,
+        level: usize,
+        parent: Option<&IndirectionNode<K, V>>,
+        start_generation: &Generation,
+        removed: &mut Vec<(usize, &'g V)>,
+        leftovers: &mut Vec<Range<usize>>,
+        guard: &'g Guard,
+    ) where
+        K: 'g,
+        V: PartialEq,
+    {
+        let main_ptr = gcas_read(inode, self, guard);
+        let main = unsafe { main_ptr.deref() };
+
+        match main.kind() {
+            MainNodeKind::Ctrie(cnode) => {
+                // a key that reaches the first c-node that branches on the secondary hash by
+                // itself doesn't have its whole hash yet
+                let extended;
+                let mut doomed = doomed;
+                if let ([hash], [key]) = (doomed.hashes, doomed.keys) {
+                    if self.hashers.extends_at(level) {
+                        extended = [self.hashers.extend(key, *hash as u64)];
+                        doomed.hashes = &extended;
+                    }
+                }
+
+                let stale = cnode.generation() != inode.generation()
+                    || (0..cnode.branches()).any(|position| match cnode.branch(position) {
+                        Branch::Indirection(child) => child.generation() != start_generation,
+                        Branch::Singleton(_) => false,
+                    });
+                if stale {
+                    // renew the c-node first, like `remove` does
+                    let renewed = cnode.renewed(start_generation.clone(), self, guard);
+                    let new_main_ptr =
+                        Owned::new(MainNode::from_ctrie_node(renewed)).into_shared(guard);
+                    if gcas(inode, main_ptr, new_main_ptr, self, guard) {
+                        self.iretain(
+                            inode,
+                            doomed,
+                            level,
+                            parent,
+                            start_generation,
+                            removed,
+                            leftovers,
+                            guard,
+                        );
+                    } else {
+                        leftovers.push(doomed.range());
+                    }
+                    return;
+                }
+
+                // singletons to remove, with the index of their key and their value
+                let mut singletons = Vec::new();
+                let mut descents = Vec::new();
+                for (index, range) in groups::<W>(doomed.hashes, level) {
+                    let flag = 1 << index;
+                    if cnode.bitmap() & flag == 0 {
+                        continue;
+                    }
+                    let position = (cnode.bitmap() & (flag - 1)).count_ones() as usize;
+                    let group = doomed.slice(range);
+                    match cnode.branch(position) {
+                        Branch::Indirection(child) => descents.push((child, group)),
+                        Branch::Singleton(snode) => {
+                            if let Some(at) = group.position(snode) {
+                                singletons.push((position, group.offset + at, snode.value()));
+                            }
+                        }
+                    }
+                }
+
+                // a non-root c-node can't be empty, so if every branch goes, the last singleton
+                // is left to be entombed and removed afterwards
+                if level > 0 && singletons.len() == cnode.branches() {
+                    let (_, index, _) = singletons.pop().unwrap();
+                    leftovers.push(index..index + 1);
+                }
+                if !singletons.is_empty() {
+                    let mut bitmap = cnode.bitmap();
+                    let mut array = Vec::with_capacity(cnode.branches() - singletons.len());
+                    let mut next = singletons.iter().peekable();
+                    let mut flags = cnode.bitmap();
+                    for position in 0..cnode.branches() {
+                        let flag = flags & flags.wrapping_neg();
+                        flags &= flags - 1;
+                        if next
+                            .next_if(|(removed, _, _)| *removed == position)
+                            .is_some()
+                        {
+                            bitmap &= !flag;
+                        } else {
+                            array.push(cnode.branch(position).clone());
+                        }
+                    }
+                    let new_cnode = CtrieNode::new(bitmap, array, inode.generation().clone());
+                    let new_main_ptr =
+                        Owned::new(new_cnode.to_contracted(level)).into_shared(guard);
+                    if gcas(inode, main_ptr, new_main_ptr, self, guard) {
+                        removed.extend(singletons.iter().map(|&(_, index, value)| (index, value)));
+                        // if the c-node was contracted into a tomb, move the remaining singleton
+                        // node up into the parent
+                        let main_ptr = gcas_read(inode, self, guard);
+                        if let (MainNodeKind::Tomb(_), Some(parent)) =
+                            (unsafe { main_ptr.deref() }.kind(), parent)
+                        {
+                            self.clean_parent(
+                                parent,
+                                inode,
+                                doomed.hashes[0],
+                                level - W,
+                                start_generation,
+                                guard,
+                            );
+                        }
+                    } else {
+                        for (_, index, _) in singletons {
+                            leftovers.push(index..index + 1);
+                        }
+                    }
+                }
+
+                // i-nodes are shared between copies of the c-node, so they can be written to
+                // whether or not the swap above succeeded
+                for (child, group) in descents {
+                    self.iretain(
+                        child,
+                        group,
+                        level + W,
+                        Some(inode),
+                        start_generation,
+                        removed,
+                        leftovers,
+                        guard,
+                    );
+                }
+            }
+
+            MainNodeKind::List(lnode) => {
+                let (mut gone, kept): (Vec<_>, Vec<_>) = lnode
+                    .entries(guard)
+                    .partition(|snode| doomed.position(snode).is_some());
+                if gone.is_empty() {
+                    return;
+                }
+                let new_main = match kept.as_slice() {
+                    // like a c-node, a list can't be emptied in one go, so the last entry is
+                    // entombed and removed afterwards
+                    [] => gone.pop().unwrap().entomb(),
+                    [only] => only.entomb(),
+                    // the kept entries stay in order
+                    _ => {
+                        let entries = kept.into_iter().cloned().collect();
+                        MainNode::from_list_node(ListNode::from_entries(entries))
+                    }
+                };
+                let new_main_ptr = Owned::new(new_main).into_shared(guard);
+                if gcas(inode, main_ptr, new_main_ptr, self, guard) {
+                    for snode in gone {
+                        let at = doomed.position(snode).unwrap();
+                        removed.push((doomed.offset + at, snode.value()));
+                    }
+                    // the entombed entry, if it was doomed, is removed with the leftovers
+                    if let MainNodeKind::Tomb(tnode) = unsafe { new_main_ptr.deref() }.kind() {
+                        if let Some(at) = doomed.position(tnode.snode()) {
+                            let index = doomed.offset + at;
+                            leftovers.push(index..index + 1);
+                        }
+                    }
+                } else {
+                    leftovers.push(doomed.range());
+                }
+            }
+
+            MainNodeKind::Tomb(_) => {
+                // the i-node is about to be removed from its parent, so help clean it up and
+                // leave the keys for later
+                if let Some(parent) = parent {
+                    self.clean(parent, level - W, guard);
+                }
+                leftovers.push(doomed.range());
+            }
+
+            MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::tests::TinyHasher;
+    use core::hash::BuildHasherDefault;
+    use crossbeam::epoch;
+    use std::vec;
+
+    #[test]
+    fn retain() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..2000u32 {
+            ctrie.insert(i, i, guard);
+        }
+
+        let mut visited = vec![0; 2000];
+        ctrie.retain(
+            |&key, _| {
+                visited[key as usize] += 1;
+                key % 10 == 0
+            },
+            guard,
+        );
+        assert!(visited.iter().all(|&count| count == 1));
+        for i in 0..2000 {
+            let expected = (i % 10 == 0).then_some(&i);
+            assert_eq!(ctrie.lookup(&i, guard), expected);
+        }
+        assert_eq!(ctrie.validate(guard), Ok(200));
+
+        ctrie.retain(|_, _| false, guard);
+        assert_eq!(ctrie.validate(guard), Ok(0));
+    }
+
+    #[test]
+    fn retain_collisions() {
+        let ctrie = Ctrie::with_hasher(BuildHasherDefault::<TinyHasher>::default());
+        let guard = &epoch::pin();
+        for keep in 0..4u32 {
+            for i in 0..50u32 {
+                ctrie.insert(i, i, guard);
+            }
+            ctrie.retain(|&key, _| key < keep, guard);
+            assert_eq!(ctrie.validate(guard), Ok(keep as usize));
+        }
+    }
+
+    #[test]
+    fn retain_updated() {
+        fn check<S: BuildHasher + Clone>(ctrie: Ctrie<u32, u32, S>) {
+            let guard = &epoch::pin();
+            for i in 0..300u32 {
+                ctrie.insert(i, i, guard);
+            }
+            // a writer updates doomed keys after the snapshot is taken, or writes them back
+            // unchanged
+            ctrie.retain(
+                |&key, _| {
+                    match key % 3 {
+                        0 => ctrie.insert(key, key + 1, guard),
+                        1 => ctrie.insert(key, key, guard),
+                        _ => {}
+                    }
+                    key % 2 == 0
+                },
+                guard,
+            );
+            for i in 0..300u32 {
+                let expected = match (i % 2, i % 3) {
+                    (_, 0) => Some(i + 1),
+                    (0, _) => Some(i),
+                    _ => None,
+                };
+                assert_eq!(ctrie.lookup(&i, guard).copied(), expected);
+            }
+            assert_eq!(ctrie.validate(guard), Ok(200));
+        }
+
+        check(Ctrie::new());
+        check(Ctrie::with_hasher(
+            BuildHasherDefault::<TinyHasher>::default(),
+        ));
+    }
+
+    #[test]
+    fn concurrent_retain() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..4000u32 {
+            ctrie.insert(i, i, guard);
+        }
+        let changes = ctrie.subscribe();
+
+        std::thread::scope(|scope| {
+            let ctrie = &ctrie;
+            scope.spawn(move || {
+                let guard = &epoch::pin();
+                // writes to keys the predicate keeps, landing in the same c-nodes
+                for i in (0..4000u32).step_by(2) {
+                    ctrie.insert(i, i + 1, guard);
+                    if i % 100 == 0 {
+                        ctrie.snapshot(guard);
+                    }
+                }
+            });
+            scope.spawn(move || {
+                let guard = &epoch::pin();
+                ctrie.retain(|&key, _| key % 2 == 0, guard);
+            });
+        });
+
+        for i in 0..4000 {
+            let value = i + 1;
+            assert_eq!(ctrie.lookup(&i, guard), (i % 2 == 0).then_some(&value));
+        }
+        assert_eq!(ctrie.validate(guard), Ok(2000));
+        let removed = changes
+            .try_iter()
+            .filter(|change| matches!(change, Event::Change(Change::Removed { .. })))
+            .count();
+        assert_eq!(removed, 2000);
+    }
+}
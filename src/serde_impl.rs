@@ -0,0 +1,193 @@
+use crate::{hashers::Hashers, Ctrie, CtrieBuilder, Key, Value};
+use core::{
+    fmt,
+    hash::{BuildHasher, BuildHasherDefault, Hasher},
+    marker::PhantomData,
+};
+use crossbeam::epoch;
+use serde::{
+    de::{DeserializeSeed, MapAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+impl<K, V, S, const W: usize, S2> Serialize for Ctrie<K, V, S, W, S2>
+where
+    K: Key + Serialize,
+    V: Value + Serialize,
+    S: BuildHasher + Clone,
+    S2: BuildHasher + Clone,
+{
+    fn serialize<T: Serializer>(&self, serializer: T) -> Result<T::Ok, T::Error> {
+        let guard = &epoch::pin();
+        // iterate over one snapshot twice, so the length is known up front
+        let snapshot = self.read_only_snapshot(guard);
+        let mut map = serializer.serialize_map(Some(snapshot.iter(guard).count()))?;
+        for (key, value) in snapshot.iter(guard) {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de, K, V, S, const W: usize> Deserialize<'de> for Ctrie<K, V, S, W>
+where
+    K: Key + Deserialize<'de>,
+    V: Value + Deserialize<'de>,
+    S: BuildHasher + Default,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        CtrieSeed::with_hasher(S::default()).deserialize(deserializer)
+    }
+}
+
+/// Deserializes a ctrie that uses the given hasher, or hashers.
+///
+/// Deserializing a ctrie with `Deserialize` always gives it a single default hasher, so a ctrie
+/// created with `Ctrie::with_hashers` or `Ctrie::with_fallback_hasher` has to be deserialized
+/// with the matching constructor of `CtrieSeed` to keep its configuration.
+///
+/// ```
+/// use ctrie::{serde_impl::CtrieSeed, Ctrie};
+/// use serde::de::DeserializeSeed;
+/// use std::collections::hash_map::RandomState;
+///
+/// let mut deserializer = serde_json::Deserializer::from_str(r#"{"a": 1, "b": 2}"#);
+/// let ctrie: Ctrie<String, u32, RandomState> = CtrieSeed::with_hasher(RandomState::new())
+///     .deserialize(&mut deserializer)
+///     .unwrap();
+/// ```
+pub struct CtrieSeed<K, V, S, const W: usize = 6, S2 = S> {
+    hashers: Hashers<S, S2>,
+    marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V, S: BuildHasher, const W: usize> CtrieSeed<K, V, S, W> {
+    pub fn with_hasher(hash_builder: S) -> Self {
+        Self::with_hashers_of(Hashers::new(hash_builder))
+    }
+}
+
+impl<K, V, S: BuildHasher, const W: usize, S2: BuildHasher> CtrieSeed<K, V, S, W, S2> {
+    /// Deserializes a ctrie like one created with `Ctrie::with_hashers`.
+    pub fn with_hashers(primary: S, secondary: S2) -> Self {
+        Self::with_hashers_of(Hashers::with_secondary::<W>(primary, secondary, 0))
+    }
+
+    /// Deserializes a ctrie like one created with `Ctrie::with_fallback_hasher`.
+    pub fn with_fallback_hasher(primary: S, secondary: S2, limit: usize) -> Self {
+        Self::with_hashers_of(Hashers::with_secondary::<W>(primary, secondary, limit))
+    }
+
+    fn with_hashers_of(hashers: Hashers<S, S2>) -> Self {
+        Self {
+            hashers,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, S, const W: usize> Default for CtrieSeed<K, V, S, W>
+where
+    S: BuildHasher + Default,
+{
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K, V, H: Default + Hasher> CtrieSeed<K, V, BuildHasherDefault<H>> {
+    pub fn new() -> Self {
+        Self::with_hasher(BuildHasherDefault::default())
+    }
+}
+
+impl<'de, K, V, S, const W: usize, S2> DeserializeSeed<'de> for CtrieSeed<K, V, S, W, S2>
+where
+    K: Key + Deserialize<'de>,
+    V: Value + Deserialize<'de>,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    type Value = Ctrie<K, V, S, W, S2>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(CtrieVisitor(self))
+    }
+}
+
+struct CtrieVisitor<K, V, S, const W: usize, S2>(CtrieSeed<K, V, S, W, S2>);
+
+impl<'de, K, V, S, const W: usize, S2> Visitor<'de> for CtrieVisitor<K, V, S, W, S2>
+where
+    K: Key + Deserialize<'de>,
+    V: Value + Deserialize<'de>,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    type Value = Ctrie<K, V, S, W, S2>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let mut builder = CtrieBuilder::with_hashers_of(self.0.hashers);
+        builder.reserve(access.size_hint().unwrap_or(0));
+        while let Some((key, value)) = access.next_entry()? {
+            builder.insert(key, value);
+        }
+        Ok(builder.build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hashers::tests::{lists, Tiny},
+        DefaultHashBuilder,
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    fn round_trip() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..100u32 {
+            ctrie.insert(i, i.to_string(), guard);
+        }
+
+        let json = serde_json::to_string(&ctrie).unwrap();
+        let map: HashMap<u32, String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(map.len(), 100);
+
+        let ctrie: Ctrie<u32, String> = serde_json::from_str(&json).unwrap();
+        for i in 0..100u32 {
+            assert_eq!(ctrie.lookup(&i, guard), Some(&i.to_string()));
+        }
+        assert_eq!(ctrie.validate(guard), Ok(100));
+    }
+
+    #[test]
+    fn seed_keeps_hashers() {
+        let ctrie = Ctrie::with_fallback_hasher(Tiny::default(), DefaultHashBuilder::default(), 4);
+        let guard = &epoch::pin();
+        for i in 0..200u32 {
+            ctrie.insert(i, i, guard);
+        }
+
+        let json = serde_json::to_string(&ctrie).unwrap();
+        let mut deserializer = serde_json::Deserializer::from_str(&json);
+        let loaded: Ctrie<u32, u32, Tiny, 6, DefaultHashBuilder> =
+            CtrieSeed::with_fallback_hasher(Tiny::default(), DefaultHashBuilder::default(), 4)
+                .deserialize(&mut deserializer)
+                .unwrap();
+        // the colliding primary hashes are still spread by the secondary hasher
+        assert!(lists(&loaded, loaded.read_root(guard), guard)
+            .iter()
+            .all(|&len| len <= 4));
+        assert_eq!(loaded.validate(guard), Ok(200));
+        assert_eq!(loaded.diff(&ctrie, guard).count(), 0);
+    }
+}
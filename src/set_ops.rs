@@ -0,0 +1,500 @@
+use crate::{
+    bulk::{build_branch, extend_collisions, sorted_entries},
+    gcas::gcas_read,
+    hash_index,
+    node::{Branch, CtrieNode, IndirectionNode, MainNode, MainNodeKind, SingletonNode},
+    Ctrie, Generation, Key, Value,
+};
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::{hash::BuildHasher, ptr};
+use crossbeam::epoch::{Atomic, Guard};
+
+/// Walks the read-only snapshots of two ctries in lockstep, building the trie of a set operation.
+struct Combine<'a, 'g, K, V, S, const W: usize, S2, F> {
+    left: &'a Ctrie<K, V, S, W, S2>,
+    right: &'a Ctrie<K, V, S, W, S2>,
+    // whether keys only in one of the ctries are kept
+    keep_left: bool,
+    keep_right: bool,
+    // the value of a key in both ctries, or `None` if such keys are dropped
+    both: Option<F>,
+    generation: Generation,
+    guard: &'g Guard,
+}
+
+impl<'a, 'g, K, V, S, F, const W: usize, S2> Combine<'a, 'g, K, V, S, W, S2, F>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+    F: Fn(&K, &V, &V) -> V,
+{
+    fn main(
+        &self,
+        ctrie: &Ctrie<K, V, S, W, S2>,
+        inode: &IndirectionNode<K, V>,
+    ) -> &'g MainNode<K, V> {
+        unsafe { gcas_read(inode, ctrie, self.guard).deref() }
+    }
+
+    /// Combines two C-nodes at `level`, given the branches of both sides with each flag, returning
+    /// the bitmap and branches of the result.
+    fn cnodes<B>(&self, branches: B, level: usize) -> (u64, Vec<Branch<K, V>>)
+    where
+        K: 'g,
+        V: 'g,
+        B: Fn(u64) -> (Option<&'g Branch<K, V>>, Option<&'g Branch<K, V>>),
+    {
+        let mut bitmap = 0;
+        let mut array = vec![];
+        for index in 0..1 << W {
+            let flag = 1u64 << index;
+            let (left, right) = branches(flag);
+            if let Some(branch) = self.branches(left, right, level + W) {
+                bitmap |= flag;
+                array.push(branch);
+            }
+        }
+        (bitmap, array)
+    }
+
+    /// Returns the branch holding a combined C-node below the root.
+    fn cnode_branch(&self, bitmap: u64, mut array: Vec<Branch<K, V>>) -> Option<Branch<K, V>> {
+        match array.len() {
+            0 => None,
+            // contract a lone singleton into its parent, like removals do
+            1 if matches!(array[0], Branch::Singleton(_)) => array.pop(),
+            _ => Some(Branch::Indirection(Arc::new(IndirectionNode::new(
+                Atomic::new(MainNode::from_ctrie_node(CtrieNode::new(
+                    bitmap,
+                    array,
+                    self.generation.clone(),
+                ))),
+                self.generation.clone(),
+            )))),
+        }
+    }
+
+    /// Combines a singleton with a subtree on the other side whose main node is at `level`.
+    ///
+    /// If the subtree is a C-node, only the branch on the path of the singleton's hash is
+    /// combined, and the others are reused or dropped like any branch only one side has.
+    fn singleton_subtree(
+        &self,
+        singleton: &'g Branch<K, V>,
+        snode: &'g SingletonNode<K, V>,
+        ctrie: &Ctrie<K, V, S, W, S2>,
+        inode: &'g IndirectionNode<K, V>,
+        singleton_left: bool,
+        level: usize,
+    ) -> Option<Branch<K, V>> {
+        let main = self.main(ctrie, inode);
+        let MainNodeKind::Ctrie(cnode) = main.kind() else {
+            let entries = self.main_entries(ctrie, main);
+            return if singleton_left {
+                self.entries(vec![snode], entries, level)
+            } else {
+                self.entries(entries, vec![snode], level)
+            };
+        };
+        let hash = self
+            .left
+            .hashers
+            .hash_at(snode.key(), snode.known_hash(), level);
+        let path = 1u64 << hash_index::<W>(hash, level);
+        let (bitmap, array) = self.cnodes(
+            |flag| {
+                let branch = child(cnode, flag);
+                let singleton = (flag == path).then_some(singleton);
+                if singleton_left {
+                    (singleton, branch)
+                } else {
+                    (branch, singleton)
+                }
+            },
+            level,
+        );
+        self.cnode_branch(bitmap, array)
+    }
+
+    /// Combines two branches whose main nodes, if any, are at `level`.
+    fn branches(
+        &self,
+        left: Option<&'g Branch<K, V>>,
+        right: Option<&'g Branch<K, V>>,
+        level: usize,
+    ) -> Option<Branch<K, V>> {
+        match (left, right) {
+            (None, None) => None,
+            // whole subtrees on one side are reused as they are
+            (Some(left), None) => self.keep_left.then(|| self.reuse(self.left, left)),
+            (None, Some(right)) => self.keep_right.then(|| self.reuse(self.right, right)),
+            (Some(Branch::Indirection(left)), Some(Branch::Indirection(right))) => {
+                let left_main = self.main(self.left, left);
+                let right_main = self.main(self.right, right);
+                // every key of a subtree shared by both sides is in both
+                if ptr::eq(left_main, right_main) && self.both.is_none() {
+                    return None;
+                }
+                match (left_main.kind(), right_main.kind()) {
+                    (MainNodeKind::Ctrie(left), MainNodeKind::Ctrie(right)) => {
+                        let (bitmap, array) =
+                            self.cnodes(|flag| (child(left, flag), child(right, flag)), level);
+                        self.cnode_branch(bitmap, array)
+                    }
+                    _ => self.entries(
+                        self.main_entries(self.left, left_main),
+                        self.main_entries(self.right, right_main),
+                        level,
+                    ),
+                }
+            }
+            // descend into a subtree along the path of a singleton on the other side
+            (Some(singleton @ Branch::Singleton(snode)), Some(Branch::Indirection(right))) => {
+                self.singleton_subtree(singleton, snode, self.right, right, true, level)
+            }
+            (Some(Branch::Indirection(left)), Some(singleton @ Branch::Singleton(snode))) => {
+                self.singleton_subtree(singleton, snode, self.left, left, false, level)
+            }
+            (Some(left), Some(right)) => self.entries(
+                self.branch_entries(self.left, left),
+                self.branch_entries(self.right, right),
+                level,
+            ),
+        }
+    }
+
+    /// Combines the entries of two subtrees whose main nodes are at `level`.
+    fn entries(
+        &self,
+        left: Vec<&'g SingletonNode<K, V>>,
+        mut right: Vec<&'g SingletonNode<K, V>>,
+        level: usize,
+    ) -> Option<Branch<K, V>> {
+        let mut entries = vec![];
+        for left in left {
+            match right.iter().position(|right| right.key() == left.key()) {
+                Some(position) => {
+                    let right = right.swap_remove(position);
+                    if let Some(both) = &self.both {
+                        let value = both(left.key(), left.value(), right.value());
+                        entries.push((left.known_hash(), left.key().clone(), value));
+                    }
+                }
+                None if self.keep_left => {
+                    entries.push((left.known_hash(), left.key().clone(), left.value().clone()))
+                }
+                None => {}
+            }
+        }
+        if self.keep_right {
+            entries.extend(right.into_iter().map(|right| {
+                (
+                    right.known_hash(),
+                    right.key().clone(),
+                    right.value().clone(),
+                )
+            }));
+        }
+        if entries.is_empty() {
+            return None;
+        }
+
+        extend_collisions::<W, _, _, _, _>(&self.left.hashers, &mut entries);
+        let (hashes, pairs) = sorted_entries::<W, _, _>(entries);
+        Some(build_branch::<W, _, _, _, _, _>(
+            &hashes,
+            &mut pairs.into_iter(),
+            level,
+            &self.left.hashers,
+            &self.generation,
+        ))
+    }
+
+    /// Returns a branch of one of the snapshots to put in the new trie.
+    fn reuse(&self, ctrie: &Ctrie<K, V, S, W, S2>, branch: &'g Branch<K, V>) -> Branch<K, V> {
+        if let Branch::Indirection(inode) = branch {
+            // an entombed singleton would otherwise wait for a write to resurrect it
+            if let MainNodeKind::Tomb(tnode) = self.main(ctrie, inode).kind() {
+                return Branch::Singleton(tnode.untombed());
+            }
+        }
+        branch.clone()
+    }
+
+    fn branch_entries(
+        &self,
+        ctrie: &Ctrie<K, V, S, W, S2>,
+        branch: &'g Branch<K, V>,
+    ) -> Vec<&'g SingletonNode<K, V>> {
+        match branch {
+            Branch::Singleton(snode) => vec![snode],
+            Branch::Indirection(inode) => self.main_entries(ctrie, self.main(ctrie, inode)),
+        }
+    }
+
+    fn main_entries(
+        &self,
+        ctrie: &Ctrie<K, V, S, W, S2>,
+        main: &'g MainNode<K, V>,
+    ) -> Vec<&'g SingletonNode<K, V>> {
+        match main.kind() {
+            MainNodeKind::Ctrie(cnode) => (0..cnode.branches())
+                .flat_map(|position| self.branch_entries(ctrie, cnode.branch(position)))
+                .collect(),
+            MainNodeKind::List(lnode) => lnode.entries(self.guard).collect(),
+            MainNodeKind::Tomb(tnode) => vec![tnode.snode()],
+            MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
+        }
+    }
+}
+
+/// Returns the branch of a C-node with the given flag, if it has one.
+fn child<K: Key, V: Value>(cnode: &CtrieNode<K, V>, flag: u64) -> Option<&Branch<K, V>> {
+    (cnode.bitmap() & flag != 0)
+        .then(|| cnode.branch((cnode.bitmap() & (flag - 1)).count_ones() as usize))
+}
+
+impl<K, V, S, const W: usize, S2> Ctrie<K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher + Clone,
+    S2: BuildHasher + Clone,
+{
+    /// Returns a new ctrie with the entries of both this ctrie and `other`, calling `resolve`
+    /// with the key and both values for keys in both.
+    ///
+    /// Reads read-only snapshots of both ctries, which must use the same hasher. The snapshots
+    /// are walked in lockstep, and subtrees only one of them has are shared with the result
+    /// rather than copied.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ctries hash keys differently, like two ctries created with their own
+    /// `RandomState`s.
+    pub fn union<F>(&self, other: &Self, resolve: F, guard: &Guard) -> Self
+    where
+        F: Fn(&K, &V, &V) -> V,
+    {
+        self.combine(other, true, true, Some(resolve), guard)
+    }
+
+    /// Returns a new ctrie with the keys in both this ctrie and `other`, whose values are
+    /// returned by `combine` given the key and both values.
+    ///
+    /// Like `union`, reads read-only snapshots of both ctries, and panics if they hash keys
+    /// differently.
+    pub fn intersection_with<F>(&self, other: &Self, combine: F, guard: &Guard) -> Self
+    where
+        F: Fn(&K, &V, &V) -> V,
+    {
+        self.combine(other, false, false, Some(combine), guard)
+    }
+
+    /// Returns a new ctrie with the entries of this ctrie whose keys aren't in `other`.
+    ///
+    /// Like `union`, reads read-only snapshots of both ctries, and panics if they hash keys
+    /// differently. Subtrees the snapshots share are skipped entirely.
+    pub fn difference(&self, other: &Self, guard: &Guard) -> Self {
+        self.combine(other, true, false, None::<fn(&K, &V, &V) -> V>, guard)
+    }
+
+    fn combine<F>(
+        &self,
+        other: &Self,
+        keep_left: bool,
+        keep_right: bool,
+        both: Option<F>,
+        guard: &Guard,
+    ) -> Self
+    where
+        F: Fn(&K, &V, &V) -> V,
+    {
+        assert!(
+            self.hashers.same_as(&other.hashers),
+            "can't combine ctries that hash keys differently"
+        );
+        let left = self.read_only_snapshot(guard);
+        let right = other.read_only_snapshot(guard);
+        let combine = Combine {
+            left: &left,
+            right: &right,
+            keep_left,
+            keep_right,
+            both,
+            generation: Generation::new(),
+            guard,
+        };
+        let root_cnode = |ctrie: &Self| match combine.main(ctrie, ctrie.read_root(guard)).kind() {
+            MainNodeKind::Ctrie(cnode) => cnode,
+            _ => unreachable!("the root main node is always a c-node"),
+        };
+        let (left_root, right_root) = (root_cnode(&left), root_cnode(&right));
+        let (bitmap, array) =
+            combine.cnodes(|flag| (child(left_root, flag), child(right_root, flag)), 0);
+        Self::from_root(
+            IndirectionNode::new(
+                Atomic::new(MainNode::from_ctrie_node(CtrieNode::new(
+                    bitmap,
+                    array,
+                    combine.generation.clone(),
+                ))),
+                combine.generation,
+            ),
+            false,
+            self.hashers.clone(),
+        )
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{tests::TinyHasher, RandomStateCtrie};
+    use core::hash::BuildHasherDefault;
+    use crossbeam::epoch::{self, Guard};
+    use std::{collections::HashMap, vec::Vec};
+
+    fn contents<S: BuildHasher + Clone>(ctrie: &Ctrie<u32, u32, S>) -> HashMap<u32, u32> {
+        let guard = &epoch::pin();
+        ctrie.iter(guard).map(|(&k, &v)| (k, v)).collect()
+    }
+
+    fn check<S: BuildHasher + Clone + Default>() {
+        let a = Ctrie::with_hasher(S::default());
+        let guard = &epoch::pin();
+        for i in 0..600u32 {
+            a.insert(i, i, guard);
+        }
+        // b shares most of its subtrees with a
+        let b = a.snapshot(guard);
+        for i in (0..600).step_by(5) {
+            b.remove(&i, guard);
+        }
+        for i in 600..800 {
+            b.insert(i, 1, guard);
+        }
+        for i in (0..600).step_by(7) {
+            b.insert(i, 2, guard);
+        }
+        let (a_map, b_map) = (contents(&a), contents(&b));
+
+        let union = a.union(&b, |_, x, y| x + y, guard);
+        let mut expected = a_map.clone();
+        for (k, v) in &b_map {
+            *expected.entry(*k).or_insert(0) += v;
+        }
+        assert_eq!(contents(&union), expected);
+        assert_eq!(union.validate(guard), Ok(expected.len()));
+
+        let intersection = a.intersection_with(&b, |_, x, y| x * 10 + y, guard);
+        let expected = a_map
+            .iter()
+            .filter_map(|(k, x)| b_map.get(k).map(|y| (*k, x * 10 + y)))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(contents(&intersection), expected);
+        assert_eq!(intersection.validate(guard), Ok(expected.len()));
+
+        let difference = b.difference(&a, guard);
+        let expected = b_map
+            .iter()
+            .filter(|(k, _)| !a_map.contains_key(k))
+            .map(|(k, v)| (*k, *v))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(contents(&difference), expected);
+        assert_eq!(difference.validate(guard), Ok(200));
+        assert_eq!(a.difference(&a, guard).validate(guard), Ok(0));
+
+        // the results are independent of the inputs
+        union.insert(0, 100, guard);
+        difference.remove(&600, guard);
+        assert_eq!(contents(&a), a_map);
+        assert_eq!(contents(&b), b_map);
+        assert_eq!(union.validate(guard), Ok(800));
+    }
+
+    /// Collects the i-nodes in the subtree of an i-node.
+    fn inodes<S: BuildHasher>(
+        ctrie: &Ctrie<u32, u32, S>,
+        inode: &IndirectionNode<u32, u32>,
+        guard: &Guard,
+        found: &mut Vec<*const IndirectionNode<u32, u32>>,
+    ) {
+        if let MainNodeKind::Ctrie(cnode) = read_main(ctrie, inode, guard).kind() {
+            for position in 0..cnode.branches() {
+                if let Branch::Indirection(child) = cnode.branch(position) {
+                    found.push(Arc::as_ptr(child));
+                    inodes(ctrie, child, guard, found);
+                }
+            }
+        }
+    }
+
+    fn read_main<'g, S: BuildHasher>(
+        ctrie: &Ctrie<u32, u32, S>,
+        inode: &IndirectionNode<u32, u32>,
+        guard: &'g Guard,
+    ) -> &'g MainNode<u32, u32> {
+        unsafe { gcas_read(inode, ctrie, guard).deref() }
+    }
+
+    #[test]
+    fn singleton_subtree() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..5000u32 {
+            ctrie.insert(i, i, guard);
+        }
+        let mut before = vec![];
+        inodes(&ctrie, ctrie.read_root(guard), guard, &mut before);
+
+        // a single key lands next to a large subtree of the other side
+        let one = Ctrie::with_hasher(ctrie.hasher().clone());
+        one.insert(5000, 5000, guard);
+        one.insert(7, 0, guard);
+        for (result, len) in [
+            (ctrie.union(&one, |_, _, &value| value, guard), 5001),
+            (one.union(&ctrie, |_, &value, _| value, guard), 5001),
+            (ctrie.difference(&one, guard), 4999),
+            (one.difference(&ctrie, guard), 1),
+            (
+                ctrie.intersection_with(&one, |_, _, &value| value, guard),
+                1,
+            ),
+        ] {
+            assert_eq!(result.validate(guard), Ok(len));
+            // only the i-nodes on the paths to the keys are rebuilt
+            let mut after = vec![];
+            inodes(&result, result.read_root(guard), guard, &mut after);
+            let rebuilt = after.iter().filter(|inode| !before.contains(inode)).count();
+            assert!(rebuilt <= 6, "{} i-nodes rebuilt", rebuilt);
+        }
+        let union = ctrie.union(&one, |_, _, &value| value, guard);
+        assert_eq!(union.lookup(&7, guard), Some(&0));
+        assert_eq!(union.lookup(&5000, guard), Some(&5000));
+        assert_eq!(union.lookup(&8, guard), Some(&8));
+    }
+
+    #[test]
+    fn set_ops() {
+        check::<BuildHasherDefault<crate::FxHasher>>();
+    }
+
+    #[test]
+    fn set_ops_collisions() {
+        check::<BuildHasherDefault<TinyHasher>>();
+    }
+
+    #[test]
+    #[should_panic(expected = "can't combine ctries that hash keys differently")]
+    fn mismatched_hashers() {
+        let guard = &epoch::pin();
+        let a = RandomStateCtrie::with_random_state();
+        let b = RandomStateCtrie::with_random_state();
+        a.insert(1, 1, guard);
+        b.insert(2, 2, guard);
+        a.union(&b, |_, _, &value| value, guard);
+    }
+}
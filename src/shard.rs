@@ -0,0 +1,265 @@
+use crate::{
+    gcas::gcas_read,
+    node::{Branch, CtrieNode, IndirectionNode, MainNode, MainNodeKind, SingletonNode},
+    Ctrie, Generation, Key, Value,
+};
+use alloc::{vec, vec::Vec};
+use core::hash::BuildHasher;
+use crossbeam::epoch::{Atomic, Guard};
+
+impl<K, V, S, const W: usize, S2> Ctrie<K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher + Clone,
+    S2: BuildHasher + Clone,
+{
+    /// Splits a read-only snapshot of the ctrie into `2^bits` ctries, where the ctrie at index
+    /// `i` holds the keys whose hashes have `i` as their lowest `bits` bits.
+    ///
+    /// The root C-node branches on the lowest bits of the hash first, so every shard is made of
+    /// a subset of its branches, and splitting takes time proportional to the fan-out of the root
+    /// rather than to the number of keys. The shards share their subtrees with the ctrie and with
+    /// each other until they are written to, like snapshots do.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bits` is greater than `W`, the number of bits the root C-node branches on.
+    pub fn split_by_prefix(&self, bits: usize, guard: &Guard) -> Vec<Self> {
+        assert!(bits <= W, "can't split on more than {} bits", W);
+        let snapshot = self.read_only_snapshot(guard);
+        let root = root_cnode(&snapshot, guard);
+
+        let mask = (1 << bits) - 1;
+        let mut shards = vec![(0, Vec::new()); 1 << bits];
+        let mut bitmap = root.bitmap();
+        let mut position = 0;
+        while bitmap != 0 {
+            let index = bitmap.trailing_zeros() as usize;
+            let flag = bitmap & bitmap.wrapping_neg();
+            bitmap &= bitmap - 1;
+            let (shard_bitmap, array) = &mut shards[index & mask];
+            *shard_bitmap |= flag;
+            array.push(root.branch(position).clone());
+            position += 1;
+        }
+
+        shards
+            .into_iter()
+            .map(|(bitmap, array)| self.with_root_branches(bitmap, array))
+            .collect()
+    }
+
+    /// Merges ctries with disjoint keys into one, like the shards returned by `split_by_prefix`.
+    ///
+    /// Reads a read-only snapshot of every ctrie. Root branches that only one of the ctries has
+    /// are reused as they are, so merging the shards of a split takes time proportional to the
+    /// fan-out of the root. Where several ctries have the same root branch, the entries of all
+    /// but the first are inserted one by one. If a key is in more than one ctrie, the value from
+    /// the last one wins.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tries` is empty, since there'd be no hasher for the result, or if the ctries
+    /// hash keys differently.
+    pub fn merge_disjoint(tries: &[Self], guard: &Guard) -> Self {
+        let first = tries.first().expect("can't merge an empty list of ctries");
+        assert!(
+            tries
+                .iter()
+                .all(|ctrie| ctrie.hashers.same_as(&first.hashers)),
+            "can't merge ctries that hash keys differently"
+        );
+        let snapshots = tries
+            .iter()
+            .map(|ctrie| ctrie.read_only_snapshot(guard))
+            .collect::<Vec<_>>();
+
+        let mut branches = vec![None; 1 << W];
+        // root branches taken by an earlier ctrie, with the snapshot they're from
+        let mut overlapping = Vec::new();
+        for snapshot in &snapshots {
+            let root = root_cnode(snapshot, guard);
+            let mut bitmap = root.bitmap();
+            let mut position = 0;
+            while bitmap != 0 {
+                let index = bitmap.trailing_zeros() as usize;
+                bitmap &= bitmap - 1;
+                let branch = root.branch(position);
+                position += 1;
+                match &branches[index] {
+                    None => branches[index] = Some(branch.clone()),
+                    Some(_) => overlapping.push((snapshot, branch)),
+                }
+            }
+        }
+
+        let mut bitmap = 0;
+        let mut array = Vec::new();
+        for (index, branch) in branches.into_iter().enumerate() {
+            if let Some(branch) = branch {
+                bitmap |= 1 << index;
+                array.push(branch);
+            }
+        }
+        let merged = first.with_root_branches(bitmap, array);
+
+        let mut entries = Vec::new();
+        for (snapshot, branch) in overlapping {
+            branch_entries(snapshot, branch, &mut entries, guard);
+        }
+        for (hash, key, value) in entries {
+            merged.insert_with_hash(hash, key, value, guard);
+        }
+        merged
+    }
+
+    /// Returns a new ctrie with the hasher of this one, whose root C-node has the given branches.
+    ///
+    /// The root gets a new generation, so the branches are copied before they are written to.
+    fn with_root_branches(&self, bitmap: u64, array: Vec<Branch<K, V>>) -> Self {
+        let generation = Generation::new();
+        let cnode = CtrieNode::new(bitmap, array, generation.clone());
+        Self::from_root(
+            IndirectionNode::new(Atomic::new(MainNode::from_ctrie_node(cnode)), generation),
+            false,
+            self.hashers.clone(),
+        )
+    }
+}
+
+fn root_cnode<'g, K, V, S, const W: usize, S2>(
+    snapshot: &Ctrie<K, V, S, W, S2>,
+    guard: &'g Guard,
+) -> &'g CtrieNode<K, V>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    let main_ptr = gcas_read(snapshot.read_root(guard), snapshot, guard);
+    match unsafe { main_ptr.deref() }.kind() {
+        MainNodeKind::Ctrie(cnode) => cnode,
+        _ => unreachable!("the root main node is always a c-node"),
+    }
+}
+
+/// Collects every entry in a branch of a read-only snapshot, with its cached hash.
+fn branch_entries<K, V, S, const W: usize, S2>(
+    snapshot: &Ctrie<K, V, S, W, S2>,
+    branch: &Branch<K, V>,
+    entries: &mut Vec<(u64, K, V)>,
+    guard: &Guard,
+) where
+    K: Key,
+    V: Value,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    let inode = match branch {
+        Branch::Singleton(snode) => {
+            entries.push(entry(snode));
+            return;
+        }
+        Branch::Indirection(inode) => inode,
+    };
+    let main_ptr = gcas_read(inode, snapshot, guard);
+    match unsafe { main_ptr.deref() }.kind() {
+        MainNodeKind::Ctrie(cnode) => {
+            for position in 0..cnode.branches() {
+                branch_entries(snapshot, cnode.branch(position), entries, guard);
+            }
+        }
+        MainNodeKind::List(lnode) => entries.extend(lnode.entries(guard).map(entry)),
+        MainNodeKind::Tomb(tnode) => entries.push(entry(tnode.snode())),
+        MainNodeKind::Failed => unreachable!("gcas_read never returns a failed node"),
+    }
+}
+
+fn entry<K: Key, V: Value>(snode: &SingletonNode<K, V>) -> (u64, K, V) {
+    (snode.hash(), snode.key().clone(), snode.value().clone())
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::RandomStateCtrie;
+    use crossbeam::epoch;
+
+    #[test]
+    fn split_and_merge() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..2000u32 {
+            ctrie.insert(i, i, guard);
+        }
+
+        let shards = ctrie.split_by_prefix(2, guard);
+        assert_eq!(shards.len(), 4);
+        let mut total = 0;
+        for (i, shard) in shards.iter().enumerate() {
+            let count = shard.validate(guard).unwrap();
+            assert!(shard
+                .iter(guard)
+                .all(|(key, _)| ctrie.hash(key) & 0b11 == i as u128));
+            total += count;
+        }
+        assert_eq!(total, 2000);
+
+        // shards are independent of the ctrie and of each other
+        for (i, shard) in shards.iter().enumerate() {
+            for key in 0..2000 {
+                if ctrie.hash(&key) & 0b11 == i as u128 {
+                    shard.insert(key, key + 1, guard);
+                }
+            }
+            shard.remove(&0, guard);
+        }
+        assert!(ctrie.iter(guard).all(|(k, v)| k == v));
+        assert_eq!(ctrie.validate(guard), Ok(2000));
+
+        let merged = Ctrie::merge_disjoint(&shards, guard);
+        assert_eq!(merged.validate(guard), Ok(1999));
+        assert!(merged.iter(guard).all(|(&k, &v)| v == k + 1));
+        assert_eq!(merged.lookup(&0, guard), None);
+
+        // the original splits and merges back into itself
+        let merged = Ctrie::merge_disjoint(&ctrie.split_by_prefix(6, guard), guard);
+        assert_eq!(merged.diff(&ctrie, guard).count(), 0);
+        assert_eq!(ctrie.split_by_prefix(0, guard).len(), 1);
+    }
+
+    #[test]
+    fn merge_overlapping() {
+        let evens = Ctrie::new();
+        let odds = Ctrie::with_hasher(evens.hasher().clone());
+        let guard = &epoch::pin();
+        for i in 0..500u32 {
+            if i % 2 == 0 { &evens } else { &odds }.insert(i, i, guard);
+        }
+
+        let merged = Ctrie::merge_disjoint(&[evens, odds], guard);
+        for i in 0..500 {
+            assert_eq!(merged.lookup(&i, guard), Some(&i));
+        }
+        assert_eq!(merged.validate(guard), Ok(500));
+    }
+
+    #[test]
+    #[should_panic(expected = "can't merge ctries that hash keys differently")]
+    fn merge_mismatched_hashers() {
+        let guard = &epoch::pin();
+        let a = RandomStateCtrie::with_random_state();
+        a.insert(1, 1, guard);
+        // a clone of the hasher hashes keys the same way
+        let b = Ctrie::with_hasher(a.hasher().clone());
+        b.insert(2, 2, guard);
+        let merged = Ctrie::merge_disjoint(&[a, b], guard);
+        assert_eq!(merged.validate(guard), Ok(2));
+
+        let c = RandomStateCtrie::with_random_state();
+        c.insert(3, 3, guard);
+        Ctrie::merge_disjoint(&[merged, c], guard);
+    }
+}
@@ -0,0 +1,372 @@
+use crate::{
+    gcas::gcas_read,
+    node::{IndirectionNode, RootNode},
+    rdcss::{rdcss, rdcss_read_root},
+    Change, Ctrie, Event, Generation, Key, Value,
+};
+use alloc::vec::Vec;
+use core::hash::BuildHasher;
+use crossbeam::epoch::{Atomic, Guard};
+
+/// A set of reads and writes that take effect atomically.
+///
+/// Reads see a read-only snapshot of the ctrie taken when the transaction started, along with the
+/// transaction's own writes. Writes are buffered until the transaction commits.
+pub struct Transaction<'g, K, V, S, const W: usize = 6, S2 = S> {
+    snapshot: Ctrie<K, V, S, W, S2>,
+    // every key read from the snapshot, with the value it had
+    reads: Vec<(K, Option<&'g V>)>,
+    // the last write to every key written, in the order the keys were first written
+    writes: Vec<(K, Option<V>)>,
+    guard: &'g Guard,
+}
+
+impl<'g, K, V, S, const W: usize, S2> Transaction<'g, K, V, S, W, S2>
+where
+    K: Key + 'g,
+    V: Value + 'g,
+    S: BuildHasher,
+    S2: BuildHasher,
+{
+    fn new(snapshot: Ctrie<K, V, S, W, S2>, guard: &'g Guard) -> Self {
+        debug_assert!(snapshot.read_only());
+        Self {
+            snapshot,
+            reads: Vec::new(),
+            writes: Vec::new(),
+            guard,
+        }
+    }
+
+    /// Looks up the value of a key, as of the start of the transaction or the transaction's last
+    /// write to it.
+    ///
+    /// If another write to the key commits before the transaction does, the transaction is
+    /// retried.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        if let Some(position) = self.writes.iter().position(|(k, _)| k == key) {
+            return self.writes[position].1.as_ref();
+        }
+        let value = self.snapshot.lookup(key, self.guard);
+        if !self.reads.iter().any(|(k, _)| k == key) {
+            self.reads.push((key.clone(), value));
+        }
+        value
+    }
+
+    /// Inserts a key-value pair when the transaction commits.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.write(key, Some(value));
+    }
+
+    /// Removes a key when the transaction commits.
+    pub fn remove(&mut self, key: &K) {
+        self.write(key.clone(), None);
+    }
+
+    fn write(&mut self, key: K, value: Option<V>) {
+        match self.writes.iter_mut().find(|(k, _)| *k == key) {
+            Some(write) => write.1 = value,
+            None => self.writes.push((key, value)),
+        }
+    }
+}
+
+impl<K, V, S, const W: usize, S2> Ctrie<K, V, S, W, S2>
+where
+    K: Key,
+    V: Value,
+    S: BuildHasher + Clone,
+    S2: BuildHasher + Clone,
+{
+    /// Runs `f` as a transaction, committing all of its writes atomically, and returns its result.
+    ///
+    /// Transactions are optimistic. `f` runs against a read-only snapshot, and its writes are
+    /// applied to a copy of the ctrie that replaces the root in a single swap. If a key read by
+    /// `f` has a different value by then, `f` runs again against a new snapshot, so it may run
+    /// several times and must not modify the ctrie directly. Writes to other keys don't make it
+    /// run again.
+    ///
+    /// Committing moves the ctrie to a new generation, like taking a snapshot does, so the next
+    /// write to every C-node copies it, whichever thread makes it.
+    pub fn transaction<'g, F, R>(&self, mut f: F, guard: &'g Guard) -> R
+    where
+        F: FnMut(&mut Transaction<'g, K, V, S, W, S2>) -> R,
+        K: 'g,
+        V: PartialEq + 'g,
+    {
+        loop {
+            let mut tx = Transaction::new(self.read_only_snapshot(guard), guard);
+            let result = f(&mut tx);
+            if tx.writes.is_empty() || self.commit(&tx, guard) {
+                return result;
+            }
+        }
+    }
+
+    /// Tries to commit the writes of a transaction, and returns whether it succeeded, or whether
+    /// a key it read changed, so it has to run again.
+    fn commit<'g>(&self, tx: &Transaction<'g, K, V, S, W, S2>, guard: &'g Guard) -> bool
+    where
+        K: 'g,
+        V: PartialEq + 'g,
+    {
+        let mut publisher = self.events.publisher();
+        loop {
+            // move the ctrie to a new generation, like a snapshot does, so writes that started
+            // before can no longer commit, and every write after changes the main node of the root
+            let root_ptr = rdcss_read_root(self, false, guard);
+            let main_ptr = gcas_read(unsafe { root_ptr.deref() }.inode(), self, guard);
+            let frozen = IndirectionNode::new(Atomic::from(main_ptr), Generation::new());
+            if !rdcss(
+                self,
+                root_ptr,
+                main_ptr,
+                RootNode::Indirection(frozen),
+                guard,
+            ) {
+                continue;
+            }
+            let root_ptr = rdcss_read_root(self, false, guard);
+
+            // values are compared rather than their addresses, since any write that passes through
+            // the c-node holding a key, and any renewal after a snapshot, copies its value. A
+            // read-only view doesn't renew the nodes its lookups pass through.
+            let contents = Self::from_root(
+                IndirectionNode::new(Atomic::from(main_ptr), Generation::new()),
+                true,
+                self.hashers.clone(),
+            );
+            let unchanged = tx
+                .reads
+                .iter()
+                .all(|(key, read)| contents.lookup(key, guard) == *read);
+            if !unchanged {
+                return false;
+            }
+
+            let base = Self::from_root(
+                IndirectionNode::new(Atomic::from(main_ptr), Generation::new()),
+                false,
+                self.hashers.clone(),
+            );
+            let mut changes = Vec::new();
+            for (key, value) in &tx.writes {
+                match value {
+                    Some(value) => {
+                        let hash = base.hashers.primary_hash(key);
+                        let previous = base.insert_entry(hash, key.clone(), value.clone(), guard);
+                        if publisher.active() {
+                            changes.push(match previous {
+                                Some(old) => Change::Updated {
+                                    key: key.clone(),
+                                    old: old.clone(),
+                                    new: value.clone(),
+                                },
+                                None => Change::Added {
+                                    key: key.clone(),
+                                    value: value.clone(),
+                                },
+                            });
+                        }
+                    }
+                    None => {
+                        let hash = base.hashers.primary_hash(key);
+                        let previous = base.remove_entry(hash, key, guard);
+                        if let (true, Some(value)) = (publisher.active(), previous) {
+                            changes.push(Change::Removed {
+                                key: key.clone(),
+                                value: value.clone(),
+                            });
+                        }
+                    }
+                }
+            }
+
+            let base_root = base.read_root(guard);
+            let committed = IndirectionNode::new(
+                Atomic::from(gcas_read(base_root, &base, guard)),
+                base_root.generation().clone(),
+            );
+            if rdcss(
+                self,
+                root_ptr,
+                main_ptr,
+                RootNode::Indirection(committed),
+                guard,
+            ) {
+                for change in changes {
+                    publisher.publish(Event::Change(change));
+                }
+                return true;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crossbeam::epoch;
+
+    #[test]
+    fn move_value() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        ctrie.insert("a", 1, guard);
+
+        let moved = ctrie.transaction(
+            |tx| {
+                let value = tx.get(&"a").copied();
+                if let Some(value) = value {
+                    tx.remove(&"a");
+                    tx.insert("b", value);
+                    // reads see the transaction's own writes
+                    assert_eq!(tx.get(&"a"), None);
+                    assert_eq!(tx.get(&"b"), Some(&value));
+                }
+                value
+            },
+            guard,
+        );
+        assert_eq!(moved, Some(1));
+        assert_eq!(ctrie.lookup(&"a", guard), None);
+        assert_eq!(ctrie.lookup(&"b", guard), Some(&1));
+        assert_eq!(ctrie.validate(guard), Ok(1));
+    }
+
+    #[test]
+    fn retry_on_conflict() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..100 {
+            ctrie.insert(i, 0, guard);
+        }
+
+        let mut runs = 0;
+        ctrie.transaction(
+            |tx| {
+                runs += 1;
+                let value = *tx.get(&0).unwrap();
+                if runs == 1 {
+                    // a write that commits between the read and the commit
+                    ctrie.insert(0, 10, guard);
+                }
+                tx.insert(0, value + 1);
+            },
+            guard,
+        );
+        assert_eq!(runs, 2);
+        assert_eq!(ctrie.lookup(&0, guard), Some(&11));
+    }
+
+    #[test]
+    fn unrelated_writes() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        ctrie.insert(0, 0, guard);
+
+        let mut runs = 0;
+        ctrie.transaction(
+            |tx| {
+                runs += 1;
+                let value = *tx.get(&0).unwrap();
+                if runs == 1 {
+                    // copies the c-node holding the key read, and the value with it
+                    ctrie.insert(1, 1, guard);
+                    ctrie.snapshot(guard);
+                    ctrie.insert(2, 2, guard);
+                    // a write that leaves the value as it was
+                    ctrie.insert(0, 0, guard);
+                }
+                tx.insert(3, value + 3);
+            },
+            guard,
+        );
+        assert_eq!(runs, 1);
+        assert_eq!(ctrie.lookup(&3, guard), Some(&3));
+        assert_eq!(ctrie.validate(guard), Ok(4));
+    }
+
+    #[test]
+    fn concurrent_transfers() {
+        const ACCOUNTS: u32 = 16;
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        for i in 0..ACCOUNTS {
+            ctrie.insert(i, 100i64, guard);
+        }
+        let total = |ctrie: &Ctrie<u32, i64>, guard| ctrie.iter(guard).map(|(_, v)| v).sum::<i64>();
+
+        std::thread::scope(|scope| {
+            for t in 0..4 {
+                let ctrie = &ctrie;
+                scope.spawn(move || {
+                    let guard = &epoch::pin();
+                    for i in 0..200 {
+                        let from = (i * 7 + t) % ACCOUNTS;
+                        let to = (i * 5 + t + 1) % ACCOUNTS;
+                        ctrie.transaction(
+                            |tx| {
+                                let a = *tx.get(&from).unwrap();
+                                let b = *tx.get(&to).unwrap();
+                                if from != to {
+                                    tx.insert(from, a - 1);
+                                    tx.insert(to, b + 1);
+                                }
+                            },
+                            guard,
+                        );
+                        // unrelated writes interleave with the transactions
+                        ctrie.insert(ACCOUNTS + t, i as i64, guard);
+                        ctrie.remove(&(ACCOUNTS + t), guard);
+                    }
+                });
+            }
+            scope.spawn(|| {
+                let guard = &epoch::pin();
+                for _ in 0..100 {
+                    let snapshot = ctrie.read_only_snapshot(guard);
+                    let sum = snapshot
+                        .iter(guard)
+                        .filter(|(&k, _)| k < ACCOUNTS)
+                        .map(|(_, v)| v)
+                        .sum::<i64>();
+                    assert_eq!(sum, i64::from(ACCOUNTS) * 100);
+                }
+            });
+        });
+
+        assert_eq!(total(&ctrie, guard), i64::from(ACCOUNTS) * 100);
+        assert_eq!(ctrie.validate(guard), Ok(ACCOUNTS as usize));
+    }
+
+    #[test]
+    fn publish_changes() {
+        let ctrie = Ctrie::new();
+        let guard = &epoch::pin();
+        ctrie.insert(1, 1, guard);
+        let changes = ctrie.subscribe();
+
+        ctrie.transaction(
+            |tx| {
+                tx.insert(1, 2);
+                tx.insert(2, 2);
+                tx.insert(2, 3);
+                tx.remove(&3);
+            },
+            guard,
+        );
+        assert_eq!(
+            changes.try_iter().collect::<Vec<_>>(),
+            vec![
+                Event::Change(Change::Updated {
+                    key: 1,
+                    old: 1,
+                    new: 2
+                }),
+                Event::Change(Change::Added { key: 2, value: 3 }),
+            ]
+        );
+    }
+}
@@ -0,0 +1,8 @@
+use ctrie::Ctrie;
+use std::{cell::Cell, thread};
+
+fn main() {
+    // `Cell` is `Send`, but a ctrie shares its values between threads, so it must be `Sync` too
+    let ctrie = Ctrie::<u32, Cell<u32>>::new();
+    thread::spawn(move || drop(ctrie));
+}
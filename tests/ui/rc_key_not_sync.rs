@@ -0,0 +1,12 @@
+use crossbeam::epoch;
+use ctrie::Ctrie;
+use std::{rc::Rc, sync::Arc, thread};
+
+fn main() {
+    let ctrie = Arc::new(Ctrie::<Rc<u32>, u32>::new());
+    let shared = ctrie.clone();
+    thread::spawn(move || {
+        let guard = &epoch::pin();
+        shared.lookup(&Rc::new(0), guard);
+    });
+}
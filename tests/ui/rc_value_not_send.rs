@@ -0,0 +1,7 @@
+use ctrie::Ctrie;
+use std::{rc::Rc, thread};
+
+fn main() {
+    let ctrie = Ctrie::<u32, Rc<u32>>::new();
+    thread::spawn(move || drop(ctrie));
+}